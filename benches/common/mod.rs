@@ -0,0 +1,36 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Timing shared by the benchmarks.
+use eswm::{Syntax, Vm};
+use std::time::{Duration, Instant};
+
+/// How many times each script is run. The fastest run is reported.
+const RUNS: usize = 5;
+
+/// Runs `source` [`RUNS`] times in fresh vms and prints the fastest run.
+pub fn bench(name: &str, source: &str, syntax: Syntax) {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let mut vm = Vm::new();
+        let start = Instant::now();
+        if let Err(error) = vm.interpret_as(source, name, syntax) {
+            panic!("{}", error);
+        }
+        best = best.min(start.elapsed());
+    }
+    println!("{:<12} {:>10.2?}", name, best);
+}
@@ -0,0 +1,54 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Times scripts that spend most of their time on global variables,
+the way hook scripts do.
+
+```sh
+cargo bench --bench globals
+```
+ */
+mod common;
+
+use common::bench;
+use eswm::Syntax;
+
+const SCRIPT: &str = "
+var count = 0;
+var total = 0;
+fun step(n) {
+    total = total + n;
+}
+while (count < 200000) {
+    step(count);
+    count = count + 1;
+}
+";
+
+const LISP: &str = "
+(defvar count 0)
+(defvar total 0)
+(defun step (n)
+  (setq total (+ total n)))
+(while (< count 200000)
+  (step count)
+  (setq count (+ count 1)))
+";
+
+fn main() {
+    bench("script", SCRIPT, Syntax::Script);
+    bench("lisp", LISP, Syntax::Lisp);
+}
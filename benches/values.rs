@@ -0,0 +1,76 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Times stack-heavy scripts, to compare the two ways values can be
+represented. Run it once with each:
+
+```sh
+cargo bench --bench values
+cargo bench --features nan_boxing --bench values
+```
+ */
+mod common;
+
+use common::bench;
+use eswm::{Syntax, Value};
+
+/// Deep recursion, where every call pushes its arguments and result.
+const FIB: &str = "
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 2) + fib(n - 1);
+}
+fib(25);
+";
+
+/// Long expressions over locals, which keep many temporaries on the stack.
+const ARITHMETIC: &str = "
+fun run() {
+    var a = 1;
+    var b = 2;
+    var c = 3;
+    var total = 0;
+    for (var i = 0; i < 300000; i = i + 1) {
+        total = total + (a * b + c) * (a + b * c) - (a - b) * (c - a) / (b + c);
+        if (!(total > 0) == (i < 0)) total = total - i;
+    }
+    return total;
+}
+run();
+";
+
+const LISP_FIB: &str = "
+(defun fib (n)
+  (if (< n 2)
+      n
+    (+ (fib (- n 2)) (fib (- n 1)))))
+(fib 25)
+";
+
+fn main() {
+    let mode = match cfg!(feature = "nan_boxing") {
+        true => "nan-boxed",
+        false => "tagged",
+    };
+    println!(
+        "{} values, {} bytes each",
+        mode,
+        std::mem::size_of::<Value>()
+    );
+    bench("fib", FIB, Syntax::Script);
+    bench("arithmetic", ARITHMETIC, Syntax::Script);
+    bench("lisp-fib", LISP_FIB, Syntax::Lisp);
+}
@@ -13,6 +13,7 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! The `rule!` macro building the entries of the eswm compiler's parse table.
 extern crate proc_macro;
 use proc_macro::TokenStream;
 
@@ -0,0 +1,665 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Compiled scripts saved to run without compiling them again.
+
+A cache starts with a header that every version of the format shares:
+
+| bytes | contents                                       |
+|-------+------------------------------------------------|
+| 4     | `ESWC`                                         |
+| 2     | the format version                             |
+| 8     | the hash of the source, see [`source_hash`]    |
+| 1     | the syntax, 0 for script and 1 for lisp        |
+| str   | the path of the source                         |
+
+It is followed by the name of every global slot the code was compiled
+against, then by the number of symbols made by `gensym` and the
+macros defined while compiling it, each a name,
+the number of required parameters, whether it takes the rest and its
+function, and last by the top-level function. A function is its arity,
+upvalue count, name, code, positions and constants, where a constant
+that is itself a function is written out in full. Numbers are little
+endian, counts and lengths take 4 bytes, and a str is its length
+followed by its utf-8 bytes.
+
+Global slots belong to the vm that compiled the code, so the slots in
+the code are moved to the ones the loading vm gives the same names.
+The code is checked as it is loaded, so a cache that was damaged or
+written by something else is rejected instead of crashing the vm.
+
+Macros are stored so `macroexpand` still knows them when the cache is
+run, as is every other macro of the vm that compiled it, and `gensym`
+carries on counting from where the expansions left it so its symbols
+stay unique.
+ */
+use crate::chunk::{long_index, Chunk, OpCode, Position, Source};
+use crate::compiler::{Macro, Syntax};
+use crate::value::{
+    heap::{Handle, Heap},
+    objects::{ObjClosure, ObjCons, ObjFunction, ObjId},
+    Value, ValueType,
+};
+use crate::vm::{
+    allocate_closure, allocate_cons, allocate_function, allocate_string, allocate_symbol, Vm,
+};
+use std::rc::Rc;
+
+/// The version of the format written. It changes whenever the layout
+/// or the instruction set does, which makes older caches stale.
+pub const FORMAT_VERSION: u16 = 4;
+
+const MAGIC: &[u8; 4] = b"ESWC";
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_SYMBOL: u8 = 5;
+const TAG_CONS: u8 = 6;
+const TAG_FUNCTION: u8 = 7;
+const TAG_INT: u8 = 8;
+
+/// Hashes source code with 64-bit FNV-1a, which stays the same from one
+/// build of eswm to the next.
+pub fn source_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The header of a cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheInfo {
+    pub version: u16,
+    /// The hash of the source the cache was compiled from.
+    pub hash: u64,
+    pub syntax: Syntax,
+    /// The path of the source, which errors are reported against.
+    pub file: String,
+}
+
+impl CacheInfo {
+    /// Reads the header at the start of `bytes`. Returns [`None`] if
+    /// they aren't a cache.
+    pub fn read(bytes: &[u8]) -> Option<CacheInfo> {
+        Decoder::new(bytes).header().ok()
+    }
+
+    /// Whether the cache can be run in place of compiling `source`.
+    pub fn is_fresh(&self, source: &str) -> bool {
+        self.version == FORMAT_VERSION && self.hash == source_hash(source)
+    }
+}
+
+/// Writes the cache of `function`, compiled from `source`.
+pub(crate) fn write(
+    vm: &Vm,
+    function: Value,
+    source: &str,
+    file: &str,
+    syntax: Syntax,
+) -> Result<Vec<u8>, &'static str> {
+    let mut encoder = Encoder {
+        vm,
+        bytes: Vec::new(),
+    };
+    encoder.bytes.extend_from_slice(MAGIC);
+    encoder
+        .bytes
+        .extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    encoder
+        .bytes
+        .extend_from_slice(&source_hash(source).to_le_bytes());
+    encoder.bytes.push(match syntax {
+        Syntax::Script => 0,
+        Syntax::Lisp => 1,
+    });
+    encoder.string(file);
+
+    let names = vm.globals.names();
+    encoder.count(names.len());
+    for name in names {
+        encoder.string(name);
+    }
+    encoder.count(vm.gensym_counter);
+    let mut macros: Vec<_> = vm.macros.iter().collect();
+    macros.sort_by_key(|(name, _)| *name);
+    encoder.count(macros.len());
+    for (name, definition) in macros {
+        encoder.string(name);
+        encoder.count(definition.required);
+        encoder.bytes.push(definition.rest as u8);
+        let closure = vm.heap.get::<ObjClosure>(definition.closure.as_handle());
+        encoder.function(closure.function)?;
+    }
+    encoder.function(function.as_handle())?;
+    Ok(encoder.bytes)
+}
+
+/// Reads the function cached in `bytes`. `source` is the text errors
+/// are shown against, if it is still around.
+///
+/// The function isn't kept alive, so it should be used before the vm
+/// next allocates.
+pub(crate) fn read(vm: &mut Vm, bytes: &[u8], source: Option<&str>) -> Result<Value, &'static str> {
+    let checkpoint = vm.checkpoint();
+    let result = load(vm, bytes, source);
+    if result.is_err() {
+        vm.restore(checkpoint);
+    }
+    result
+}
+
+fn load(vm: &mut Vm, bytes: &[u8], source: Option<&str>) -> Result<Value, &'static str> {
+    let mut decoder = Decoder::new(bytes);
+    let info = decoder.header()?;
+    if info.version != FORMAT_VERSION {
+        return Err("Cache was written by another version of eswm.");
+    }
+    if source.is_some_and(|source| !info.is_fresh(source)) {
+        return Err("Cache is older than its source.");
+    }
+
+    let mut slots = Vec::new();
+    for _ in 0..decoder.count()? {
+        let name = decoder.string()?;
+        slots.push(vm.globals.slot(&name));
+    }
+    let mut loader = Loader {
+        vm,
+        source: Rc::new(Source::new(&info.file, source.unwrap_or(""))),
+        slots,
+    };
+    let roots = loader.vm.compiler_roots.len();
+    let function = loader
+        .macros(&mut decoder)
+        .and_then(|_| loader.closed_function(&mut decoder));
+    loader.vm.compiler_roots.truncate(roots);
+    match function {
+        Ok(_) if decoder.offset != bytes.len() => Err("Cache has bytes after its code."),
+        function => function,
+    }
+}
+
+struct Encoder<'a> {
+    vm: &'a Vm,
+    bytes: Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn count(&mut self, count: usize) {
+        self.bytes.extend_from_slice(&(count as u32).to_le_bytes());
+    }
+
+    fn string(&mut self, string: &str) {
+        self.count(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn function(&mut self, function: Handle) -> Result<(), &'static str> {
+        let function = self.vm.heap.get::<ObjFunction>(function);
+        self.count(function.arity);
+        self.count(function.upvalue_count);
+        match function.name {
+            Some(ref name) => {
+                self.bytes.push(1);
+                self.string(name);
+            }
+            None => self.bytes.push(0),
+        }
+
+        let chunk = &function.chunk;
+        self.count(chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+        let positions: Vec<_> = chunk.positions().collect();
+        self.count(positions.len());
+        for (offset, position) in positions {
+            self.count(offset);
+            self.count(position.line);
+            self.count(position.column);
+        }
+        self.count(chunk.constants.len());
+        for constant in chunk.constants.iter() {
+            self.value(*constant)?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: Value) -> Result<(), &'static str> {
+        match value.val_type() {
+            ValueType::Nil => self.bytes.push(TAG_NIL),
+            ValueType::Bool if value.as_bool() => self.bytes.push(TAG_TRUE),
+            ValueType::Bool => self.bytes.push(TAG_FALSE),
+            ValueType::Number => {
+                self.bytes.push(TAG_NUMBER);
+                self.bytes
+                    .extend_from_slice(&value.as_number().to_le_bytes());
+            }
+            ValueType::Int => {
+                self.bytes.push(TAG_INT);
+                self.bytes.extend_from_slice(&value.as_int().to_le_bytes());
+            }
+            ValueType::Obj => match value.obj_type() {
+                ObjId::String => {
+                    self.bytes.push(TAG_STRING);
+                    self.string(self.vm.heap.string(value.as_handle()));
+                }
+                ObjId::Symbol => {
+                    self.bytes.push(TAG_SYMBOL);
+                    self.string(self.vm.heap.symbol(value.as_handle()));
+                }
+                ObjId::Cons => {
+                    let cons = self.vm.heap.get::<ObjCons>(value.as_handle());
+                    let (car, cdr) = (cons.car, cons.cdr);
+                    self.bytes.push(TAG_CONS);
+                    self.value(car)?;
+                    self.value(cdr)?;
+                }
+                ObjId::Function => {
+                    self.bytes.push(TAG_FUNCTION);
+                    self.function(value.as_handle())?;
+                }
+                ObjId::Closure | ObjId::Upvalue | ObjId::Native => {
+                    return Err("Can't cache code holding a function value as a constant.")
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset.saturating_add(length))
+            .ok_or("Cache ends too soon.")?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn count(&mut self) -> Result<usize, &'static str> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let length = self.count()?;
+        let bytes = self.take(length)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| "Cache holds a string that isn't utf-8.")
+    }
+
+    fn header(&mut self) -> Result<CacheInfo, &'static str> {
+        if self.take(4)? != MAGIC {
+            return Err("Not an eswm cache.");
+        }
+        let version = u16::from_le_bytes(self.take(2)?.try_into().unwrap());
+        let hash = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        let syntax = match self.byte()? {
+            0 => Syntax::Script,
+            1 => Syntax::Lisp,
+            _ => return Err("Cache has an unknown syntax."),
+        };
+        let file = self.string()?;
+        Ok(CacheInfo {
+            version,
+            hash,
+            syntax,
+            file,
+        })
+    }
+}
+
+/// Reads the constant index or global slot `operand` holds, 3 bytes
+/// long for the long instructions and 1 byte otherwise.
+fn read_index(operand: &[u8]) -> usize {
+    match operand.len() {
+        3 => long_index(operand),
+        _ => operand[0] as usize,
+    }
+}
+
+struct Loader<'a> {
+    vm: &'a mut Vm,
+    source: Rc<Source>,
+    /// The slot in the vm of each slot the code was compiled against.
+    slots: Vec<usize>,
+}
+
+impl Loader<'_> {
+    /// Reads the macros and defines them in the vm, along with the
+    /// `gensym` counter.
+    fn macros(&mut self, decoder: &mut Decoder) -> Result<(), &'static str> {
+        let counter = decoder.count()?;
+        self.vm.gensym_counter = self.vm.gensym_counter.max(counter);
+        for _ in 0..decoder.count()? {
+            let name = decoder.string()?;
+            let required = decoder.count()?;
+            let rest = decoder.byte()? != 0;
+            let function = self.closed_function(decoder)?;
+            let closure =
+                allocate_closure(self.vm, ObjClosure::new(function.as_handle(), Vec::new()));
+            let definition = Macro {
+                closure,
+                required,
+                rest,
+            };
+            if let Some(replaced) = self.vm.macros.insert(name, definition) {
+                self.vm.compiler_roots.push(replaced.closure);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a function made into a closure by the loader rather than
+    /// by its code, which has nothing to capture.
+    fn closed_function(&mut self, decoder: &mut Decoder) -> Result<Value, &'static str> {
+        let function = self.function(decoder)?;
+        match self.vm.heap.get::<ObjFunction>(function.as_handle()).upvalue_count {
+            0 => Ok(function),
+            _ => Err("Cache has a top level function capturing variables."),
+        }
+    }
+
+    /// Reads a function, leaving it and the objects it holds in the
+    /// compiler roots.
+    fn function(&mut self, decoder: &mut Decoder) -> Result<Value, &'static str> {
+        let mut function = ObjFunction::new();
+        function.arity = decoder.count()?;
+        function.upvalue_count = decoder.count()?;
+        if decoder.byte()? == 1 {
+            function.name = Some(decoder.string()?);
+        }
+
+        let length = decoder.count()?;
+        let code = decoder.take(length)?;
+        let mut positions = Vec::new();
+        for _ in 0..decoder.count()? {
+            let offset = decoder.count()?;
+            let line = decoder.count()?;
+            let column = decoder.count()?;
+            positions.push((offset, Position { line, column }));
+        }
+        let mut chunk = Chunk::new();
+        chunk.source = self.source.clone();
+        for (index, (offset, position)) in positions.iter().enumerate() {
+            let end = match positions.get(index + 1) {
+                Some((next, _)) => *next,
+                None => code.len(),
+            };
+            for byte in code
+                .get(*offset..end)
+                .ok_or("Cache has a bad position table.")?
+            {
+                chunk.write(*byte, *position);
+            }
+        }
+        if chunk.code.len() != code.len() {
+            return Err("Cache has a bad position table.");
+        }
+        for _ in 0..decoder.count()? {
+            let constant = self.value(decoder)?;
+            chunk.constant(constant);
+        }
+
+        function.chunk = chunk;
+        self.relocate(&mut function)?;
+        let function = allocate_function(self.vm, function);
+        self.vm.compiler_roots.push(function);
+        Ok(function)
+    }
+
+    fn value(&mut self, decoder: &mut Decoder) -> Result<Value, &'static str> {
+        let value = match decoder.byte()? {
+            TAG_NIL => return Ok(Value::None),
+            TAG_FALSE => return Ok(false.into()),
+            TAG_TRUE => return Ok(true.into()),
+            TAG_NUMBER => {
+                let bytes = decoder.take(8)?.try_into().unwrap();
+                return Ok(f64::from_le_bytes(bytes).into());
+            }
+            TAG_INT => {
+                let bytes = decoder.take(8)?.try_into().unwrap();
+                return Ok(i64::from_le_bytes(bytes).into());
+            }
+            TAG_STRING => {
+                let string = decoder.string()?;
+                allocate_string(self.vm, string)
+            }
+            TAG_SYMBOL => {
+                let name = decoder.string()?;
+                allocate_symbol(self.vm, name)
+            }
+            TAG_CONS => {
+                let car = self.value(decoder)?;
+                let cdr = self.value(decoder)?;
+                allocate_cons(self.vm, car, cdr)
+            }
+            TAG_FUNCTION => return self.function(decoder),
+            _ => return Err("Cache holds a constant of an unknown kind."),
+        };
+        self.vm.compiler_roots.push(value);
+        Ok(value)
+    }
+
+    /// Moves the global slots in the code of `function` to the slots of
+    /// the vm, and checks every instruction is whole, every operand is
+    /// in range and the code can't run off the stack or its end.
+    fn relocate(&self, function: &mut ObjFunction) -> Result<(), &'static str> {
+        let upvalue_count = function.upvalue_count;
+        let chunk = &mut function.chunk;
+        let mut starts = vec![false; chunk.code.len()];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            starts[offset] = true;
+            let op = chunk.code[offset];
+            if op > OpCode::PrintLisp as u8 {
+                return Err("Cache holds an unknown instruction.");
+            }
+            let op = OpCode::from(op);
+            let index_size = if op.is_long() { 3 } else { 1 };
+            let operand = chunk.code.get(offset + 1..offset + 1 + index_size);
+            let constant = operand.and_then(|operand| chunk.constants.get(read_index(operand)));
+            match op {
+                OpCode::Constant | OpCode::ConstantLong if constant.is_none() => {
+                    return Err("Cache refers to a constant it doesn't hold.");
+                }
+                // The length of a closure instruction comes from its function.
+                OpCode::Closure | OpCode::ClosureLong
+                    if !constant.is_some_and(|constant| constant.is_obj_type(ObjId::Function)) =>
+                {
+                    return Err("Cache makes a closure of something other than a function.");
+                }
+                _ => (),
+            }
+            let size = 1 + chunk.operand_size(&self.vm.heap, offset);
+            if offset + size > chunk.code.len() {
+                return Err("Cache ends in the middle of an instruction.");
+            }
+            let operands = &chunk.code[offset + 1..offset + size];
+
+            match op {
+                OpCode::GetUpvalue | OpCode::SetUpvalue if operands[0] as usize >= upvalue_count => {
+                    return Err("Cache refers to a variable its function doesn't capture.");
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    // Captured locals are checked along with the stack.
+                    for capture in operands[index_size..].chunks(2) {
+                        let captured = match capture[0] {
+                            0 => (capture[1] as usize) < upvalue_count,
+                            1 => true,
+                            _ => false,
+                        };
+                        if !captured {
+                            return Err("Cache captures a variable that doesn't exist.");
+                        }
+                    }
+                }
+                _ => (),
+            }
+
+            let global = matches!(
+                op,
+                OpCode::DefineGlobal
+                    | OpCode::GetGlobal
+                    | OpCode::SetGlobal
+                    | OpCode::DefineGlobalLong
+                    | OpCode::GetGlobalLong
+                    | OpCode::SetGlobalLong
+            );
+            if global {
+                let operand = &mut chunk.code[offset + 1..offset + 1 + index_size];
+                let slot = read_index(operand);
+                let slot = *self
+                    .slots
+                    .get(slot)
+                    .ok_or("Cache refers to a global it doesn't name.")?;
+                match index_size {
+                    3 if slot < 1 << 24 => operand.copy_from_slice(&[
+                        (slot >> 16) as u8,
+                        (slot >> 8) as u8,
+                        slot as u8,
+                    ]),
+                    1 if slot <= u8::MAX as usize => operand[0] = slot as u8,
+                    _ => return Err("Too many global variables to load the cache."),
+                }
+            }
+            offset += size;
+        }
+        check_stack(&self.vm.heap, function, &starts)
+    }
+}
+
+/// Follows the code of `function` from its start, tracking the height
+/// of the stack to check the locals used exist, nothing is popped that
+/// wasn't pushed and every jump lands on an instruction. `starts` marks
+/// the offsets instructions start at.
+///
+/// The compiler leaves the stack at the same height on every path to
+/// an instruction, so code that doesn't is rejected too.
+fn check_stack(heap: &Heap, function: &ObjFunction, starts: &[bool]) -> Result<(), &'static str> {
+    let chunk = &function.chunk;
+    let mut heights = vec![None; chunk.code.len()];
+    // Slot zero holds the function being called, followed by its arguments.
+    let mut pending = vec![(0, function.arity + 1)];
+    while let Some((offset, height)) = pending.pop() {
+        if offset >= chunk.code.len() {
+            return Err("Cache code runs past its end.");
+        }
+        if !starts[offset] {
+            return Err("Cache jumps into the middle of an instruction.");
+        }
+        match heights[offset] {
+            Some(known) if known == height => continue,
+            Some(_) => return Err("Cache code leaves the stack uneven."),
+            None => heights[offset] = Some(height),
+        }
+
+        let op = OpCode::from(chunk.code[offset]);
+        let size = 1 + chunk.operand_size(heap, offset);
+        let operands = &chunk.code[offset + 1..offset + size];
+        let next = offset + size;
+        let jump = || ((operands[0] as usize) << 8) | operands[1] as usize;
+        // The values the instruction pops and pushes.
+        let (pops, pushes) = match op {
+            OpCode::Return | OpCode::Print | OpCode::PrintLisp | OpCode::Pop | OpCode::CloseUpvalue => (1, 0),
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => (1, 0),
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::GetLocal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::ClosureLong => (0, 1),
+            OpCode::Negate | OpCode::Not | OpCode::BitNot => (1, 1),
+            OpCode::SetGlobal | OpCode::SetGlobalLong | OpCode::SetLocal | OpCode::SetUpvalue => (1, 1),
+            OpCode::JumpIfFalse => (1, 1),
+            OpCode::Jump | OpCode::Loop | OpCode::PopHandler => (0, 0),
+            OpCode::Call => (operands[0] as usize + 1, 1),
+            OpCode::PushHandler => (2, 0),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::IntDivide
+            | OpCode::Modulo
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight => (2, 1),
+        };
+        // The function itself is never popped.
+        if height <= pops {
+            return Err("Cache code pops more than it pushes.");
+        }
+        let after = height - pops + pushes;
+
+        let locals: Vec<usize> = match op {
+            OpCode::GetLocal | OpCode::SetLocal => vec![operands[0] as usize],
+            OpCode::Closure | OpCode::ClosureLong => {
+                let index_size = if op.is_long() { 3 } else { 1 };
+                operands[index_size..]
+                    .chunks(2)
+                    .filter(|capture| capture[0] == 1)
+                    .map(|capture| capture[1] as usize)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        if locals.iter().any(|&slot| slot >= height) {
+            return Err("Cache refers to a local that doesn't exist.");
+        }
+
+        match op {
+            OpCode::Return => (),
+            OpCode::Jump => pending.push((next + jump(), after)),
+            OpCode::Loop => {
+                let target = next.checked_sub(jump()).ok_or("Cache code runs past its start.")?;
+                pending.push((target, after));
+            }
+            OpCode::JumpIfFalse => pending.extend([(next, after), (next + jump(), after)]),
+            // The handler is called with the stack unwound to where it
+            // was installed, with the handler and error object on top.
+            OpCode::PushHandler => pending.extend([(next, after), (next + jump(), after + 2)]),
+            _ => pending.push((next, after)),
+        }
+    }
+    Ok(())
+}
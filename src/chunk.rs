@@ -0,0 +1,403 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::value::{heap::Heap, objects::ObjFunction, Value};
+use std::fmt::{self, Display};
+use std::rc::Rc;
+/// Code representing and instruction to execute.
+#[derive(Clone, Copy)]
+pub enum OpCode {
+    Return,
+    Constant,
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    ConstantLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    ClosureLong,
+    PushHandler,
+    PopHandler,
+    NotEqual,
+    IntDivide,
+    Modulo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    PrintLisp,
+}
+
+impl OpCode {
+    /// The variant of `self` taking a 24-bit constant index or global
+    /// slot, if there is one.
+    pub fn long(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            _ => None,
+        }
+    }
+
+    /// Whether the operand of `self` is a 24-bit constant index or global slot.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClosureLong
+        )
+    }
+}
+
+/// Reads the 24-bit index at the start of `bytes`.
+pub fn long_index(bytes: &[u8]) -> usize {
+    ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize
+}
+
+impl From<u8> for OpCode {
+    fn from(value: u8) -> OpCode {
+        match value {
+            0 => OpCode::Return,
+            1 => OpCode::Constant,
+            2 => OpCode::Negate,
+            3 => OpCode::Add,
+            4 => OpCode::Subtract,
+            5 => OpCode::Multiply,
+            6 => OpCode::Divide,
+            7 => OpCode::Nil,
+            8 => OpCode::True,
+            9 => OpCode::False,
+            10 => OpCode::Not,
+            11 => OpCode::Equal,
+            12 => OpCode::Greater,
+            13 => OpCode::Less,
+            14 => OpCode::Print,
+            15 => OpCode::Pop,
+            16 => OpCode::DefineGlobal,
+            17 => OpCode::GetGlobal,
+            18 => OpCode::SetGlobal,
+            19 => OpCode::GetLocal,
+            20 => OpCode::SetLocal,
+            21 => OpCode::Jump,
+            22 => OpCode::JumpIfFalse,
+            23 => OpCode::Loop,
+            24 => OpCode::Call,
+            25 => OpCode::Closure,
+            26 => OpCode::GetUpvalue,
+            27 => OpCode::SetUpvalue,
+            28 => OpCode::CloseUpvalue,
+            29 => OpCode::ConstantLong,
+            30 => OpCode::DefineGlobalLong,
+            31 => OpCode::GetGlobalLong,
+            32 => OpCode::SetGlobalLong,
+            33 => OpCode::ClosureLong,
+            34 => OpCode::PushHandler,
+            35 => OpCode::PopHandler,
+            36 => OpCode::NotEqual,
+            37 => OpCode::IntDivide,
+            38 => OpCode::Modulo,
+            39 => OpCode::BitAnd,
+            40 => OpCode::BitOr,
+            41 => OpCode::BitXor,
+            42 => OpCode::BitNot,
+            43 => OpCode::ShiftLeft,
+            44 => OpCode::ShiftRight,
+            45 => OpCode::PrintLisp,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            OpCode::Return => write!(f, "OP_RETURN"),
+            OpCode::Constant => write!(f, "OP_CONSTANT"),
+            OpCode::Negate => write!(f, "OP_NEGATE"),
+            OpCode::Add => write!(f, "OP_ADD"),
+            OpCode::Subtract => write!(f, "OP_SUBTRACT"),
+            OpCode::Multiply => write!(f, "OP_MULTIPLY"),
+            OpCode::Divide => write!(f, "OP_DIVIDE"),
+            OpCode::Nil => write!(f, "OP_NIL"),
+            OpCode::True => write!(f, "OP_TRUE"),
+            OpCode::False => write!(f, "OP_FALSE"),
+            OpCode::Not => write!(f, "OP_NOT"),
+            OpCode::Equal => write!(f, "OP_EQUAL"),
+            OpCode::Greater => write!(f, "OP_GREATER"),
+            OpCode::Less => write!(f, "OP_LESS"),
+            OpCode::Print => write!(f, "OP_PRINT"),
+            OpCode::Pop => write!(f, "OP_POP"),
+            OpCode::DefineGlobal => write!(f, "OP_DEFINE_GLOBAL"),
+            OpCode::GetGlobal => write!(f, "OP_GET_GLOBAL"),
+            OpCode::SetGlobal => write!(f, "OP_SET_GLOBAL"),
+            OpCode::GetLocal => write!(f, "OP_GET_LOCAL"),
+            OpCode::SetLocal => write!(f, "OP_SET_LOCAL"),
+            OpCode::Jump => write!(f, "OP_JUMP"),
+            OpCode::JumpIfFalse => write!(f, "OP_JUMP_IF_FALSE"),
+            OpCode::Loop => write!(f, "OP_LOOP"),
+            OpCode::Call => write!(f, "OP_CALL"),
+            OpCode::Closure => write!(f, "OP_CLOSURE"),
+            OpCode::GetUpvalue => write!(f, "OP_GET_UPVALUE"),
+            OpCode::SetUpvalue => write!(f, "OP_SET_UPVALUE"),
+            OpCode::CloseUpvalue => write!(f, "OP_CLOSE_UPVALUE"),
+            OpCode::ConstantLong => write!(f, "OP_CONSTANT_LONG"),
+            OpCode::DefineGlobalLong => write!(f, "OP_DEFINE_GLOBAL_LONG"),
+            OpCode::GetGlobalLong => write!(f, "OP_GET_GLOBAL_LONG"),
+            OpCode::SetGlobalLong => write!(f, "OP_SET_GLOBAL_LONG"),
+            OpCode::ClosureLong => write!(f, "OP_CLOSURE_LONG"),
+            OpCode::PushHandler => write!(f, "OP_PUSH_HANDLER"),
+            OpCode::PopHandler => write!(f, "OP_POP_HANDLER"),
+            OpCode::NotEqual => write!(f, "OP_NOT_EQUAL"),
+            OpCode::IntDivide => write!(f, "OP_INT_DIVIDE"),
+            OpCode::Modulo => write!(f, "OP_MODULO"),
+            OpCode::BitAnd => write!(f, "OP_BIT_AND"),
+            OpCode::BitOr => write!(f, "OP_BIT_OR"),
+            OpCode::BitXor => write!(f, "OP_BIT_XOR"),
+            OpCode::BitNot => write!(f, "OP_BIT_NOT"),
+            OpCode::ShiftLeft => write!(f, "OP_SHIFT_LEFT"),
+            OpCode::ShiftRight => write!(f, "OP_SHIFT_RIGHT"),
+            OpCode::PrintLisp => write!(f, "OP_PRINT_LISP"),
+        }
+    }
+}
+
+/// A line and column in the source code, both starting at 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A file of source code, shared by the chunks compiled from it.
+#[derive(Debug, Default)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Source {
+        Source {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Returns line number `line` of the source, without its line ending.
+    pub fn line(&self, line: usize) -> Option<&str> {
+        self.text
+            .split('\n')
+            .nth(line.checked_sub(1)?)
+            .map(|text| text.trim_end_matches('\r'))
+    }
+}
+
+/// A run of bytes in [`Chunk::code`] generated from the same position.
+#[derive(Clone, Copy)]
+struct LineRun {
+    /// Offset of the first byte of the run.
+    offset: u32,
+    line: u32,
+    column: u32,
+}
+
+/// A chunk houses the instructions for the vm to execute
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    /// The positions of `code`, one entry per run of bytes sharing a position.
+    lines: Vec<LineRun>,
+    /// The source file the chunk was compiled from.
+    pub source: Rc<Source>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            constants: Vec::new(),
+            lines: Vec::new(),
+            source: Rc::default(),
+        }
+    }
+    /// Writes to [`chunk.code`] and records the position it came from.
+    pub fn write(&mut self, code: u8, position: Position) {
+        let run = LineRun {
+            offset: self.code.len() as u32,
+            line: position.line as u32,
+            column: position.column as u32,
+        };
+        self.code.push(code);
+        match self.lines.last() {
+            Some(last) if last.line == run.line && last.column == run.column => {}
+            _ => self.lines.push(run),
+        }
+    }
+
+    /// Returns the source position of the byte at `offset`.
+    pub fn position(&self, offset: usize) -> Position {
+        let run = self
+            .lines
+            .partition_point(|run| run.offset as usize <= offset)
+            .saturating_sub(1);
+        self.lines
+            .get(run)
+            .map(|run| Position {
+                line: run.line as usize,
+                column: run.column as usize,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the line of the byte at `offset`.
+    #[cfg(any(test, feature = "debug_trace_execution", feature = "debug_print_code"))]
+    pub fn line(&self, offset: usize) -> usize {
+        self.position(offset).line
+    }
+
+    /// The offset of each run of bytes sharing a position, with that position.
+    pub fn positions(&self) -> impl Iterator<Item = (usize, Position)> + '_ {
+        self.lines.iter().map(|run| {
+            let position = Position {
+                line: run.line as usize,
+                column: run.column as usize,
+            };
+            (run.offset as usize, position)
+        })
+    }
+
+    /// The number of bytes taken up by the line table.
+    pub fn lines_size(&self) -> usize {
+        self.lines.capacity() * std::mem::size_of::<LineRun>()
+    }
+    /// Returns the number of bytes following the opcode at `offset`.
+    /// `heap` holds the functions of the closures the chunk makes.
+    pub fn operand_size(&self, heap: &Heap, offset: usize) -> usize {
+        let op = OpCode::from(self.code[offset]);
+        match op {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal => 1,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler => 2,
+            OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong => 3,
+            OpCode::Closure | OpCode::ClosureLong => {
+                let (index, size) = if op.is_long() {
+                    (long_index(&self.code[offset + 1..]), 3)
+                } else {
+                    (self.code[offset + 1] as usize, 1)
+                };
+                let function = self.constants[index].as_handle();
+                // Each captured variable takes two bytes after the constant.
+                size + 2 * heap.get::<ObjFunction>(function).upvalue_count
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes to [`chunk.constans`] and returns its position in the array.
+    pub fn constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    #[test]
+    fn bytes_from_one_position_share_a_run() {
+        let mut chunk = Chunk::new();
+        for _ in 0..3 {
+            chunk.write(OpCode::Nil as u8, at(1, 1));
+        }
+        chunk.write(OpCode::Pop as u8, at(1, 5));
+        chunk.write(OpCode::Nil as u8, at(2, 1));
+        chunk.write(OpCode::Return as u8, at(2, 1));
+
+        let runs: Vec<_> = chunk.positions().collect();
+        assert_eq!(runs, [(0, at(1, 1)), (3, at(1, 5)), (4, at(2, 1))]);
+    }
+
+    #[test]
+    fn lookup_finds_the_run_holding_an_offset() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, at(1, 1));
+        chunk.write(OpCode::Nil as u8, at(1, 1));
+        chunk.write(OpCode::Pop as u8, at(3, 7));
+        chunk.write(OpCode::Return as u8, at(4, 2));
+
+        assert_eq!(chunk.position(0), at(1, 1));
+        assert_eq!(chunk.position(1), at(1, 1));
+        assert_eq!(chunk.position(2), at(3, 7));
+        assert_eq!(chunk.line(3), 4);
+        // Offsets past the end belong to the last run.
+        assert_eq!(chunk.line(10), 4);
+        assert_eq!(Chunk::new().position(0), Position::default());
+    }
+}
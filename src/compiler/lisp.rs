@@ -0,0 +1,1204 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Compiles S-expressions into the same bytecode as the script syntax.
+
+Every form compiles to code that leaves exactly one value on the
+stack. The variables of a `let` are locals of the function it is in,
+so each value still waiting on the stack for the instruction using it
+is tracked as a nameless local, a temporary, keeping the slots of the
+locals declared above it right.
+ */
+use super::macros::{self, Macro, EXPANSION_MAX};
+use super::reader::{Datum, DatumKind, Reader};
+use super::{pop_compiler, push_compiler, Compiler, FunctionType, Upvalue, LONG_INDEX_MAX};
+use crate::error::{Diagnostic, ErrorKind, Note, Span};
+use crate::chunk::{OpCode, Position, Source};
+use crate::value::{
+    objects::{is_keyword, ObjClosure, ObjCons, ObjFunction, ObjId},
+    Value, ValueType,
+};
+use crate::vm::{
+    allocate_closure, allocate_cons, allocate_function, allocate_string, allocate_symbol,
+    InterpretResult, Vm, VmErr,
+};
+use std::rc::Rc;
+
+struct LispCompiler<'a> {
+    vm: &'a mut Vm,
+    compiler: Box<Compiler>,
+    /// The file being compiled.
+    source: Rc<Source>,
+    /// The errors found so far.
+    errors: Vec<Diagnostic>,
+    /// The number of macro calls being expanded within each other.
+    expansion_depth: usize,
+}
+
+impl<'a> LispCompiler<'a> {
+    fn new(vm: &'a mut Vm, source: Rc<Source>) -> Self {
+        let mut compiler = Box::new(Compiler::new(FunctionType::Script));
+        compiler.function.chunk.source = source.clone();
+        LispCompiler {
+            vm,
+            compiler,
+            source,
+            errors: Vec::new(),
+            expansion_depth: 0,
+        }
+    }
+
+    fn error(&mut self, position: Position, message: &str) {
+        self.error_of(ErrorKind::Compile, position, message);
+    }
+
+    fn error_of(&mut self, kind: ErrorKind, position: Position, message: &str) -> &mut Diagnostic {
+        let span = Span::new(&self.source, position, 0);
+        self.errors.push(Diagnostic::new(kind, message, Some(span)));
+        self.errors.last_mut().unwrap()
+    }
+
+    /// Reports an error underlining the source of `datum`.
+    fn error_at(&mut self, datum: &Datum, message: &str) -> &mut Diagnostic {
+        let span = Span::new(&self.source, datum.position, datum.width);
+        self.errors.push(Diagnostic::new(ErrorKind::Compile, message, Some(span)));
+        self.errors.last_mut().unwrap()
+    }
+
+    fn had_error(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    fn emit_byte(&mut self, byte: u8, position: Position) {
+        self.compiler.emit_byte(byte, position);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8, position: Position) {
+        self.emit_byte(byte1, position);
+        self.emit_byte(byte2, position);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, position: Position) {
+        if let Err(message) = self.compiler.emit_loop(loop_start, position) {
+            self.error(position, message);
+        }
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode, position: Position) -> usize {
+        self.compiler.emit_jump(instruction, position)
+    }
+
+    fn patch_jump(&mut self, offset: usize, position: Position) {
+        if let Err(message) = self.compiler.patch_jump(offset) {
+            self.error(position, message);
+        }
+    }
+
+    fn make_constant<T: Into<Value>>(&mut self, value: T, position: Position) -> usize {
+        let value = value.into();
+        // Keep the constant alive should a collection happen before the
+        // function is moved onto the heap.
+        self.vm.compiler_roots.push(value);
+        match self.compiler.add_constant(value) {
+            Ok(constant) => constant,
+            Err(message) => {
+                self.error(position, message);
+                0
+            }
+        }
+    }
+
+    fn emit_constant<T: Into<Value>>(&mut self, value: T, position: Position) {
+        let constant = self.make_constant(value, position);
+        self.compiler
+            .emit_indexed(OpCode::Constant, constant, position);
+    }
+
+    /// Records the value just pushed as a temporary. Returns whether it
+    /// was, which fails if the function has too many locals.
+    fn push_temporary(&mut self, position: Position) -> bool {
+        if let Err(message) = self.compiler.add_local(String::new(), position) {
+            self.error(position, message);
+            return false;
+        }
+        let depth = self.compiler.scope_depth;
+        self.compiler.locals[self.compiler.local_count - 1].depth = depth;
+        true
+    }
+
+    /// Compiles `operands` one after the other, leaving their values on
+    /// the stack for the instruction that follows to pop.
+    fn operands<'d>(&mut self, operands: impl IntoIterator<Item = &'d Datum>) {
+        let base = self.compiler.local_count;
+        let mut operands = operands.into_iter().peekable();
+        while let Some(operand) = operands.next() {
+            self.expression(operand);
+            if operands.peek().is_some() {
+                self.push_temporary(operand.position);
+            }
+        }
+        self.compiler.local_count = base;
+    }
+
+    /// Returns the slot of the global variable `name`.
+    fn global_slot(&mut self, name: &str, position: Position) -> usize {
+        let slot = self.vm.globals.slot(name);
+        if slot > LONG_INDEX_MAX {
+            self.error(position, "Too many global variables.");
+        }
+        slot
+    }
+
+    fn expression(&mut self, datum: &Datum) {
+        let position = datum.position;
+        match datum.kind {
+            DatumKind::Number(number) => self.emit_constant(number, position),
+            DatumKind::Integer(integer) => self.emit_constant(integer, position),
+            DatumKind::String(ref string) => {
+                let string = allocate_string(self.vm, string.clone());
+                self.emit_constant(string, position);
+            }
+            DatumKind::Symbol(ref name) | DatumKind::Uninterned(ref name, _) => {
+                self.variable(name, position)
+            }
+            DatumKind::List(ref items) => match items.split_first() {
+                None => self.emit_byte(OpCode::Nil as u8, position),
+                Some((head, args)) => match head.symbol() {
+                    Some(name) if self.vm.macros.contains_key(name) => self.expand(name, datum),
+                    Some(name) if self.special_form(name, args, position) => {}
+                    _ => self.call(head, args, position),
+                },
+            },
+            DatumKind::DottedList(..) => {
+                self.error_at(datum, "Can't evaluate a dotted list.");
+            }
+        }
+    }
+
+    /// Compiles `forms` one after the other, keeping the value of the last.
+    fn body(&mut self, forms: &[Datum], position: Position) {
+        match forms.split_last() {
+            None => self.emit_byte(OpCode::Nil as u8, position),
+            Some((last, rest)) => {
+                for form in rest {
+                    self.expression(form);
+                    self.emit_byte(OpCode::Pop as u8, form.position);
+                }
+                self.expression(last);
+            }
+        }
+    }
+
+    /// Compiles the special form `name`. Returns false if there is no
+    /// special form by that name and the list is a function call.
+    fn special_form(&mut self, name: &str, args: &[Datum], position: Position) -> bool {
+        match name {
+            "quote" => self.quote(args, position),
+            "quasiquote" => self.quasiquote(args, position),
+            "unquote" | "unquote-splicing" => {
+                self.error(position, &format!("'{}' outside of a backquote.", name))
+            }
+            "progn" => self.body(args, position),
+            "setq" => self.setq(args, position),
+            "defvar" | "defconst" => self.defvar(name, args, position),
+            "if" => self.if_(args, position),
+            "when" => self.when(name, args, position, false),
+            "unless" => self.when(name, args, position, true),
+            "cond" => self.cond(args, position),
+            "and" => self.and_(args, position),
+            "or" => self.or_(args, position),
+            "not" | "null" => self.unary(name, args, position, &[OpCode::Not]),
+            "while" => self.while_(args, position),
+            "let" => self.let_(name, args, position, false),
+            "let*" => self.let_(name, args, position, true),
+            "function" => self.function_quote(args, position),
+            "lambda" => self.lambda(args, position),
+            "defun" => self.defun(args, position),
+            "defmacro" => self.defmacro(args, position),
+            "condition-case" => self.condition_case(args, position),
+            "print" => {
+                self.unary(name, args, position, &[OpCode::PrintLisp]);
+                self.emit_byte(OpCode::Nil as u8, position);
+            }
+            "+" => self.arithmetic(args, position, OpCode::Add, 0),
+            "*" => self.arithmetic(args, position, OpCode::Multiply, 1),
+            "-" => self.minus(args, position),
+            "floor" => self.floor(args, position),
+            "mod" => self.binary(name, args, position, &[OpCode::Modulo]),
+            "logand" => self.arithmetic(args, position, OpCode::BitAnd, -1),
+            "logior" => self.arithmetic(args, position, OpCode::BitOr, 0),
+            "logxor" => self.arithmetic(args, position, OpCode::BitXor, 0),
+            "lognot" => self.unary(name, args, position, &[OpCode::BitNot]),
+            "ash" => self.binary(name, args, position, &[OpCode::ShiftLeft]),
+            "<" => self.binary(name, args, position, &[OpCode::Less]),
+            ">" => self.binary(name, args, position, &[OpCode::Greater]),
+            "<=" => self.binary(name, args, position, &[OpCode::Greater, OpCode::Not]),
+            ">=" => self.binary(name, args, position, &[OpCode::Less, OpCode::Not]),
+            "=" | "eq" | "equal" => self.binary(name, args, position, &[OpCode::Equal]),
+            "/=" => self.binary(name, args, position, &[OpCode::Equal, OpCode::Not]),
+            _ => return false,
+        }
+        true
+    }
+
+    fn quote(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("quote", args, 1, Some(1), position) {
+            return;
+        }
+        let value = self.datum_value(&args[0]);
+        self.emit_constant(value, position);
+    }
+
+    /// Converts a quoted `datum` into the value it stands for.
+    fn datum_value(&mut self, datum: &Datum) -> Value {
+        let (items, tail) = match datum.kind {
+            DatumKind::Number(number) => return number.into(),
+            DatumKind::Integer(integer) => return integer.into(),
+            DatumKind::String(ref string) => return allocate_string(self.vm, string.clone()),
+            DatumKind::Uninterned(_, symbol) => return symbol,
+            DatumKind::Symbol(ref name) => {
+                return match name.as_str() {
+                    "nil" => Value::None,
+                    "t" => true.into(),
+                    _ => allocate_symbol(self.vm, name.clone()),
+                }
+            }
+            DatumKind::List(ref items) => (items, None),
+            DatumKind::DottedList(ref items, ref tail) => (items, Some(tail)),
+        };
+
+        let mut list = match tail {
+            Some(tail) => self.datum_value(tail),
+            None => Value::None,
+        };
+        // Every part of the list is rooted as it is built.
+        self.vm.compiler_roots.push(list);
+        for item in items.iter().rev() {
+            let car = self.datum_value(item);
+            self.vm.compiler_roots.push(car);
+            list = allocate_cons(self.vm, car, list);
+            self.vm.compiler_roots.push(list);
+        }
+        list
+    }
+
+    /// Reports an error unless `args` holds between `min` and `max` forms.
+    fn check_arity(
+        &mut self,
+        name: &str,
+        args: &[Datum],
+        min: usize,
+        max: Option<usize>,
+        position: Position,
+    ) -> bool {
+        if args.len() < min || max.is_some_and(|max| args.len() > max) {
+            let expected = match max {
+                Some(max) if max == min => format!("{}", min),
+                Some(max) => format!("{} to {}", min, max),
+                None => format!("at least {}", min),
+            };
+            let plural = if expected == "1" { "" } else { "s" };
+            self.error(
+                position,
+                &format!(
+                    "'{}' expects {} argument{} but got {}.",
+                    name,
+                    expected,
+                    plural,
+                    args.len()
+                ),
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Converts `value`, returned by a macro, back into the form it stands for.
+    fn value_datum(&mut self, value: Value, position: Position) -> Option<Datum> {
+        let kind = match value.val_type() {
+            ValueType::Nil => DatumKind::Symbol(String::from("nil")),
+            ValueType::Bool if value.as_bool() => DatumKind::Symbol(String::from("t")),
+            ValueType::Bool => DatumKind::Symbol(String::from("nil")),
+            ValueType::Number => DatumKind::Number(value.as_number()),
+            ValueType::Int => DatumKind::Integer(value.as_int()),
+            ValueType::Obj => match value.obj_type() {
+                ObjId::String => DatumKind::String(self.vm.heap.string(value.as_handle()).to_string()),
+                ObjId::Symbol => {
+                    let name = self.vm.heap.symbol(value.as_handle());
+                    match self.vm.heap.find_symbol(name) {
+                        Some(interned) if Value::from(interned) == value => DatumKind::Symbol(name.to_string()),
+                        _ => {
+                            // The symbol lives on in the datum after the
+                            // expansion holding it is gone.
+                            let name = format!("{} #{}", name, value.as_handle().index());
+                            self.vm.compiler_roots.push(value);
+                            DatumKind::Uninterned(name, value)
+                        }
+                    }
+                }
+                ObjId::Cons => {
+                    let mut items = Vec::new();
+                    let mut tail = value;
+                    while tail.is_obj_type(ObjId::Cons) {
+                        let cons = self.vm.heap.get::<ObjCons>(tail.as_handle());
+                        let car = cons.car;
+                        tail = cons.cdr;
+                        items.push(self.value_datum(car, position)?);
+                    }
+                    if tail.is_type(ValueType::Nil) {
+                        DatumKind::List(items)
+                    } else {
+                        let tail = self.value_datum(tail, position)?;
+                        DatumKind::DottedList(items, Box::new(tail))
+                    }
+                }
+                _ => {
+                    let shown = value.display_lisp(&self.vm.heap).to_string();
+                    self.error(
+                        position,
+                        &format!("Can't compile '{}' from a macro expansion.", shown),
+                    );
+                    return None;
+                }
+            },
+        };
+        Some(Datum {
+            kind,
+            position,
+            width: 0,
+        })
+    }
+
+    /// Compiles the expansion of `form`, a call to the macro `name`.
+    fn expand(&mut self, name: &str, form: &Datum) {
+        let position = form.position;
+        if self.expansion_depth == EXPANSION_MAX {
+            self.error(position, "Macro expansion nested too deeply.");
+            return;
+        }
+
+        let value = self.datum_value(form);
+        let expansion = match macros::macroexpand(self.vm, value) {
+            Ok(expansion) => expansion,
+            Err(error) => {
+                let message = format!("Failed to expand macro '{}'.", name);
+                let diagnostic = self.error_of(ErrorKind::Compile, position, &message);
+                if let VmErr::RuntimeError(cause) = error {
+                    diagnostic.cause = Some(cause);
+                }
+                return;
+            }
+        };
+        if let Some(expansion) = self.value_datum(expansion, position) {
+            self.expansion_depth += 1;
+            self.expression(&expansion);
+            self.expansion_depth -= 1;
+        }
+    }
+
+    /// Compiles a backquoted `datum` into the code building it, like
+    /// `quote` but with the forms marked by a comma evaluated.
+    fn quasiquote(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("quasiquote", args, 1, Some(1), position) {
+            return;
+        }
+        let code = backquote(&args[0], 0);
+        self.expression(&code);
+    }
+
+    /// Returns the name of `datum`, reporting an error if it isn't a variable.
+    fn variable_name<'d>(&mut self, datum: &'d Datum) -> Option<&'d str> {
+        match datum.symbol() {
+            Some(name) if matches!(name, "nil" | "t") || is_keyword(name) => {
+                self.error_at(
+                    datum,
+                    &format!("Can't use constant '{}' as a variable.", name),
+                );
+                None
+            }
+            Some(name) => Some(name),
+            None => {
+                self.error_at(datum, "Expect variable name.");
+                None
+            }
+        }
+    }
+
+    /// Finds where the variable `name` lives. Returns the instructions
+    /// to get and set it, along with their operand.
+    fn resolve(&mut self, name: &str, position: Position) -> (OpCode, OpCode, usize) {
+        match self.compiler.resolve_local(name) {
+            Ok(Some(slot)) => return (OpCode::GetLocal, OpCode::SetLocal, slot as usize),
+            Ok(None) => {}
+            Err(message) => self.error(position, message),
+        }
+        match self.compiler.resolve_upvalue(name) {
+            Ok(Some(index)) => return (OpCode::GetUpvalue, OpCode::SetUpvalue, index as usize),
+            Ok(None) => {}
+            Err(message) => self.error(position, message),
+        }
+        let global = self.global_slot(name, position);
+        (OpCode::GetGlobal, OpCode::DefineGlobal, global)
+    }
+
+    fn variable(&mut self, name: &str, position: Position) {
+        match name {
+            "nil" => self.emit_byte(OpCode::Nil as u8, position),
+            "t" => self.emit_byte(OpCode::True as u8, position),
+            _ if is_keyword(name) => {
+                let keyword = allocate_symbol(self.vm, name.to_string());
+                self.emit_constant(keyword, position);
+            }
+            _ => {
+                let (get_op, _, arg) = self.resolve(name, position);
+                self.compiler.emit_indexed(get_op, arg, position);
+            }
+        }
+    }
+
+    /// Stores the value on top of the stack in `name`, leaving it on the stack.
+    fn set_variable(&mut self, name: &str, position: Position) {
+        let (get_op, set_op, arg) = self.resolve(name, position);
+        self.compiler.emit_indexed(set_op, arg, position);
+        if matches!(set_op, OpCode::DefineGlobal) {
+            // Defining a global pops its value.
+            self.compiler.emit_indexed(get_op, arg, position);
+        }
+    }
+
+    fn setq(&mut self, args: &[Datum], position: Position) {
+        if !args.len().is_multiple_of(2) {
+            self.error(position, "'setq' expects pairs of variables and values.");
+            return;
+        }
+        if args.is_empty() {
+            self.emit_byte(OpCode::Nil as u8, position);
+        }
+
+        for (i, pair) in args.chunks(2).enumerate() {
+            if i > 0 {
+                self.emit_byte(OpCode::Pop as u8, position);
+            }
+            let name = match self.variable_name(&pair[0]) {
+                Some(name) => name,
+                None => return,
+            };
+            self.expression(&pair[1]);
+            self.set_variable(name, pair[0].position);
+        }
+    }
+
+    /// Compiles `(defvar name [value [docstring]])`, which sets the global
+    /// `name` to `value`, or nil if there is no value. `defvar` leaves a
+    /// global that is already defined alone, `defconst` always sets it.
+    fn defvar(&mut self, form: &str, args: &[Datum], position: Position) {
+        if !self.check_arity(form, args, 1, Some(3), position) {
+            return;
+        }
+        let name = match self.variable_name(&args[0]) {
+            Some(name) => name,
+            None => return,
+        };
+        let mut end_jump = None;
+        if form == "defvar" {
+            let symbol = |name| Datum::new_symbol(name, position);
+            let quoted = Datum::new_list(vec![symbol("quote"), args[0].clone()], position);
+            self.call(&symbol("boundp"), &[quoted], position);
+            let define_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+            self.emit_byte(OpCode::Pop as u8, position);
+            end_jump = Some(self.emit_jump(OpCode::Jump, position));
+            self.patch_jump(define_jump, position);
+            self.emit_byte(OpCode::Pop as u8, position);
+        }
+        match args.get(1) {
+            Some(value) => self.expression(value),
+            None => self.emit_byte(OpCode::Nil as u8, position),
+        }
+        let global = self.global_slot(name, position);
+        self.compiler
+            .emit_indexed(OpCode::DefineGlobal, global, position);
+        if let Some(end_jump) = end_jump {
+            self.patch_jump(end_jump, position);
+        }
+        self.emit_byte(OpCode::Nil as u8, position);
+    }
+
+    fn if_(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("if", args, 2, None, position) {
+            return;
+        }
+        self.expression(&args[0]);
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.expression(&args[1]);
+
+        let else_jump = self.emit_jump(OpCode::Jump, position);
+        self.patch_jump(then_jump, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.body(&args[2..], position);
+        self.patch_jump(else_jump, position);
+    }
+
+    /// Compiles `when`, or `unless` if `negate` is set.
+    fn when(&mut self, form: &str, args: &[Datum], position: Position, negate: bool) {
+        if !self.check_arity(form, args, 1, None, position) {
+            return;
+        }
+        self.expression(&args[0]);
+        if negate {
+            self.emit_byte(OpCode::Not as u8, position);
+        }
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.body(&args[1..], position);
+
+        let else_jump = self.emit_jump(OpCode::Jump, position);
+        self.patch_jump(then_jump, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.emit_byte(OpCode::Nil as u8, position);
+        self.patch_jump(else_jump, position);
+    }
+
+    /// Compiles `(cond (test body...)...)`. A clause without a body
+    /// evaluates to the value of its test.
+    fn cond(&mut self, args: &[Datum], position: Position) {
+        let mut end_jumps = Vec::new();
+        for clause in args {
+            let forms = match clause.kind {
+                DatumKind::List(ref forms) if !forms.is_empty() => forms,
+                _ => {
+                    self.error_at(clause, "Expect '(test body...)' in 'cond'.");
+                    return;
+                }
+            };
+            self.expression(&forms[0]);
+            let next_jump = self.emit_jump(OpCode::JumpIfFalse, clause.position);
+            if forms.len() > 1 {
+                self.emit_byte(OpCode::Pop as u8, clause.position);
+                self.body(&forms[1..], clause.position);
+            }
+            end_jumps.push(self.emit_jump(OpCode::Jump, clause.position));
+            self.patch_jump(next_jump, clause.position);
+            self.emit_byte(OpCode::Pop as u8, clause.position);
+        }
+
+        self.emit_byte(OpCode::Nil as u8, position);
+        for jump in end_jumps {
+            self.patch_jump(jump, position);
+        }
+    }
+
+    fn and_(&mut self, args: &[Datum], position: Position) {
+        let (last, rest) = match args.split_last() {
+            Some(split) => split,
+            None => return self.emit_byte(OpCode::True as u8, position),
+        };
+        let mut end_jumps = Vec::new();
+        for arg in rest {
+            self.expression(arg);
+            end_jumps.push(self.emit_jump(OpCode::JumpIfFalse, position));
+            self.emit_byte(OpCode::Pop as u8, position);
+        }
+        self.expression(last);
+        for jump in end_jumps {
+            self.patch_jump(jump, position);
+        }
+    }
+
+    fn or_(&mut self, args: &[Datum], position: Position) {
+        let (last, rest) = match args.split_last() {
+            Some(split) => split,
+            None => return self.emit_byte(OpCode::Nil as u8, position),
+        };
+        let mut end_jumps = Vec::new();
+        for arg in rest {
+            self.expression(arg);
+            let else_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+            end_jumps.push(self.emit_jump(OpCode::Jump, position));
+            self.patch_jump(else_jump, position);
+            self.emit_byte(OpCode::Pop as u8, position);
+        }
+        self.expression(last);
+        for jump in end_jumps {
+            self.patch_jump(jump, position);
+        }
+    }
+
+    fn while_(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("while", args, 1, None, position) {
+            return;
+        }
+        let loop_start = self.compiler.function.chunk.code.len();
+        self.expression(&args[0]);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.body(&args[1..], position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.emit_loop(loop_start, position);
+
+        self.patch_jump(exit_jump, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.emit_byte(OpCode::Nil as u8, position);
+    }
+
+    /// Splits the bindings of a `let` into the names and the forms giving their values.
+    fn bindings<'d>(&mut self, bindings: &'d Datum) -> Option<Vec<(&'d Datum, Option<&'d Datum>)>> {
+        let bindings = match bindings.kind {
+            DatumKind::List(ref bindings) => bindings,
+            _ => {
+                self.error_at(bindings, "Expect a list of bindings.");
+                return None;
+            }
+        };
+
+        let mut split = Vec::new();
+        for binding in bindings {
+            match binding.kind {
+                DatumKind::Symbol(_) | DatumKind::Uninterned(..) => split.push((binding, None)),
+                DatumKind::List(ref pair) if !pair.is_empty() && pair.len() <= 2 => {
+                    split.push((&pair[0], pair.get(1)))
+                }
+                _ => {
+                    self.error_at(binding, "Expect 'name' or '(name value)' binding.");
+                    return None;
+                }
+            }
+        }
+        Some(split)
+    }
+
+    /// Compiles `let`, or `let*` if `sequential` is set, where each value
+    /// sees the variables bound before it. The variables are locals
+    /// above a slot pushed for the value of the `let`, which the value
+    /// of the body is moved into before they are popped.
+    fn let_(&mut self, form: &str, args: &[Datum], position: Position, sequential: bool) {
+        if !self.check_arity(form, args, 1, None, position) {
+            return;
+        }
+        let bindings = match self.bindings(&args[0]) {
+            Some(bindings) => bindings,
+            None => return,
+        };
+        let result = self.compiler.local_count;
+        self.emit_byte(OpCode::Nil as u8, position);
+        if !self.push_temporary(position) {
+            return;
+        }
+        self.compiler.scope_depth += 1;
+
+        let first = self.compiler.local_count;
+        let mut names = Vec::new();
+        for (name, value) in bindings.iter() {
+            let variable = self.variable_name(name);
+            match value {
+                Some(value) => self.expression(value),
+                None => self.emit_byte(OpCode::Nil as u8, name.position),
+            }
+            match variable {
+                Some(variable) if sequential => {
+                    if let Err(message) = self.compiler.add_local(variable.to_string(), name.position) {
+                        self.error_at(name, message);
+                    }
+                    self.compiler.mark_initialized();
+                }
+                _ => {
+                    self.push_temporary(name.position);
+                    names.push(variable.map(|variable| (variable, *name)));
+                }
+            }
+        }
+        // The values of a `let` are all worked out before any is bound.
+        for (slot, name) in (first..self.compiler.local_count).zip(names) {
+            let (name, datum) = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(declared) = self.compiler.declaration(name) {
+                let span = Span::new(&self.source, declared, name.chars().count());
+                let message = "Already a variable with this name in this scope.";
+                let error = self.error_at(datum, message);
+                error.notes.push(Note::new("The earlier declaration is here.", Some(span)));
+            }
+            let local = &mut self.compiler.locals[slot];
+            local.name = name.to_string();
+            local.position = datum.position;
+        }
+
+        self.body(&args[1..], position);
+        self.emit_bytes(OpCode::SetLocal as u8, result as u8, position);
+        self.emit_byte(OpCode::Pop as u8, position);
+        self.compiler.end_scope(position);
+        // The slot left holds the value of the `let`.
+        self.compiler.local_count = result;
+    }
+
+    /// Compiles `(function name)`, or `#'name`, which gives the function
+    /// `name` is bound to. A lambda is compiled as it is.
+    fn function_quote(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("function", args, 1, Some(1), position) {
+            return;
+        }
+        let is_lambda = match args[0].kind {
+            DatumKind::List(ref items) => items.first().and_then(Datum::symbol) == Some("lambda"),
+            _ => false,
+        };
+        if args[0].symbol().is_none() && !is_lambda {
+            self.error_at(&args[0], "Expect a function name or a lambda.");
+            return;
+        }
+        self.expression(&args[0]);
+    }
+
+    fn lambda(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("lambda", args, 1, None, position) {
+            return;
+        }
+        if let Some(params) = self.parameters(&args[0]) {
+            self.function("lambda", &params, &args[1..], position);
+        }
+    }
+
+    fn defun(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("defun", args, 2, None, position) {
+            return;
+        }
+        let name = match self.variable_name(&args[0]) {
+            Some(name) => name,
+            None => return,
+        };
+        if let Some(params) = self.parameters(&args[1]) {
+            self.function(name, &params, &args[2..], position);
+            let global = self.global_slot(name, position);
+            self.compiler
+                .emit_indexed(OpCode::DefineGlobal, global, position);
+        }
+        self.emit_byte(OpCode::Nil as u8, position);
+    }
+
+    /// Defines a macro as soon as it is compiled, so the forms after it can use it.
+    fn defmacro(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("defmacro", args, 2, None, position) {
+            return;
+        }
+        let name = match self.variable_name(&args[0]) {
+            Some(name) => name,
+            None => return,
+        };
+        let mut params = match self.parameters(&args[1]) {
+            Some(params) => params,
+            None => return,
+        };
+        let required = params
+            .iter()
+            .position(|param| param.symbol() == Some("&rest"))
+            .unwrap_or(params.len());
+        let rest = required < params.len();
+        if rest {
+            params.remove(required);
+            if params.len() != required + 1 {
+                self.error_at(&args[1], "Expect one parameter after '&rest'.");
+                return;
+            }
+        }
+
+        // The macro is compiled on its own, as it can't capture the
+        // variables of code that hasn't run yet.
+        let mut compiler = LispCompiler::new(self.vm, self.source.clone());
+        let (function, _) = compiler.function_object(name, &params, &args[2..], position);
+        if compiler.had_error() {
+            self.errors.append(&mut compiler.errors);
+            return;
+        }
+        let function = allocate_function(self.vm, function);
+        self.vm.compiler_roots.push(function);
+        let closure = allocate_closure(self.vm, ObjClosure::new(function.as_handle(), Vec::new()));
+        let definition = Macro {
+            closure,
+            required,
+            rest,
+        };
+        // The macro replaced comes back if the compile fails.
+        if let Some(replaced) = self.vm.macros.insert(name.to_string(), definition) {
+            self.vm.compiler_roots.push(replaced.closure);
+        }
+        self.emit_byte(OpCode::Nil as u8, position);
+    }
+
+    /// Compiles `(condition-case var body handlers...)`. Each handler is
+    /// `(conditions handler-body...)`, where conditions is an error
+    /// symbol or a list of them, and runs with `var` bound to the error
+    /// object of an error raised by `body`. The symbols `error` and `t`
+    /// catch every error. Errors no handler catches are signalled again.
+    fn condition_case(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("condition-case", args, 2, None, position) {
+            return;
+        }
+        let var = match args[0].symbol() {
+            // The error object still needs a parameter to be passed in.
+            Some("nil") => Datum::new_symbol("condition-case error", args[0].position),
+            _ => match self.variable_name(&args[0]) {
+                Some(_) => args[0].clone(),
+                None => return,
+            },
+        };
+
+        // The handlers are compiled as a function taking the error
+        // object, dispatching on its symbol with a `cond`. The vm only
+        // calls it for the errors one of the clauses catches.
+        let symbol = |name| Datum::new_symbol(name, position);
+        let list = |items| Datum::new_list(items, position);
+        let mut clauses = vec![symbol("cond")];
+        let mut caught = Vec::new();
+        let mut catches_all = false;
+        for handler in &args[2..] {
+            let (conditions, body) = match handler.kind {
+                DatumKind::List(ref items) if !items.is_empty() => (&items[0], &items[1..]),
+                _ => {
+                    self.error_at(handler, "Expect '(conditions body...)' handler.");
+                    return;
+                }
+            };
+            let conditions = match conditions.kind {
+                DatumKind::Symbol(_) | DatumKind::Uninterned(..) => vec![conditions.clone()],
+                DatumKind::List(ref items) if items.iter().all(|item| item.symbol().is_some()) => {
+                    items.clone()
+                }
+                _ => {
+                    self.error_at(conditions, "Expect an error symbol or a list of them.");
+                    return;
+                }
+            };
+
+            let catch_all = conditions
+                .iter()
+                .any(|condition| matches!(condition.symbol(), Some("error" | "t")));
+            catches_all |= catch_all;
+            let test = if catch_all {
+                symbol("t")
+            } else {
+                let error_symbol = list(vec![symbol("car"), var.clone()]);
+                let mut test = vec![symbol("or")];
+                for condition in conditions {
+                    let quoted = list(vec![symbol("quote"), condition.clone()]);
+                    test.push(list(vec![symbol("eq"), error_symbol.clone(), quoted]));
+                    caught.push(condition);
+                }
+                list(test)
+            };
+            let mut clause = vec![test];
+            match body.is_empty() {
+                true => clause.push(symbol("nil")),
+                false => clause.extend_from_slice(body),
+            }
+            clauses.push(list(clause));
+        }
+
+        // The error symbols caught, or t for every error, go below the
+        // handler for the vm to check before unwinding.
+        match catches_all {
+            true => self.expression(&symbol("t")),
+            false => self.expression(&list(vec![symbol("quote"), list(caught)])),
+        }
+        self.function("condition-case", &[&var], &[list(clauses)], position);
+        let handler_jump = self.emit_jump(OpCode::PushHandler, position);
+        self.expression(&args[1]);
+        self.emit_byte(OpCode::PopHandler as u8, position);
+        let end_jump = self.emit_jump(OpCode::Jump, position);
+
+        // An error unwinds the stack to here with the handler and the
+        // error object on top.
+        self.patch_jump(handler_jump, position);
+        self.emit_call(1, position);
+        self.patch_jump(end_jump, position);
+    }
+
+    fn parameters<'d>(&mut self, params: &'d Datum) -> Option<Vec<&'d Datum>> {
+        match params.kind {
+            DatumKind::List(ref params) => Some(params.iter().collect()),
+            _ => {
+                self.error_at(params, "Expect parameter list.");
+                None
+            }
+        }
+    }
+
+    /// Compiles a function taking `params` and leaves a closure of it on the stack.
+    fn function(&mut self, name: &str, params: &[&Datum], body: &[Datum], position: Position) {
+        let (function, upvalues) = self.function_object(name, params, body, position);
+        let function = allocate_function(self.vm, function);
+        let constant = self.make_constant(function, position);
+        self.compiler
+            .emit_indexed(OpCode::Closure, constant, position);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index, position);
+        }
+    }
+
+    /// Compiles a function taking `params`, returning it along with the
+    /// variables it captures.
+    fn function_object(
+        &mut self,
+        name: &str,
+        params: &[&Datum],
+        body: &[Datum],
+        position: Position,
+    ) -> (ObjFunction, Vec<Upvalue>) {
+        push_compiler(
+            &mut self.compiler,
+            FunctionType::Function,
+            Some(name.to_string()),
+            self.source.clone(),
+        );
+        self.compiler.scope_depth += 1;
+
+        if params.len() > u8::MAX as usize {
+            self.error(position, "Can't have more than 255 parameters.");
+        }
+        for param in params {
+            let name = match self.variable_name(param) {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(declared) = self.compiler.declaration(name) {
+                let span = Span::new(&self.source, declared, name.chars().count());
+                let message = "Already a variable with this name in this scope.";
+                let error = self.error_at(param, message);
+                error.notes.push(Note::new("The earlier declaration is here.", Some(span)));
+            }
+            if let Err(message) = self.compiler.add_local(name.to_string(), param.position) {
+                self.error_at(param, message);
+            }
+            self.compiler.mark_initialized();
+            self.compiler.function.arity += 1;
+        }
+
+        // A docstring is not part of the body.
+        let body = match body.split_first() {
+            Some((first, rest)) if !rest.is_empty() => match first.kind {
+                DatumKind::String(_) => rest,
+                _ => body,
+            },
+            _ => body,
+        };
+        self.body(body, position);
+        self.emit_byte(OpCode::Return as u8, position);
+
+        let upvalues = std::mem::take(&mut self.compiler.upvalues);
+        let function = pop_compiler(&mut self.compiler, self.vm, !self.errors.is_empty());
+        (function, upvalues)
+    }
+
+    fn emit_call(&mut self, arg_count: usize, position: Position) {
+        if arg_count > u8::MAX as usize {
+            self.error(position, "Can't have more than 255 arguments.");
+        }
+        self.emit_bytes(OpCode::Call as u8, arg_count as u8, position);
+    }
+
+    fn call(&mut self, callee: &Datum, args: &[Datum], position: Position) {
+        self.operands(std::iter::once(callee).chain(args));
+        self.emit_call(args.len(), position);
+    }
+
+    fn unary(&mut self, name: &str, args: &[Datum], position: Position, ops: &[OpCode]) {
+        if !self.check_arity(name, args, 1, Some(1), position) {
+            return;
+        }
+        self.expression(&args[0]);
+        for op in ops {
+            self.emit_byte(*op as u8, position);
+        }
+    }
+
+    fn binary(&mut self, name: &str, args: &[Datum], position: Position, ops: &[OpCode]) {
+        if !self.check_arity(name, args, 2, Some(2), position) {
+            return;
+        }
+        self.operands(args);
+        for op in ops {
+            self.emit_byte(*op as u8, position);
+        }
+    }
+
+    /// Combines `args` from left to right with `op`, or gives `identity` if there are none.
+    fn arithmetic(&mut self, args: &[Datum], position: Position, op: OpCode, identity: i64) {
+        let (first, rest) = match args.split_first() {
+            Some(split) => split,
+            None => return self.emit_constant(identity, position),
+        };
+        self.expression(first);
+        for arg in rest {
+            // The value so far waits on the stack for the next argument.
+            let base = self.compiler.local_count;
+            self.push_temporary(position);
+            self.expression(arg);
+            self.compiler.local_count = base;
+            self.emit_byte(op as u8, position);
+        }
+    }
+
+    fn minus(&mut self, args: &[Datum], position: Position) {
+        if args.len() == 1 {
+            self.expression(&args[0]);
+            self.emit_byte(OpCode::Negate as u8, position);
+        } else {
+            self.arithmetic(args, position, OpCode::Subtract, 0);
+        }
+    }
+
+    /// `(floor x)` rounds `x` down to an integer, and `(floor x y)`
+    /// divides `x` by `y` and rounds the quotient down.
+    fn floor(&mut self, args: &[Datum], position: Position) {
+        if !self.check_arity("floor", args, 1, Some(2), position) {
+            return;
+        }
+        match args.get(1) {
+            Some(_) => self.operands(args),
+            None => {
+                self.expression(&args[0]);
+                self.emit_constant(1, position);
+            }
+        }
+        self.emit_byte(OpCode::IntDivide as u8, position);
+    }
+
+    /// Finishes the top-level code and returns its function.
+    fn end_compiler(&mut self, position: Position) -> ObjFunction {
+        self.emit_bytes(OpCode::Nil as u8, OpCode::Return as u8, position);
+        pop_compiler(&mut self.compiler, self.vm, !self.errors.is_empty())
+    }
+}
+
+/// Returns the argument of `datum` if it is the list `(name argument)`.
+fn quoted<'d>(datum: &'d Datum, name: &str) -> Option<&'d Datum> {
+    match datum.kind {
+        DatumKind::List(ref items) if items.len() == 2 && items[0].symbol() == Some(name) => {
+            Some(&items[1])
+        }
+        _ => None,
+    }
+}
+
+/// Whether a backquoted `datum`, nested `depth` backquotes deep, has
+/// anything to evaluate.
+fn has_unquote(datum: &Datum, depth: usize) -> bool {
+    if let Some(inner) = quoted(datum, "unquote").or_else(|| quoted(datum, "unquote-splicing")) {
+        return depth == 0 || has_unquote(inner, depth - 1);
+    }
+    if let Some(inner) = quoted(datum, "quasiquote") {
+        return has_unquote(inner, depth + 1);
+    }
+    match datum.kind {
+        DatumKind::List(ref items) => items.iter().any(|item| has_unquote(item, depth)),
+        DatumKind::DottedList(ref items, ref tail) => {
+            items.iter().any(|item| has_unquote(item, depth)) || has_unquote(tail, depth)
+        }
+        _ => false,
+    }
+}
+
+/// Rewrites a backquoted `datum` into calls to `list` and `append`
+/// that build it.
+fn backquote(datum: &Datum, depth: usize) -> Datum {
+    let position = datum.position;
+    let symbol = |name| Datum::new_symbol(name, position);
+    let list = |items| Datum::new_list(items, position);
+    let quote = |datum| list(vec![symbol("quote"), datum]);
+    if !has_unquote(datum, depth) {
+        return quote(datum.clone());
+    }
+    for name in ["unquote", "unquote-splicing"] {
+        if let Some(inner) = quoted(datum, name) {
+            if depth == 0 {
+                return inner.clone();
+            }
+            return list(vec![symbol("list"), quote(symbol(name)), backquote(inner, depth - 1)]);
+        }
+    }
+    if let Some(inner) = quoted(datum, "quasiquote") {
+        let inner = backquote(inner, depth + 1);
+        return list(vec![symbol("list"), quote(symbol("quasiquote")), inner]);
+    }
+
+    let (items, tail) = match datum.kind {
+        DatumKind::List(ref items) => (items, symbol("nil")),
+        DatumKind::DottedList(ref items, ref tail) => (items, backquote(tail, depth)),
+        _ => unreachable!("atoms have nothing to unquote"),
+    };
+    // Runs of items that aren't spliced in are gathered into one call to `list`.
+    let mut segments = vec![symbol("append")];
+    let mut run = vec![symbol("list")];
+    for item in items {
+        match quoted(item, "unquote-splicing") {
+            Some(inner) if depth == 0 => {
+                if run.len() > 1 {
+                    segments.push(list(std::mem::replace(&mut run, vec![symbol("list")])));
+                }
+                segments.push(inner.clone());
+            }
+            _ => run.push(backquote(item, depth)),
+        }
+    }
+    if run.len() > 1 {
+        segments.push(list(run));
+    }
+    segments.push(tail);
+    list(segments)
+}
+
+/// Compiles the S-expressions in `source` into the function for its
+/// top-level code. If `repl` is set the function returns the value of
+/// the last one.
+pub fn compile(vm: &mut Vm, source: Rc<Source>, repl: bool) -> InterpretResult<Value> {
+    let mut reader = Reader::new(&source.text);
+    let mut compiler = LispCompiler::new(vm, source.clone());
+    let mut position = Position { line: 1, column: 1 };
+    // The value of each form is popped once it is known not to be the last.
+    let mut has_value = false;
+
+    while let Some(datum) = reader.read() {
+        match datum {
+            Ok(datum) => {
+                if has_value {
+                    compiler.emit_byte(OpCode::Pop as u8, position);
+                }
+                compiler.expression(&datum);
+                has_value = true;
+                position = datum.position;
+            }
+            Err(error) => {
+                // The rest of the source can't be read reliably.
+                let kind = ErrorKind::Read;
+                compiler.error_of(kind, error.position, error.message).incomplete = error.incomplete;
+                break;
+            }
+        }
+    }
+
+    if has_value {
+        let op = if repl { OpCode::Return } else { OpCode::Pop };
+        compiler.emit_byte(op as u8, position);
+    }
+    let function = compiler.end_compiler(position);
+    let result = if !compiler.had_error() {
+        Ok(allocate_function(compiler.vm, function))
+    } else {
+        Err(VmErr::CompileError(std::mem::take(&mut compiler.errors)))
+    };
+    compiler.vm.compiler_roots.clear();
+    result
+}
@@ -0,0 +1,99 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Expands the macros defined with `defmacro`.
+
+A macro is a closure run by the compiler. It is called with the
+unevaluated arguments of a form as values, quoted the way `quote` would
+quote them, and returns the form to compile in its place.
+ */
+use crate::value::{
+    objects::{ObjCons, ObjId},
+    Value, ValueType,
+};
+use crate::vm::{InterpretResult, Vm};
+
+/// How many times a form may expand before it is assumed to never stop.
+pub(super) const EXPANSION_MAX: usize = 256;
+
+/// A macro defined with `defmacro`.
+#[derive(Clone)]
+pub struct Macro {
+    pub closure: Value,
+    /// The number of parameters before `&rest`.
+    pub required: usize,
+    /// Whether the arguments after the required ones are passed as a list.
+    pub rest: bool,
+}
+
+/// Returns the macro `form` calls, if it is a call to one.
+fn form_macro(vm: &Vm, form: Value) -> Option<&Macro> {
+    if !form.is_obj_type(ObjId::Cons) {
+        return None;
+    }
+    let head = vm.heap.get::<ObjCons>(form.as_handle()).car;
+    if !head.is_obj_type(ObjId::Symbol) {
+        return None;
+    }
+    vm.macros.get(vm.heap.symbol(head.as_handle()))
+}
+
+/// Expands `form` once. Returns `None` if it isn't a call to a macro.
+pub fn expand_once(vm: &mut Vm, form: Value) -> InterpretResult<Option<Value>> {
+    let (closure, required, rest) = match form_macro(vm, form) {
+        Some(definition) => (definition.closure, definition.required, definition.rest),
+        None => return Ok(None),
+    };
+
+    let mut args = Vec::new();
+    let mut tail = vm.heap.get::<ObjCons>(form.as_handle()).cdr;
+    while tail.is_obj_type(ObjId::Cons) && (args.len() < required || !rest) {
+        let cons = vm.heap.get::<ObjCons>(tail.as_handle());
+        args.push(cons.car);
+        tail = cons.cdr;
+    }
+    if rest && args.len() == required {
+        // What is left of `form` is already the list of the rest.
+        args.push(tail);
+    } else if !tail.is_type(ValueType::Nil) || args.len() < required {
+        let given = if rest { "at least " } else { "" };
+        return Err(vm.runtime_error(&format!(
+            "Macro expects {}{} arguments but got {}.",
+            given,
+            required,
+            args.len()
+        )));
+    }
+
+    vm.call_function(closure, &args).map(Some)
+}
+
+/// Expands `form` until it is no longer a call to a macro. The forms
+/// within it are left as they are.
+pub fn macroexpand(vm: &mut Vm, mut form: Value) -> InterpretResult<Value> {
+    for _ in 0..EXPANSION_MAX {
+        // The expansion is kept on the stack as the next one may collect garbage.
+        vm.push(form)?;
+        let expansion = expand_once(vm, form)?;
+        vm.pop()?;
+        match expansion {
+            Some(expansion) => form = expansion,
+            None => return Ok(form),
+        }
+    }
+    Err(vm.runtime_error("Macro expansion doesn't terminate."))
+}
+
@@ -17,18 +17,28 @@
 extern crate eswm_proc;
 // use std::rc::Rc;
 // use std::cell::RefCell;
-use crate::lib::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, Position, Source};
 #[cfg(feature = "debug_print_code")]
-use crate::lib::debug::disassemble_chunk;
-use crate::value::Value;
-use crate::vm::{allocate_string, InterpretResult, Vm, VmErr};
+use crate::debug::disassemble_chunk;
+use crate::error::{Diagnostic, ErrorKind, Note, Span};
+use crate::value::{objects::ObjFunction, Value, FIXNUM_MAX, FIXNUM_MIN};
+use crate::vm::{allocate_function, allocate_string, InterpretResult, Vm, VmErr};
 
 use eswm_proc::rule;
+use std::rc::Rc;
 
+mod lisp;
+mod macros;
+mod optimize;
+mod reader;
 mod scanner;
+pub use macros::{macroexpand, Macro};
+pub use optimize::OptLevel;
 use scanner::{Scanner, Token, TokenType};
 
 const UINT8_COUNT: usize = u8::MAX as usize + 1;
+/// The largest constant index a long instruction can hold.
+const LONG_INDEX_MAX: usize = (1 << 24) - 1;
 
 #[derive(Default, Copy, Clone)]
 struct ParseRule {
@@ -43,70 +53,308 @@ fn gen_compiler_stack() -> Vec<Local> {
     n
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Local {
-    name: Token,
+    name: String,
+    /// Where the variable is declared.
+    position: Position,
     depth: isize,
+    /// Whether a closure captures this variable.
+    is_captured: bool,
 }
 
-impl Default for Local {
-    fn default() -> Local {
-	Local {
-	    name: Token::default(),
-	    depth: 0,
-	}
-    }
+/// A variable a function captures from an enclosing function.
+#[derive(Clone, Copy)]
+pub struct Upvalue {
+    /// The slot of the local or index of the enclosing function's upvalue.
+    index: u8,
+    /// Whether `index` refers to a local of the enclosing function.
+    is_local: bool,
 }
 
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum FunctionType {
+    Function,
+    Script,
+}
+
 pub struct Compiler {
+    /// The compiler of the function this one is nested in.
+    enclosing: Option<Box<Compiler>>,
+    function: ObjFunction,
+    function_type: FunctionType,
     locals: Vec<Local>,
     local_count: usize,
+    upvalues: Vec<Upvalue>,
     scope_depth: isize,
 }
 
 impl Compiler {
-    pub fn new() -> Compiler {
+    pub fn new(function_type: FunctionType) -> Compiler {
 	Compiler {
+	    enclosing: None,
+	    function: ObjFunction::new(),
+	    function_type,
 	    locals: gen_compiler_stack(),
-	    local_count: 0,
+	    // The first slot is claimed by the function being called.
+	    local_count: 1,
+	    upvalues: Vec::new(),
 	    scope_depth: 0,
 	}
     }
 
     fn mark_initialized(&mut self) {
+	if self.scope_depth == 0 {
+	    return;
+	}
 	let depth = self.scope_depth;
 	self.locals[self.local_count - 1].depth = depth;
     }
 
+    fn add_local(&mut self, name: String, position: Position) -> Result<(), &'static str> {
+	if self.local_count == UINT8_COUNT {
+	    return Err("Too many local variables in fuction.");
+	}
+
+	let local = &mut self.locals[self.local_count];
+	self.local_count += 1;
+	local.name = name;
+	local.position = position;
+	local.depth = -1;
+	local.is_captured = false;
+	Ok(())
+    }
+
+    /// Returns where `name` is declared in the innermost scope, if it is.
+    fn declaration(&self, name: &str) -> Option<Position> {
+	let mut i = self.local_count as isize - 1;
+	while i >= 0 {
+	    let local = &self.locals[i as usize];
+	    if local.depth != -1 && local.depth < self.scope_depth {
+		break;
+	    }
+
+	    if local.name == name {
+		return Some(local.position);
+	    }
+	    i -= 1;
+	}
+	None
+    }
+
+    fn resolve_local(&self, name: &str) -> Result<Option<u8>, &'static str> {
+	let mut i = self.local_count as isize - 1;
+	while i >= 0 {
+	    let local = &self.locals[i as usize];
+	    if local.name == name {
+		if local.depth == -1 {
+		    return Err("Can't read variable in its own initializer.");
+		}
+
+		return Ok(Some(i as u8));
+	    }
+	    i -= 1;
+	}
+	Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+	let position = self
+	    .upvalues
+	    .iter()
+	    .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local);
+	if let Some(position) = position {
+	    return Ok(position as u8);
+	}
+
+	if self.upvalues.len() == UINT8_COUNT {
+	    return Err("Too many closure variables in function.");
+	}
+
+	self.upvalues.push(Upvalue { index, is_local });
+	self.function.upvalue_count = self.upvalues.len();
+	Ok((self.upvalues.len() - 1) as u8)
+    }
+
+    /// Looks for `name` in the enclosing functions, capturing it along the way.
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>, &'static str> {
+	let enclosing = match self.enclosing.as_mut() {
+	    Some(enclosing) => enclosing,
+	    None => return Ok(None),
+	};
+
+	if let Some(local) = enclosing.resolve_local(name)? {
+	    enclosing.locals[local as usize].is_captured = true;
+	    return self.add_upvalue(local, true).map(Some);
+	}
+
+	match enclosing.resolve_upvalue(name)? {
+	    Some(upvalue) => self.add_upvalue(upvalue, false).map(Some),
+	    None => Ok(None),
+	}
+    }
+
+    fn emit_byte(&mut self, byte: u8, position: Position) {
+	self.function.chunk.write(byte, position);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, position: Position) -> Result<(), &'static str> {
+	self.emit_byte(OpCode::Loop as u8, position);
+
+	// +2 to skip over the operands of the loop instruction itself.
+	let offset = self.function.chunk.code.len() - loop_start + 2;
+	self.emit_byte(((offset >> 8) & 0xff) as u8, position);
+	self.emit_byte((offset & 0xff) as u8, position);
+	if offset > u16::MAX as usize {
+	    return Err("Loop body too large.");
+	}
+	Ok(())
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode, position: Position) -> usize {
+	self.emit_byte(instruction as u8, position);
+	self.emit_byte(0xff, position);
+	self.emit_byte(0xff, position);
+	self.function.chunk.code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> Result<(), &'static str> {
+	// -2 to adjust for the bytecode for the jump offset itself.
+	let jump = self.function.chunk.code.len() - offset - 2;
+
+	if jump > u16::MAX as usize {
+	    return Err("Too much code to jump over.");
+	}
+
+	self.function.chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
+	self.function.chunk.code[offset + 1] = (jump & 0xff) as u8;
+	Ok(())
+    }
+
+    fn add_constant(&mut self, value: Value) -> Result<usize, &'static str> {
+	let constant = self.function.chunk.constant(value);
+	if constant > LONG_INDEX_MAX {
+	    return Err("Too many constants in one chunk.");
+	}
+	Ok(constant)
+    }
+
+    /// Emits `instruction` with the constant `index` as its operand,
+    /// switching to the long form of `instruction` if `index` does not fit in a byte.
+    fn emit_indexed(&mut self, instruction: OpCode, index: usize, position: Position) {
+	match instruction.long() {
+	    Some(long) if index > u8::MAX as usize => {
+		self.emit_byte(long as u8, position);
+		self.emit_byte(((index >> 16) & 0xff) as u8, position);
+		self.emit_byte(((index >> 8) & 0xff) as u8, position);
+		self.emit_byte((index & 0xff) as u8, position);
+	    }
+	    _ => {
+		self.emit_byte(instruction as u8, position);
+		self.emit_byte(index as u8, position);
+	    }
+	}
+    }
+
+    /// Emits the instructions that discard the locals of the innermost scope.
+    fn end_scope(&mut self, position: Position) {
+	self.scope_depth -= 1;
+
+	while self.local_count > 0 && self.locals[self.local_count - 1].depth > self.scope_depth {
+	    if self.locals[self.local_count - 1].is_captured {
+		self.emit_byte(OpCode::CloseUpvalue as u8, position);
+	    } else {
+		self.emit_byte(OpCode::Pop as u8, position);
+	    }
+	    self.local_count -= 1;
+	}
+    }
+}
+
+/// Makes `compiler` start on a new function nested in the one it was compiling.
+fn push_compiler(
+    compiler: &mut Box<Compiler>,
+    function_type: FunctionType,
+    name: Option<String>,
+    source: Rc<Source>,
+) {
+    let mut nested = Box::new(Compiler::new(function_type));
+    nested.function.chunk.source = source;
+    nested.function.name = name;
+    let enclosing = std::mem::replace(compiler, nested);
+    compiler.enclosing = Some(enclosing);
+}
+
+/// Finishes the function `compiler` is compiling and returns to the enclosing one.
+fn pop_compiler(compiler: &mut Box<Compiler>, vm: &Vm, had_error: bool) -> ObjFunction {
+    if !had_error {
+        optimize::optimize(&mut compiler.function.chunk, &vm.heap, vm.opt_level);
+    }
+    #[cfg(feature = "debug_print_code")]
+    {
+        if !had_error {
+            let function = &compiler.function;
+            let name = function.name.as_deref().unwrap_or("<script>");
+            disassemble_chunk(&vm.heap, &vm.globals, &function.chunk, name);
+        }
+    }
+
+    match compiler.enclosing.take() {
+        Some(enclosing) => std::mem::replace(compiler, enclosing).function,
+        None => std::mem::take(&mut compiler.function),
+    }
 }
 struct Parser<'a, 'b> {
-    current: Option<Token>,
-    previous: Option<Token>,
-    scanner: &'a mut Scanner,
-    chunk: &'b mut Chunk,
+    current: Option<Token<'a>>,
+    previous: Option<Token<'a>>,
+    scanner: &'a mut Scanner<'a>,
     rule: Option<&'a ParseRule>,
-    had_error: bool,
+    /// The errors found so far.
+    errors: Vec<Diagnostic>,
     panic_mode: bool,
     vm: &'b mut Vm,
-    compiler: &'b mut Compiler,
+    compiler: Box<Compiler>,
+    /// The file being compiled.
+    source: Rc<Source>,
+    /// Whether the source was typed at the repl, which shows the value
+    /// of an expression statement ending it.
+    repl: bool,
 }
 
 impl<'a, 'b> Parser<'a, 'b> {
-    fn new(vm: &'b mut Vm, compiler: &'b mut Compiler, scanner: &'a mut Scanner, chunk: &'b mut Chunk) -> Self {
+    fn new(vm: &'b mut Vm, scanner: &'a mut Scanner<'a>, source: Rc<Source>, repl: bool) -> Self {
+        let mut compiler = Box::new(Compiler::new(FunctionType::Script));
+        compiler.function.chunk.source = source.clone();
         Parser {
             current: None,
             previous: None,
             scanner,
-            chunk,
             rule: None,
-            had_error: false,
+            errors: Vec::new(),
             panic_mode: false,
             vm,
 	    compiler,
+	    source,
+	    repl,
         }
     }
 
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
+    /// Starts compiling a new function nested in the current one.
+    fn init_compiler(&mut self, function_type: FunctionType) {
+        let previous = self.previous.as_ref().unwrap();
+        let name = if previous.id == TokenType::Identifier {
+            previous.string()
+        } else {
+            String::from("lambda")
+        };
+        push_compiler(&mut self.compiler, function_type, Some(name), self.source.clone());
+    }
+
     fn advance(&mut self) {
         self.previous = self.current.clone();
 
@@ -141,8 +389,18 @@ impl<'a, 'b> Parser<'a, 'b> {
         true
     }
 
+    /// The position of the previous token, given to the bytes it compiles to.
+    fn position(&self) -> Position {
+        let previous = self.previous.as_ref().unwrap();
+        Position {
+            line: previous.line,
+            column: previous.column,
+        }
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.previous.as_ref().unwrap().line);
+        let position = self.position();
+        self.compiler.emit_byte(byte, position);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -150,33 +408,59 @@ impl<'a, 'b> Parser<'a, 'b> {
         self.emit_byte(byte2);
     }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        let position = self.position();
+        if let Err(message) = self.compiler.emit_loop(loop_start, position) {
+            self.error(message);
+        }
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        let position = self.position();
+        self.compiler.emit_jump(instruction, position)
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        if let Err(message) = self.compiler.patch_jump(offset) {
+            self.error(message);
+        }
+    }
+
     fn emit_return(&mut self) {
+        self.emit_byte(OpCode::Nil as u8);
         self.emit_byte(OpCode::Return as u8);
     }
 
-    fn make_constant<T: Into<Value>>(&mut self, value: T) -> u8 {
-        let constant = self.chunk.constant(value.into());
-        if constant > u8::MAX {
-            self.error("Too many constants in one chunk");
-            0
-        } else {
-            constant
+    fn make_constant<T: Into<Value>>(&mut self, value: T) -> usize {
+        let value = value.into();
+        // Keep the constant alive should a collection happen before the
+        // function is moved onto the heap.
+        self.vm.compiler_roots.push(value);
+        match self.compiler.add_constant(value) {
+            Ok(constant) => constant,
+            Err(message) => {
+                self.error(message);
+                0
+            }
         }
     }
 
     fn emit_constant<T: Into<Value>>(&mut self, constant: T) {
         let constant = self.make_constant(constant);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        self.emit_indexed(OpCode::Constant, constant);
     }
 
-    fn end_compiler(&mut self) {
+    /// Emits `instruction` with the constant `index` as its operand,
+    /// switching to the long form of `instruction` if `index` does not fit in a byte.
+    fn emit_indexed(&mut self, instruction: OpCode, index: usize) {
+        let position = self.position();
+        self.compiler.emit_indexed(instruction, index, position);
+    }
+
+    /// Finishes the current function and returns to the enclosing compiler.
+    fn end_compiler(&mut self) -> ObjFunction {
         self.emit_return();
-        #[cfg(feature = "debug_print_code")]
-        {
-            if !self.had_error {
-                disassemble_chunk(&self.chunk, "code");
-            }
-        }
+        pop_compiler(&mut self.compiler, self.vm, !self.errors.is_empty())
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
@@ -185,9 +469,12 @@ impl<'a, 'b> Parser<'a, 'b> {
         self.get_rule(new_rule);
 	let can_assign = precedence <= Precedence::Assignment;
         if let Some(rule) = self.rule {
-            
-	    if let Some(rulefn) = rule.prefix {
-                rulefn(self, can_assign);
+            match rule.prefix {
+                Some(rulefn) => rulefn(self, can_assign),
+                None => {
+                    self.error("Expect expression.");
+                    return;
+                }
             }
 
 	    
@@ -217,21 +504,23 @@ impl<'a, 'b> Parser<'a, 'b> {
 	}
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
-         let value = allocate_string(self.vm, name.string());
-        self.make_constant(value)
+    /// Returns the slot of the global variable `name`.
+    fn global_slot(&mut self, name: &Token) -> usize {
+        let slot = self.vm.globals.slot(name.lexeme);
+        if slot > LONG_INDEX_MAX {
+            self.error("Too many global variables.");
+        }
+        slot
     }
 
-    fn add_local(&mut self, name: Token) {
-	if self.compiler.local_count == UINT8_COUNT {
-	    self.error("Too many local variables in fuction.");
-	    return;
+    fn add_local(&mut self, name: &Token) {
+	let position = Position {
+	    line: name.line,
+	    column: name.column,
+	};
+	if let Err(message) = self.compiler.add_local(name.string(), position) {
+	    self.error(message);
 	}
-	
-	let mut local = &mut self.compiler.locals[self.compiler.local_count];
-	self.compiler.local_count += 1;
-	local.name = name;
-	local.depth = -1;
     }
     
     fn declare_variable(&mut self) {
@@ -245,22 +534,16 @@ impl<'a, 'b> Parser<'a, 'b> {
 	    panic!("Expected Token");
 	};
 
-	let mut i = self.compiler.local_count as isize - 1;
-	while i >= 0 {
-	    let local = &self.compiler.locals[i as usize];
-	    if local.depth != -1 && local.depth < self.compiler.scope_depth {
-		break;
-	    }
-
-	    if identifiers_equal(&name, &local.name){
-		self.error("Already a variable with this name in this scope.");
+	if let Some(declared) = self.compiler.declaration(name.lexeme) {
+	    let span = Span::new(&self.source, declared, name.lexeme.chars().count());
+	    if let Some(error) = self.error("Already a variable with this name in this scope.") {
+		error.notes.push(Note::new("The earlier declaration is here.", Some(span)));
 	    }
-	    i -= 1;
 	}
-	self.add_local(name);
+	self.add_local(&name);
     }
     
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
 	self.declare_variable();
 
@@ -269,19 +552,19 @@ impl<'a, 'b> Parser<'a, 'b> {
 	}
 	
         let token = self.previous.as_ref().unwrap().clone();
-        self.identifier_constant(&token)
+        self.global_slot(&token)
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
 	if self.compiler.scope_depth > 0 {
 	    self.compiler.mark_initialized();
 	    return;
 	}       
-	self.emit_bytes(OpCode::DefineGlobal as u8, global);
+	self.emit_indexed(OpCode::DefineGlobal, global);
     }
 
     fn get_rule(&mut self, id: TokenType) {
-        if id as usize > 39 {
+        if id as usize >= RULES.len() {
             self.rule = None;
             return;
         }
@@ -293,67 +576,77 @@ impl<'a, 'b> Parser<'a, 'b> {
     }
 
     fn end_scope(&mut self) {
-	self.compiler.scope_depth -= 1;
-
-	while self.compiler.local_count > 0 && (self.compiler.locals[self.compiler.local_count - 1].depth) as isize > self.compiler.scope_depth {
-	    self.emit_byte(OpCode::Pop as u8);
-	    self.compiler.local_count -= 1;
-	}
-
+	let position = self.position();
+	self.compiler.end_scope(position);
     }
 
     fn resolve_local(&mut self, name: &Token) -> Result<u8, ()> {
-	if self.compiler.local_count == 0 {
-	    return Err(());
+	match self.compiler.resolve_local(name.lexeme) {
+	    Ok(Some(slot)) => Ok(slot),
+	    Ok(None) => Err(()),
+	    Err(message) => {
+		self.error(message);
+		Ok(0)
+	    }
 	}
-	
-	let mut i = (self.compiler.local_count - 1) as isize;
-	while i >= 0 {
-	    let local = &self.compiler.locals[i as usize];
-	    if identifiers_equal(name, &local.name) {
-		if local.depth == -1 {
-		    self.error("Can't read variable in its own initializer.");
-		}
+    }
 
-		return Ok(i as u8);
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<u8, ()> {
+	match self.compiler.resolve_upvalue(name.lexeme) {
+	    Ok(Some(index)) => Ok(index),
+	    Ok(None) => Err(()),
+	    Err(message) => {
+		self.error(message);
+		Ok(0)
 	    }
-	    i -= 1;
 	}
-	Err(())
     }
 
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(&self.current.clone().unwrap(), message);
+    fn error_at_current(&mut self, message: &str) -> Option<&mut Diagnostic> {
+        self.error_at(&self.current.clone().unwrap(), message)
     }
 
-    fn error(&mut self, message: &str) {
-        self.error_at(&self.previous.clone().unwrap(), message);
+    fn error(&mut self, message: &str) -> Option<&mut Diagnostic> {
+        self.error_at(&self.previous.clone().unwrap(), message)
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
+    /// Records an error at `token`, returning it so notes can be added.
+    /// Returns [`None`] if the error is suppressed while recovering from
+    /// an earlier one.
+    fn error_at(&mut self, token: &Token, message: &str) -> Option<&mut Diagnostic> {
         if self.panic_mode {
-            return;
+            return None;
         }
 
         self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-
-        if token.id == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.id == TokenType::Error {
-            // Nothing.
-        } else {
-            eprint!(" at '{}'", token.string())
-        }
-
-        eprintln!(": {}", message);
-        self.had_error = true;
+        let kind = match token.id {
+            TokenType::Error => ErrorKind::Read,
+            _ => ErrorKind::Compile,
+        };
+        let position = Position {
+            line: token.line,
+            column: token.column,
+        };
+        let length = match token.id {
+            // The lexeme of an error token is its message.
+            TokenType::Error | TokenType::Eof => 0,
+            _ => token.lexeme.chars().count(),
+        };
+        let span = Span::new(&self.source, position, length);
+        let mut error = Diagnostic::new(kind, message, Some(span));
+        error.found = match token.id {
+            TokenType::Eof => Some(String::from("end")),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", token.string())),
+        };
+        // An error token at the end is a string left open.
+        error.incomplete = token.id == TokenType::Eof
+            || (token.id == TokenType::Error && self.scanner.is_at_end());
+        self.errors.push(error);
+        self.errors.last_mut()
     }
 }
-fn identifiers_equal(a: &Token, b: &Token ) -> bool {
-    a.string() == b.string()
-}
 fn binary(parser: &mut Parser, _can_assign: bool) {
     let operator_id = parser.previous.as_ref().unwrap().id;
     parser.get_rule(operator_id);
@@ -372,10 +665,60 @@ fn binary(parser: &mut Parser, _can_assign: bool) {
         TokenType::Minus => parser.emit_byte(OpCode::Subtract as u8),
         TokenType::Star => parser.emit_byte(OpCode::Multiply as u8),
         TokenType::Slash => parser.emit_byte(OpCode::Divide as u8),
+        TokenType::TildeSlash => parser.emit_byte(OpCode::IntDivide as u8),
+        TokenType::Percent => parser.emit_byte(OpCode::Modulo as u8),
+        TokenType::Ampersand => parser.emit_byte(OpCode::BitAnd as u8),
+        TokenType::Pipe => parser.emit_byte(OpCode::BitOr as u8),
+        TokenType::Caret => parser.emit_byte(OpCode::BitXor as u8),
+        TokenType::LessLess => parser.emit_byte(OpCode::ShiftLeft as u8),
+        TokenType::GreaterGreater => parser.emit_byte(OpCode::ShiftRight as u8),
         _ => unreachable!(),
     }
 }
 
+fn and_(parser: &mut Parser, _can_assign: bool) {
+    let end_jump = parser.emit_jump(OpCode::JumpIfFalse);
+
+    parser.emit_byte(OpCode::Pop as u8);
+    parser.parse_precedence(Precedence::And);
+
+    parser.patch_jump(end_jump);
+}
+
+fn or_(parser: &mut Parser, _can_assign: bool) {
+    let else_jump = parser.emit_jump(OpCode::JumpIfFalse);
+    let end_jump = parser.emit_jump(OpCode::Jump);
+
+    parser.patch_jump(else_jump);
+    parser.emit_byte(OpCode::Pop as u8);
+
+    parser.parse_precedence(Precedence::Or);
+    parser.patch_jump(end_jump);
+}
+
+fn call(parser: &mut Parser, _can_assign: bool) {
+    let arg_count = argument_list(parser);
+    parser.emit_bytes(OpCode::Call as u8, arg_count);
+}
+
+fn argument_list(parser: &mut Parser) -> u8 {
+    let mut arg_count: usize = 0;
+    if !parser.check(TokenType::RightParen) {
+        loop {
+            expression(parser);
+            if arg_count == u8::MAX as usize {
+                parser.error("Can't have more than 255 arguments.");
+            }
+            arg_count += 1;
+            if !parser.matches(TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    parser.consume(TokenType::RightParen, "Expect ')' after arguments.");
+    arg_count as u8
+}
+
 fn literal(parser: &mut Parser, _can_assign: bool) {
     match parser.previous.as_ref().unwrap().id {
         TokenType::False => parser.emit_byte(OpCode::False as u8),
@@ -387,21 +730,44 @@ fn literal(parser: &mut Parser, _can_assign: bool) {
 
 fn grouping(parser: &mut Parser, _can_assign: bool) {
     expression(parser);
-    parser.consume(TokenType::LeftParen, "Exect ')' after expression.");
+    parser.consume(TokenType::RightParen, "Exect ')' after expression.");
 }
 
 fn number(parser: &mut Parser, _can_assign: bool) {
-    let value: f64 = parser
-        .previous
-        .as_ref()
-        .unwrap()
-        .string()
-        .as_str()
-        .parse()
-        .unwrap();
+    let lexeme = parser.previous.as_ref().unwrap().string();
+    let (digits, radix) = match lexeme.get(..2) {
+        Some("0x" | "0X") => (&lexeme[2..], 16),
+        Some("0o" | "0O") => (&lexeme[2..], 8),
+        Some("0b" | "0B") => (&lexeme[2..], 2),
+        _ => (lexeme.as_str(), 10),
+    };
+    let value = match parse_integer(digits, radix) {
+        Ok(integer) => Value::from(integer),
+        Err(message) if radix != 10 => {
+            parser.error(message);
+            return;
+        }
+        // Decimals with a fraction and integers too large to be fixnums
+        // are floats.
+        Err(_) => Value::from(lexeme.parse::<f64>().unwrap()),
+    };
     parser.emit_constant(value);
 }
 
+/// Parses the digits of an integer literal written in `radix`, with an
+/// optional sign. Gives an error message if they aren't all digits or
+/// don't fit in a fixnum.
+fn parse_integer(digits: &str, radix: u32) -> Result<i64, &'static str> {
+    let unsigned = digits.strip_prefix(['+', '-']).unwrap_or(digits);
+    if unsigned.is_empty() || !unsigned.chars().all(|c| c.is_digit(radix)) {
+        return Err("Invalid integer literal.");
+    }
+    match i64::from_str_radix(digits, radix) {
+        Ok(integer) if (FIXNUM_MIN..=FIXNUM_MAX).contains(&integer) => Ok(integer),
+        _ => Err("Integer literal is too large."),
+    }
+}
+
 fn string(parser: &mut Parser, _can_assign: bool) {
     let string = String::from(&parser.previous.as_ref().unwrap().string());
     let mut string = string.chars();
@@ -419,26 +785,30 @@ fn variable(parser: &mut Parser, can_assign: bool) {
 }
 
 fn named_variable(parser: &mut Parser, token: &Token, can_assign: bool) {
-    let get_op: u8;
-    let set_op: u8;
-    let arg: u8;
+    let get_op: OpCode;
+    let set_op: OpCode;
+    let arg: usize;
 
     if let Ok(v) = parser.resolve_local(token) {
-	arg = v;
-	get_op = OpCode::GetLocal as u8;
-	set_op = OpCode::SetLocal as u8;
+	arg = v as usize;
+	get_op = OpCode::GetLocal;
+	set_op = OpCode::SetLocal;
+    } else if let Ok(v) = parser.resolve_upvalue(token) {
+	arg = v as usize;
+	get_op = OpCode::GetUpvalue;
+	set_op = OpCode::SetUpvalue;
     } else {
-	arg = parser.identifier_constant(token);
-	get_op = OpCode::GetGlobal as u8;
-	set_op = OpCode::SetGlobal as u8;
+	arg = parser.global_slot(token);
+	get_op = OpCode::GetGlobal;
+	set_op = OpCode::SetGlobal;
     }
 
 
     if can_assign && parser.matches(TokenType::Equal) {
         expression(parser);
-        parser.emit_bytes(set_op, arg);
+        parser.emit_indexed(set_op, arg);
     } else {
-        parser.emit_bytes(get_op, arg);
+        parser.emit_indexed(get_op, arg);
     }
 }
 
@@ -452,6 +822,7 @@ fn unary(parser: &mut Parser, _can_assign: bool) {
     match operator_type {
         TokenType::Bang => parser.emit_byte(OpCode::Not as u8),
         TokenType::Minus => parser.emit_byte(OpCode::Negate as u8),
+        TokenType::Tilde => parser.emit_byte(OpCode::BitNot as u8),
         _ => unreachable!(),
     }
 }
@@ -468,8 +839,53 @@ fn block(parser: &mut Parser) {
     parser.consume(TokenType::RightBrace, "Expect '}' after block.");
 }
 
+fn function(parser: &mut Parser, function_type: FunctionType) {
+    parser.init_compiler(function_type);
+    parser.begin_scope();
+
+    parser.consume(TokenType::LeftParen, "Expect '(' after function name.");
+    if !parser.check(TokenType::RightParen) {
+        loop {
+            parser.compiler.function.arity += 1;
+            if parser.compiler.function.arity > u8::MAX as usize {
+                parser.error_at_current("Can't have more than 255 parameters.");
+            }
+            let constant = parser.parse_variable("Expect parameter name.");
+            parser.define_variable(constant);
+            if !parser.matches(TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    parser.consume(TokenType::RightParen, "Expect ')' after parameters.");
+    parser.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+    block(parser);
+
+    let upvalues = std::mem::take(&mut parser.compiler.upvalues);
+    let function = parser.end_compiler();
+    let function = allocate_function(parser.vm, function);
+    let constant = parser.make_constant(function);
+    parser.emit_indexed(OpCode::Closure, constant);
+
+    for upvalue in upvalues {
+        parser.emit_byte(upvalue.is_local as u8);
+        parser.emit_byte(upvalue.index);
+    }
+}
+
+fn lambda(parser: &mut Parser, _can_assign: bool) {
+    function(parser, FunctionType::Function);
+}
+
+fn fun_decleration(parser: &mut Parser) {
+    let global = parser.parse_variable("Expect function name.");
+    parser.compiler.mark_initialized();
+    function(parser, FunctionType::Function);
+    parser.define_variable(global);
+}
+
 fn var_decleration(parser: &mut Parser) {
-    let global: u8 = parser.parse_variable("Expect variable name.");
+    let global = parser.parse_variable("Expect variable name.");
 
     if parser.matches(TokenType::Equal) {
         expression(parser);
@@ -486,7 +902,9 @@ fn var_decleration(parser: &mut Parser) {
 }
 
 fn decleration(parser: &mut Parser) {
-    if parser.matches(TokenType::Var) {
+    if parser.matches(TokenType::Fun) {
+        fun_decleration(parser);
+    } else if parser.matches(TokenType::Var) {
         var_decleration(parser);
     } else {
         statement(parser);
@@ -500,6 +918,14 @@ fn decleration(parser: &mut Parser) {
 fn statement(parser: &mut Parser) {
     if parser.matches(TokenType::Print) {
         print_statement(parser);
+    } else if parser.matches(TokenType::For) {
+        for_statement(parser);
+    } else if parser.matches(TokenType::If) {
+        if_statement(parser);
+    } else if parser.matches(TokenType::Return) {
+        return_statement(parser);
+    } else if parser.matches(TokenType::While) {
+        while_statement(parser);
     } else if parser.matches(TokenType::LeftBrace) {
 	parser.begin_scope();
 	block(parser);
@@ -509,6 +935,121 @@ fn statement(parser: &mut Parser) {
     }
 }
 
+fn return_statement(parser: &mut Parser) {
+    if parser.compiler.function_type == FunctionType::Script {
+        parser.error("Can't return from top-level code.");
+    }
+
+    if parser.matches(TokenType::Semicolon) {
+        parser.emit_return();
+    } else {
+        expression(parser);
+        parser.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        parser.emit_byte(OpCode::Return as u8);
+    }
+}
+
+fn for_statement(parser: &mut Parser) {
+    parser.begin_scope();
+    parser.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+    let mut loop_variable = None;
+    if parser.matches(TokenType::Semicolon) {
+        // No initializer.
+    } else if parser.matches(TokenType::Var) {
+        var_decleration(parser);
+        loop_variable = Some(parser.compiler.local_count - 1);
+    } else {
+        expression_statement(parser);
+    }
+
+    let mut loop_start = parser.chunk().code.len();
+    let mut exit_jump = None;
+    if !parser.matches(TokenType::Semicolon) {
+        expression(parser);
+        parser.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+        // Jump out of the loop if the condition is false.
+        exit_jump = Some(parser.emit_jump(OpCode::JumpIfFalse));
+        parser.emit_byte(OpCode::Pop as u8);
+    }
+
+    if !parser.matches(TokenType::RightParen) {
+        let body_jump = parser.emit_jump(OpCode::Jump);
+        let increment_start = parser.chunk().code.len();
+        expression(parser);
+        parser.emit_byte(OpCode::Pop as u8);
+        parser.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        parser.emit_loop(loop_start);
+        loop_start = increment_start;
+        parser.patch_jump(body_jump);
+    }
+
+    // Each pass of the body gets its own copy of the loop variable, so a
+    // closure made in the body keeps the value of its pass.
+    parser.begin_scope();
+    let copy = loop_variable.map(|slot| {
+        let local = &parser.compiler.locals[slot];
+        let (name, position) = (local.name.clone(), local.position);
+        parser.emit_bytes(OpCode::GetLocal as u8, slot as u8);
+        if let Err(message) = parser.compiler.add_local(name, position) {
+            parser.error(message);
+        }
+        parser.compiler.mark_initialized();
+        parser.compiler.local_count - 1
+    });
+    statement(parser);
+    if let (Some(slot), Some(copy)) = (loop_variable, copy) {
+        // Assignments in the body carry over to the next pass.
+        parser.emit_bytes(OpCode::GetLocal as u8, copy as u8);
+        parser.emit_bytes(OpCode::SetLocal as u8, slot as u8);
+        parser.emit_byte(OpCode::Pop as u8);
+    }
+    parser.end_scope();
+    parser.emit_loop(loop_start);
+
+    if let Some(exit_jump) = exit_jump {
+        parser.patch_jump(exit_jump);
+        parser.emit_byte(OpCode::Pop as u8);
+    }
+    parser.end_scope();
+}
+
+fn while_statement(parser: &mut Parser) {
+    let loop_start = parser.chunk().code.len();
+    parser.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+    expression(parser);
+    parser.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+    let exit_jump = parser.emit_jump(OpCode::JumpIfFalse);
+    parser.emit_byte(OpCode::Pop as u8);
+    statement(parser);
+    parser.emit_loop(loop_start);
+
+    parser.patch_jump(exit_jump);
+    parser.emit_byte(OpCode::Pop as u8);
+}
+
+fn if_statement(parser: &mut Parser) {
+    parser.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+    expression(parser);
+    parser.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+    let then_jump = parser.emit_jump(OpCode::JumpIfFalse);
+    parser.emit_byte(OpCode::Pop as u8);
+    statement(parser);
+
+    let else_jump = parser.emit_jump(OpCode::Jump);
+
+    parser.patch_jump(then_jump);
+    parser.emit_byte(OpCode::Pop as u8);
+
+    if parser.matches(TokenType::Else) {
+        statement(parser);
+    }
+    parser.patch_jump(else_jump);
+}
+
 fn print_statement(parser: &mut Parser) {
     expression(parser);
     parser.consume(TokenType::Semicolon, "Expected ';' after value.");
@@ -541,12 +1082,27 @@ fn synchronize(parser: &mut Parser) {
 
 fn expression_statement(parser: &mut Parser) {
     expression(parser);
-    parser.consume(TokenType::Semicolon, "Expect ';' after expression.");
+    let top_level = parser.compiler.function_type == FunctionType::Script
+        && parser.compiler.scope_depth == 0;
+    if parser.repl && top_level {
+        // The script returns the value of the expression ending it, and
+        // the semicolon after it may be left out.
+        if !parser.check(TokenType::Eof) {
+            parser.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        }
+        if parser.check(TokenType::Eof) {
+            parser.emit_byte(OpCode::Return as u8);
+            return;
+        }
+    } else {
+        parser.consume(TokenType::Semicolon, "Expect ';' after expression.");
+    }
     parser.emit_byte(OpCode::Pop as u8);
 }
 
-#[derive(Eq, Ord, PartialEq, PartialOrd, Copy, Clone, Debug)]
+#[derive(Eq, Ord, PartialEq, PartialOrd, Copy, Clone, Debug, Default)]
 enum Precedence {
+    #[default]
     None,
     /// =
     Assignment,
@@ -558,11 +1114,19 @@ enum Precedence {
     Equality,
     /// < > <= >=
     Comparison,
+    /// |
+    BitOr,
+    /// ^
+    BitXor,
+    /// &
+    BitAnd,
+    /// << >>
+    Shift,
     /// + -
     Term,
-    /// * /
+    /// * / ~/ %
     Factor,
-    /// ! -
+    /// ! - ~
     Unary,
     /// . ()
     Call,
@@ -577,7 +1141,11 @@ impl Precedence {
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
             Precedence::Unary => Precedence::Call,
@@ -588,16 +1156,10 @@ impl Precedence {
     }
 }
 
-impl Default for Precedence {
-    fn default() -> Self {
-        Precedence::None
-    }
-}
-
 #[rustfmt::skip]
-const RULES: [ParseRule; 40] = [
+const RULES: [ParseRule; 48] = [
     // Single character tokens.
-    rule!((TokenType::LeftParen   , Some(grouping), None        , Precedence::None      )),
+    rule!((TokenType::LeftParen   , Some(grouping), Some(call)  , Precedence::Call      )),
     rule!((TokenType::RightParan  , None          , None        , Precedence::None      )),
     rule!((TokenType::LeftBrace   , None          , None        , Precedence::None      )),
     rule!((TokenType::RightBrace  , None          , None        , Precedence::None      )),
@@ -608,7 +1170,13 @@ const RULES: [ParseRule; 40] = [
     rule!((TokenType::Semicolon   , None          , None        , Precedence::None      )),
     rule!((TokenType::Slash       , None          , Some(binary), Precedence::Factor    )),
     rule!((TokenType::Star        , None          , Some(binary), Precedence::Factor    )),
+    rule!((TokenType::Percent     , None          , Some(binary), Precedence::Factor    )),
+    rule!((TokenType::Ampersand   , None          , Some(binary), Precedence::BitAnd    )),
+    rule!((TokenType::Pipe        , None          , Some(binary), Precedence::BitOr     )),
+    rule!((TokenType::Caret       , None          , Some(binary), Precedence::BitXor    )),
+    rule!((TokenType::Tilde       , Some(unary)   , None        , Precedence::None      )),
     // One or two character tokens						        
+    rule!((TokenType::TildeSlash  , None          , Some(binary), Precedence::Factor    )),
     rule!((TokenType::Bang        , Some(unary)   , None        , Precedence::None      )),
     rule!((TokenType::BangEqual   , None          , Some(binary), Precedence::Equality  )),
     rule!((TokenType::Equal       , None          , None        , Precedence::None      )),
@@ -617,20 +1185,22 @@ const RULES: [ParseRule; 40] = [
     rule!((TokenType::GreaterEqual, None          , Some(binary), Precedence::Comparison)),
     rule!((TokenType::Less        , None          , Some(binary), Precedence::Comparison)),
     rule!((TokenType::LessEqual   , None          , Some(binary), Precedence::Comparison)),
+    rule!((TokenType::LessLess    , None          , Some(binary), Precedence::Shift     )),
+    rule!((TokenType::GreaterGreater, None        , Some(binary), Precedence::Shift     )),
     // Literals						        		    
     rule!((TokenType::Identifier  , Some(variable), None        , Precedence::None      )),
     rule!((TokenType::String      , Some(string)  , None        , Precedence::None      )),
     rule!((TokenType::Number      , Some(number)  , None        , Precedence::None      )),
     // Keywords						        		        
-    rule!((TokenType::And         , None          , None        , Precedence::None      )),
+    rule!((TokenType::And         , None          , Some(and_)  , Precedence::And       )),
     rule!((TokenType::Class       , None          , None        , Precedence::None      )),
     rule!((TokenType::Else        , None          , None        , Precedence::None      )),
     rule!((TokenType::False       , Some(literal) , None        , Precedence::None      )),
     rule!((TokenType::For         , None          , None        , Precedence::None      )),
-    rule!((TokenType::Fun         , None          , None        , Precedence::None      )),
+    rule!((TokenType::Fun         , Some(lambda)  , None        , Precedence::None      )),
     rule!((TokenType::If          , None          , None        , Precedence::None      )),
     rule!((TokenType::Nil         , Some(literal) , None        , Precedence::None      )),
-    rule!((TokenType::Or          , None          , None        , Precedence::None      )),
+    rule!((TokenType::Or          , None          , Some(or_)   , Precedence::Or        )),
     rule!((TokenType::Print       , None          , None        , Precedence::None      )),
     rule!((TokenType::Return      , None          , None        , Precedence::None      )),
     rule!((TokenType::Super       , None          , None        , Precedence::None      )),
@@ -642,23 +1212,71 @@ const RULES: [ParseRule; 40] = [
     rule!((TokenType::Eof         , None          , None        , Precedence::None      )),
 ];
 
-pub fn compile<'a, 'b>(vm: &'a mut Vm, source: &'b str) -> InterpretResult<Chunk> {
-    let mut chunk = Chunk::new();
-    let mut source: Vec<char> = source.chars().collect();
-    source.push('\0');
-    let mut scanner = Scanner::new(&source);
-    let mut compiler = Compiler::new();
-    let mut parser = Parser::new(vm, &mut compiler, &mut scanner, &mut chunk);
+/// The syntaxes source code can be written in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Syntax {
+    /// C-like declarations and statements.
+    Script,
+    /// Emacs Lisp style S-expressions.
+    Lisp,
+}
+
+impl Syntax {
+    /// Picks the syntax of the file at `path` from its extension.
+    pub fn from_path(path: &str) -> Syntax {
+        match std::path::Path::new(path).extension() {
+            Some(extension) if extension == "el" || extension == "lisp" => Syntax::Lisp,
+            _ => Syntax::Script,
+        }
+    }
+}
+
+/// Compiles `source`, read from `file`, into the function for its top-level code.
+pub fn compile(vm: &mut Vm, source: &str, file: &str, syntax: Syntax) -> InterpretResult<Value> {
+    compile_as(vm, source, file, syntax, false)
+}
+
+/// Compiles `source` like [`compile`], but as typed at the repl: the
+/// top-level code returns the value of the expression ending it rather
+/// than nil.
+pub fn compile_repl(vm: &mut Vm, source: &str, file: &str, syntax: Syntax) -> InterpretResult<Value> {
+    compile_as(vm, source, file, syntax, true)
+}
+
+fn compile_as(
+    vm: &mut Vm,
+    source: &str,
+    file: &str,
+    syntax: Syntax,
+    repl: bool,
+) -> InterpretResult<Value> {
+    let source = Rc::new(Source::new(file, source));
+    let checkpoint = vm.checkpoint();
+    let result = match syntax {
+        Syntax::Script => compile_script(vm, source, repl),
+        Syntax::Lisp => lisp::compile(vm, source, repl),
+    };
+    if result.is_err() {
+        vm.restore(checkpoint);
+    }
+    result
+}
+
+fn compile_script(vm: &mut Vm, source: Rc<Source>, repl: bool) -> InterpretResult<Value> {
+    let mut scanner = Scanner::new(&source.text);
+    let mut parser = Parser::new(vm, &mut scanner, source.clone(), repl);
     parser.advance();
 
     while !parser.matches(TokenType::Eof) {
         decleration(&mut parser);
     }
 
-    parser.end_compiler();
-    if !parser.had_error {
-        Ok(chunk)
+    let function = parser.end_compiler();
+    let result = if parser.errors.is_empty() {
+        Ok(allocate_function(parser.vm, function))
     } else {
-        Err(VmErr::CompileError)
-    }
+        Err(VmErr::CompileError(std::mem::take(&mut parser.errors)))
+    };
+    parser.vm.compiler_roots.clear();
+    result
 }
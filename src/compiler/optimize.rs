@@ -0,0 +1,391 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Rewrites the bytecode of a finished function into less of it.
+
+The chunk is decoded into a list of instructions, with jumps pointing
+at the instruction they land on rather than at an offset. Each
+instruction is then appended to the optimized list in turn, and the
+end of that list is rewritten for as long as a rule matches it, so
+`1 + 2 * 3` folds all the way down to one constant. Finally the list
+is encoded back into the chunk, with the jump offsets and the position
+of every instruction worked out again.
+
+No rewrite looks past an instruction a jump lands on, as the code
+before it doesn't run on every path reaching it.
+ */
+use super::LONG_INDEX_MAX;
+use crate::chunk::{long_index, Chunk, OpCode, Position};
+use crate::value::{heap::Heap, Value, ValueType};
+use std::collections::HashSet;
+
+/// How much the compiler optimizes the bytecode it emits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// The bytecode is left as the compiler emitted it.
+    None,
+    /// `Equal` followed by `Not` is fused into `NotEqual`, and values
+    /// that are pushed only to be popped, along with code that can't be
+    /// reached, are removed.
+    Peephole,
+    /// Arithmetic, bitwise operations and comparisons of constants are
+    /// also done while compiling.
+    #[default]
+    Full,
+}
+
+struct Instruction {
+    op: OpCode,
+    /// The bytes following the opcode, other than the offset of a jump.
+    operands: Vec<u8>,
+    /// The instruction a jump lands on.
+    target: Option<usize>,
+    /// The index of the instruction in the chunk as it was compiled,
+    /// which is how jumps refer to it.
+    id: usize,
+    position: Position,
+}
+
+impl Instruction {
+    /// The number of bytes the instruction is encoded in.
+    fn size(&self) -> usize {
+        match self.target {
+            Some(_) => 3,
+            None => 1 + self.operands.len(),
+        }
+    }
+
+    /// The value the instruction pushes, if it pushes a constant.
+    fn constant(&self, chunk: &Chunk) -> Option<Value> {
+        let index = match self.op {
+            OpCode::Constant => self.operands[0] as usize,
+            OpCode::ConstantLong => long_index(&self.operands),
+            OpCode::Nil => return Some(Value::None),
+            OpCode::True => return Some(true.into()),
+            OpCode::False => return Some(false.into()),
+            _ => return None,
+        };
+        Some(chunk.constants[index])
+    }
+
+    fn number(&self, chunk: &Chunk) -> Option<Value> {
+        self.constant(chunk).filter(|value| value.is_numeric())
+    }
+}
+
+fn is_jump(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler
+    )
+}
+
+/// Splits the code of `chunk` into instructions. Returns [`None`] if a
+/// jump lands somewhere other than the start of an instruction.
+fn decode(heap: &Heap, chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut offsets = Vec::new();
+    // The offset each jump lands on, resolved once every instruction is known.
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from(chunk.code[offset]);
+        let id = instructions.len();
+        let (operands, size) = if is_jump(op) {
+            let jump = ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+            let landing = match op {
+                OpCode::Loop => (offset + 3).checked_sub(jump)?,
+                _ => offset + 3 + jump,
+            };
+            jumps.push((id, landing));
+            (Vec::new(), 3)
+        } else {
+            let size = chunk.operand_size(heap, offset);
+            (chunk.code[offset + 1..offset + 1 + size].to_vec(), 1 + size)
+        };
+        instructions.push(Instruction {
+            op,
+            operands,
+            target: None,
+            id,
+            position: chunk.position(offset),
+        });
+        offsets.push(offset);
+        offset += size;
+    }
+
+    for (id, landing) in jumps {
+        let target = offsets.binary_search(&landing).ok()?;
+        instructions[id].target = Some(target);
+    }
+    Some(instructions)
+}
+
+/// Returns which of `instructions` can run, following every jump from
+/// the first of them.
+fn reachable(instructions: &[Instruction]) -> Vec<bool> {
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(id) = pending.pop() {
+        if id >= instructions.len() || reachable[id] {
+            continue;
+        }
+        reachable[id] = true;
+        let instruction = &instructions[id];
+        pending.extend(instruction.target);
+        if !matches!(instruction.op, OpCode::Jump | OpCode::Loop | OpCode::Return) {
+            pending.push(id + 1);
+        }
+    }
+    reachable
+}
+
+/// Writes `instructions` into `chunk` in place of its code. Returns
+/// false, leaving `chunk` as it was, if a jump no longer fits its operand.
+fn encode(chunk: &mut Chunk, instructions: &[Instruction]) -> bool {
+    let mut offsets = vec![0; instructions.len()];
+    let mut ids = std::collections::HashMap::new();
+    let mut offset = 0;
+    for (index, instruction) in instructions.iter().enumerate() {
+        offsets[index] = offset;
+        ids.insert(instruction.id, index);
+        offset += instruction.size();
+    }
+
+    let mut code = Chunk::new();
+    code.source = chunk.source.clone();
+    for (index, instruction) in instructions.iter().enumerate() {
+        let position = instruction.position;
+        code.write(instruction.op as u8, position);
+        let target = match instruction.target {
+            Some(target) => offsets[ids[&target]],
+            None => {
+                for byte in instruction.operands.iter() {
+                    code.write(*byte, position);
+                }
+                continue;
+            }
+        };
+        let after = offsets[index] + 3;
+        let jump = match instruction.op {
+            OpCode::Loop => after - target,
+            _ => target - after,
+        };
+        if jump > u16::MAX as usize {
+            return false;
+        }
+        code.write((jump >> 8) as u8, position);
+        code.write((jump & 0xff) as u8, position);
+    }
+
+    code.constants = std::mem::take(&mut chunk.constants);
+    *chunk = code;
+    true
+}
+
+/// Returns an instruction pushing `value`, adding it to the constants
+/// of `chunk` if it isn't there yet.
+fn constant(chunk: &mut Chunk, value: Value, id: usize, position: Position) -> Option<Instruction> {
+    let (op, operands) = match value.val_type() {
+        ValueType::Nil => (OpCode::Nil, Vec::new()),
+        ValueType::Bool if value.as_bool() => (OpCode::True, Vec::new()),
+        ValueType::Bool => (OpCode::False, Vec::new()),
+        ValueType::Number | ValueType::Int => {
+            // An integer and a float of the same value are kept apart.
+            let existing = chunk.constants.iter().position(|constant| {
+                constant.val_type() == value.val_type()
+                    && match value.is_type(ValueType::Int) {
+                        true => constant.as_int() == value.as_int(),
+                        false => constant.as_number().to_bits() == value.as_number().to_bits(),
+                    }
+            });
+            let index = match existing {
+                Some(index) => index,
+                None if chunk.constants.len() <= LONG_INDEX_MAX => chunk.constant(value),
+                None => return None,
+            };
+            if index > u8::MAX as usize {
+                let bytes = vec![(index >> 16) as u8, (index >> 8) as u8, index as u8];
+                (OpCode::ConstantLong, bytes)
+            } else {
+                (OpCode::Constant, vec![index as u8])
+            }
+        }
+        ValueType::Obj => return None,
+    };
+    Some(Instruction {
+        op,
+        operands,
+        target: None,
+        id,
+        position,
+    })
+}
+
+struct Optimizer<'a> {
+    chunk: &'a mut Chunk,
+    level: OptLevel,
+    /// The ids of the instructions jumps land on.
+    targets: HashSet<usize>,
+    /// The instructions optimized so far.
+    out: Vec<Instruction>,
+}
+
+impl Optimizer<'_> {
+    /// Whether the last `count` instructions can be rewritten as one:
+    /// no jump lands on any but the first of them.
+    fn tail_is_straight(&self, count: usize) -> bool {
+        self.out.len() >= count
+            && self.out[self.out.len() + 1 - count..]
+                .iter()
+                .all(|instruction| !self.targets.contains(&instruction.id))
+    }
+
+    /// Replaces the last `count` instructions with `replacement`, if there is one.
+    fn replace(&mut self, count: usize, replacement: Option<Instruction>) -> bool {
+        let replacement = match replacement {
+            Some(replacement) => replacement,
+            None => return false,
+        };
+        self.out.truncate(self.out.len() - count);
+        self.out.push(replacement);
+        true
+    }
+
+    /// Rewrites the end of the optimized code once. Returns whether it
+    /// changed.
+    fn rewrite(&mut self) -> bool {
+        let len = self.out.len();
+        if len < 2 || !self.tail_is_straight(2) {
+            return false;
+        }
+        let (first, last) = (&self.out[len - 2], &self.out[len - 1]);
+        let (id, position) = (first.id, first.position);
+
+        if self.level >= OptLevel::Full {
+            let operand = first.constant(self.chunk);
+            let folded = match (operand, last.op) {
+                (Some(value), OpCode::Negate) if value.is_numeric() => Some(-value),
+                (Some(value), OpCode::BitNot) if value.is_type(ValueType::Int) => Some(!value),
+                (Some(value), OpCode::Not) => Some(value.is_falsy().into()),
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                let replacement = constant(self.chunk, folded, id, position);
+                return self.replace(2, replacement);
+            }
+
+            if len >= 3 && self.tail_is_straight(3) {
+                let operands = (
+                    self.out[len - 3].number(self.chunk),
+                    self.out[len - 2].number(self.chunk),
+                );
+                if let (Some(a), Some(b)) = operands {
+                    let ints = a.is_type(ValueType::Int) && b.is_type(ValueType::Int);
+                    // Division by zero and overflow are left to fail when run.
+                    let folded: Option<Value> = match last.op {
+                        OpCode::Add => Some(a + b),
+                        OpCode::Subtract => Some(a - b),
+                        OpCode::Multiply => Some(a * b),
+                        OpCode::Divide => Some(a / b),
+                        OpCode::IntDivide => a.floor_div(b),
+                        OpCode::Modulo => a.modulo(b),
+                        OpCode::BitAnd if ints => Some(a & b),
+                        OpCode::BitOr if ints => Some(a | b),
+                        OpCode::BitXor if ints => Some(a ^ b),
+                        OpCode::ShiftLeft if ints => a.shift_left(b.as_int()),
+                        OpCode::ShiftRight if ints => a.shift_left(-b.as_int()),
+                        OpCode::Greater => Some((a > b).into()),
+                        OpCode::Less => Some((a < b).into()),
+                        OpCode::Equal => Some((a == b).into()),
+                        _ => None,
+                    };
+                    if let Some(folded) = folded {
+                        let (id, position) = (self.out[len - 3].id, self.out[len - 3].position);
+                        let replacement = constant(self.chunk, folded, id, position);
+                        return self.replace(3, replacement);
+                    }
+                }
+            }
+        }
+
+        match (first.op, last.op) {
+            (OpCode::Equal, OpCode::Not) => {
+                let fused = Instruction {
+                    op: OpCode::NotEqual,
+                    operands: Vec::new(),
+                    target: None,
+                    id,
+                    position,
+                };
+                self.replace(2, Some(fused))
+            }
+            (
+                OpCode::Constant
+                | OpCode::ConstantLong
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::GetLocal
+                | OpCode::GetUpvalue,
+                OpCode::Pop,
+            ) if !self.targets.contains(&id) => {
+                // The value is popped as soon as it is pushed.
+                self.out.truncate(len - 2);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Optimizes the code of `chunk` as far as `level` allows. `heap` holds
+/// the functions of the closures it makes.
+pub fn optimize(chunk: &mut Chunk, heap: &Heap, level: OptLevel) {
+    if level == OptLevel::None {
+        return;
+    }
+    let instructions = match decode(heap, chunk) {
+        Some(instructions) => instructions,
+        None => return,
+    };
+    let reachable = reachable(&instructions);
+    // Only jumps that can run keep the instruction they land on apart.
+    let targets = instructions
+        .iter()
+        .filter(|instruction| reachable[instruction.id])
+        .filter_map(|instruction| instruction.target)
+        .collect();
+
+    let constants = chunk.constants.len();
+    let mut optimizer = Optimizer {
+        chunk,
+        level,
+        targets,
+        out: Vec::with_capacity(instructions.len()),
+    };
+    for instruction in instructions {
+        if reachable[instruction.id] {
+            optimizer.out.push(instruction);
+            while optimizer.rewrite() {}
+        }
+    }
+
+    let out = std::mem::take(&mut optimizer.out);
+    if !encode(chunk, &out) {
+        chunk.constants.truncate(constants);
+    }
+}
@@ -0,0 +1,338 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! Reads S-expressions into a tree of [`Datum`]s.
+
+The syntax follows `tools/eswm_syntax.org`: an S-expression is either
+an atom or a parenthesised list of S-expressions. Atoms are numbers,
+strings and symbols, where a symbol is any run of characters that
+isn't whitespace, a parenthesis, a quote, a comma or the start of a
+comment. Numbers without a fraction or exponent are read as integers,
+as are `#x`, `#o` and `#b` followed by hexadecimal, octal or binary
+digits.
+
+`'datum` is read as `(quote datum)`, `#'name` as `(function name)`,
+and `(a b . c)` as a list whose last cdr is `c` rather than nil.
+Backquote, comma and comma-at read the same way as `(quasiquote datum)`,
+`(unquote datum)` and `(unquote-splicing datum)`.
+ */
+use super::scanner::column;
+use crate::chunk::Position;
+use crate::value::Value;
+
+#[derive(Clone, Debug)]
+pub(super) enum DatumKind {
+    Number(f64),
+    Integer(i64),
+    String(String),
+    Symbol(String),
+    /// A symbol a macro made with `gensym`, which isn't interned. The
+    /// name is unique to the symbol and isn't one the reader can give a
+    /// symbol, so it can't be mistaken for a variable of the same name.
+    Uninterned(String, Value),
+    List(Vec<Datum>),
+    /// A list ending in something other than nil.
+    DottedList(Vec<Datum>, Box<Datum>),
+}
+
+/// A single S-expression along with where it was read from.
+#[derive(Clone, Debug)]
+pub(super) struct Datum {
+    pub(super) kind: DatumKind,
+    pub(super) position: Position,
+    /// The number of characters the datum was read from, 0 if it wasn't read.
+    pub(super) width: usize,
+}
+
+impl Datum {
+    pub(super) fn new_symbol(name: &str, position: Position) -> Datum {
+        Datum {
+            kind: DatumKind::Symbol(name.to_string()),
+            position,
+            width: 0,
+        }
+    }
+
+    pub(super) fn new_list(items: Vec<Datum>, position: Position) -> Datum {
+        Datum {
+            kind: DatumKind::List(items),
+            position,
+            width: 0,
+        }
+    }
+
+    /// The name of the symbol, if the datum is one.
+    pub(super) fn symbol(&self) -> Option<&str> {
+        match self.kind {
+            DatumKind::Symbol(ref name) | DatumKind::Uninterned(ref name, _) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct ReadError {
+    pub(super) message: &'static str,
+    pub(super) position: Position,
+    /// Whether the error is the source ending in the middle of a datum.
+    pub(super) incomplete: bool,
+}
+
+pub(super) struct Reader<'a> {
+    source: &'a str,
+    current: usize,
+    line: usize,
+    /// Byte offset of the first character of the current line.
+    line_start: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Reader {
+            source,
+            current: 0,
+            line: 1,
+            line_start: 0,
+        }
+    }
+
+    fn is_delimiter(c: u8) -> bool {
+        c.is_ascii_whitespace() || matches!(c, b'(' | b')' | b'"' | b'\'' | b'`' | b',' | b';')
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+
+    fn peek(&self) -> u8 {
+        self.source
+            .as_bytes()
+            .get(self.current)
+            .copied()
+            .unwrap_or(b'\0')
+    }
+
+    fn peek_next(&self) -> u8 {
+        self.source
+            .as_bytes()
+            .get(self.current + 1)
+            .copied()
+            .unwrap_or(b'\0')
+    }
+
+    fn advance(&mut self) -> u8 {
+        let c = self.peek();
+        self.current += 1;
+        if c == b'\n' {
+            self.line += 1;
+            self.line_start = self.current;
+        }
+        c
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: column(self.source, self.line_start, self.current),
+        }
+    }
+
+    fn error<T>(&self, message: &'static str, position: Position) -> Result<T, ReadError> {
+        Err(ReadError {
+            message,
+            position,
+            incomplete: self.is_at_end(),
+        })
+    }
+
+    fn skip_whitespace(&mut self) {
+        while !self.is_at_end() {
+            match self.peek() {
+                b';' => {
+                    while self.peek() != b'\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                }
+                c if c.is_ascii_whitespace() => {
+                    self.advance();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Reads the next datum, or returns `None` once the source is exhausted.
+    pub fn read(&mut self) -> Option<Result<Datum, ReadError>> {
+        self.skip_whitespace();
+        if self.is_at_end() {
+            return None;
+        }
+        Some(self.datum())
+    }
+
+    fn datum(&mut self) -> Result<Datum, ReadError> {
+        self.skip_whitespace();
+        let position = self.position();
+        let start = self.current;
+        if self.is_at_end() {
+            return self.error("Expect expression.", position);
+        }
+
+        let kind = match self.peek() {
+            b'(' => {
+                self.advance();
+                self.list(position)?
+            }
+            b')' => return self.error("Unexpected ')'.", position),
+            b'"' => {
+                self.advance();
+                self.string(position)?
+            }
+            b'#' if self.peek_next() == b'\'' => {
+                self.advance();
+                self.advance();
+                self.abbreviation("function", position)?
+            }
+            b'\'' => {
+                self.advance();
+                self.abbreviation("quote", position)?
+            }
+            b'`' => {
+                self.advance();
+                self.abbreviation("quasiquote", position)?
+            }
+            b',' => {
+                self.advance();
+                if self.peek() == b'@' {
+                    self.advance();
+                    self.abbreviation("unquote-splicing", position)?
+                } else {
+                    self.abbreviation("unquote", position)?
+                }
+            }
+            _ => self.atom()?,
+        };
+        let width = self.source[start..self.current].chars().count();
+        Ok(Datum {
+            kind,
+            position,
+            width,
+        })
+    }
+
+    /// Reads the datum after a quote character as `(name datum)`.
+    fn abbreviation(&mut self, name: &str, position: Position) -> Result<DatumKind, ReadError> {
+        let datum = self.datum()?;
+        Ok(DatumKind::List(vec![Datum::new_symbol(name, position), datum]))
+    }
+
+    fn list(&mut self, start: Position) -> Result<DatumKind, ReadError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.is_at_end() {
+                return self.error("Expect ')' to close list.", start);
+            }
+            if self.peek() == b')' {
+                self.advance();
+                return Ok(DatumKind::List(items));
+            }
+
+            let item = self.datum()?;
+            if item.symbol() != Some(".") {
+                items.push(item);
+                continue;
+            }
+            if items.is_empty() {
+                return self.error("Expect an element before '.'.", item.position);
+            }
+            let tail = self.datum()?;
+            self.skip_whitespace();
+            if self.peek() != b')' {
+                return self.error(
+                    "Expect ')' after the tail of a dotted list.",
+                    self.position(),
+                );
+            }
+            self.advance();
+            return Ok(DatumKind::DottedList(items, Box::new(tail)));
+        }
+    }
+
+    fn string(&mut self, start: Position) -> Result<DatumKind, ReadError> {
+        let mut string = Vec::new();
+        loop {
+            if self.is_at_end() {
+                return self.error("Unterminated string.", start);
+            }
+            match self.advance() {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.position();
+                    string.push(match self.advance() {
+                        b'n' => b'\n',
+                        b't' => b'\t',
+                        b'\\' => b'\\',
+                        b'"' => b'"',
+                        b'\n' => continue,
+                        _ => return self.error("Unknown escape sequence.", escape),
+                    });
+                }
+                c => string.push(c),
+            }
+        }
+        // The source is valid utf-8 and only whole characters were copied.
+        Ok(DatumKind::String(String::from_utf8(string).unwrap()))
+    }
+
+    fn atom(&mut self) -> Result<DatumKind, ReadError> {
+        let position = self.position();
+        let start = self.current;
+        while !self.is_at_end() && !Reader::is_delimiter(self.peek()) {
+            self.advance();
+        }
+        let text = &self.source[start..self.current];
+
+        let radix = match text.get(..2) {
+            Some("#x" | "#X") => Some(16),
+            Some("#o" | "#O") => Some(8),
+            Some("#b" | "#B") => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            return match super::parse_integer(&text[2..], radix) {
+                Ok(integer) => Ok(DatumKind::Integer(integer)),
+                Err(message) => self.error(message, position),
+            };
+        }
+
+        let numeric = text
+            .bytes()
+            .all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'+' | b'-' | b'e' | b'E'));
+        let starts_like_number = text
+            .trim_start_matches(['+', '-'])
+            .starts_with(|c: char| c.is_ascii_digit() || c == '.');
+        if !numeric || !starts_like_number {
+            return Ok(DatumKind::Symbol(text.to_string()));
+        }
+        // Integers too large to be fixnums are read as floats instead.
+        Ok(match (super::parse_integer(text, 10), text.parse::<f64>()) {
+            (Ok(integer), _) => DatumKind::Integer(integer),
+            (Err(_), Ok(number)) => DatumKind::Number(number),
+            (Err(_), Err(_)) => DatumKind::Symbol(text.to_string()),
+        })
+    }
+}
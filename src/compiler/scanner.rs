@@ -28,7 +28,13 @@ pub(super) enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
     // One or two character tokens.
+    TildeSlash,
     Bang,
     BangEqual,
     Equal,
@@ -37,6 +43,8 @@ pub(super) enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    LessLess,
+    GreaterGreater,
     // Literals.
     Identifier,
     String,
@@ -64,140 +72,138 @@ pub(super) enum TokenType {
 }
 
 #[derive(Clone)]
-pub(super) struct Token {
+pub(super) struct Token<'a> {
     pub(super) id: TokenType,
-    pub(super) start: Option<*const char>,
-    pub(super) length: Option<usize>,
+    /// The source text of the token, or the message of an error token.
+    pub(super) lexeme: &'a str,
     pub(super) line: usize,
-    pub(super) error_string: Option<String>,
+    pub(super) column: usize,
 }
 
-impl Default for Token {
-    fn default() -> Token {
+impl Default for Token<'_> {
+    fn default() -> Self {
 	Token {
 	    id: TokenType::Error,
-	    start: None,
-	    length: None,
+	    lexeme: "",
 	    line: 0,
-	    error_string: None,
+	    column: 0,
 	}
     }
 }
 
-impl Token {
+impl Token<'_> {
     pub fn string(&self) -> String {
-        if self.id != TokenType::Error {
-            let mut string = String::new();
-            let temp_ptr = self.start.unwrap();
-            for i in 0..self.length.unwrap() {
-                unsafe {
-                    let character = *temp_ptr.add(i);
-                    string.push(character);
-                }
-            }
-            string
-        } else {
-            self.error_string.clone().unwrap()
-        }
+        self.lexeme.to_string()
     }
 }
-pub(super) struct Scanner {
-    start: *const char,
-    current: *const char,
+pub(super) struct Scanner<'a> {
+    source: &'a str,
+    /// Byte offset of the start of the token being scanned.
+    start: usize,
+    /// Byte offset of the character being looked at.
+    current: usize,
     line: usize,
+    /// Byte offset of the first character of the current line.
+    line_start: usize,
+    /// The line and column the token being scanned starts at.
+    start_line: usize,
+    start_column: usize,
 }
 
-impl Scanner {
-    pub fn new(source: &Vec<char>) -> Self {
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self {
         Scanner {
-            start: &source[0],
-            current: &source[0],
+            source,
+            start: 0,
+            current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
-    fn is_alpha(c: char) -> bool {
-        c >= 'a' && c <= 'z' || c >= 'A' && c <= 'Z' || c == '_'
+    fn is_alpha(c: u8) -> bool {
+        c.is_ascii_alphabetic() || c == b'_'
     }
 
-    fn is_digit(c: char) -> bool {
-        c >= '0' && c <= '9'
+    fn is_digit(c: u8) -> bool {
+        c.is_ascii_digit()
     }
 
-    fn is_at_end(&mut self) -> bool {
-        //	println!("{:?}, {}", unsafe{*self.current as char}, unsafe{*self.current as char == '\0'});
-        unsafe { *self.current as char == '\0' }
+    pub(super) fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
     }
 
-    fn advance(&mut self) -> char {
-        unsafe {
-            self.current = self.current.add(1);
-            *self.current.sub(1) as char
-        }
+    fn byte_at(&self, offset: usize) -> u8 {
+        self.source.as_bytes().get(offset).copied().unwrap_or(b'\0')
     }
 
-    fn peek(&mut self) -> char {
-        unsafe { *self.current as char }
+    fn advance(&mut self) -> u8 {
+        self.current += 1;
+        self.byte_at(self.current - 1)
     }
 
-    fn peek_next(&mut self) -> char {
+    fn peek(&self) -> u8 {
+        self.byte_at(self.current)
+    }
+
+    fn peek_next(&self) -> u8 {
         if self.is_at_end() {
-            '\0'
+            b'\0'
         } else {
-            unsafe { *self.current.add(1) as char }
+            self.byte_at(self.current + 1)
         }
     }
 
-    fn matches(&mut self, expected: char) -> bool {
+    fn matches(&mut self, expected: u8) -> bool {
         if self.is_at_end() {
             return false;
         }
-        if unsafe { *self.current } as char != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        unsafe {
-            self.current = self.current.add(1);
-        }
+        self.current += 1;
         true
     }
 
-    fn make_token(&mut self, id: TokenType) -> Token {
-        unsafe {
-            Token {
-                id,
-                start: Some(self.start),
-                length: Some(self.current.offset_from(self.start) as usize),
-                line: self.line,
-                error_string: None,
-            }
+    fn make_token(&self, id: TokenType) -> Token<'a> {
+        Token {
+            id,
+            lexeme: &self.source[self.start..self.current],
+            line: self.start_line,
+            column: self.start_column,
         }
     }
 
-    fn error_token(&mut self, message: &str) -> Token {
+    fn error_token(&self, message: &'static str) -> Token<'a> {
         Token {
             id: TokenType::Error,
-            start: None,
-            length: None,
-            line: self.line,
-            error_string: Some(message.to_string()),
+            lexeme: message,
+            line: self.start_line,
+            column: self.start_column,
         }
     }
 
+    /// Consumes a newline character.
+    fn newline(&mut self) {
+        self.advance();
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             let c = self.peek();
             match c {
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                }
-                '\n' => {
-                    self.line += 1;
+                b' ' | b'\r' | b'\t' => {
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
+                b'\n' => self.newline(),
+                b'/' => {
+                    if self.peek_next() == b'/' {
+                        while self.peek() != b'\n' && !self.is_at_end() {
                             self.advance();
                         }
                     } else {
@@ -217,16 +223,8 @@ impl Scanner {
         rest: &str,
         id: TokenType,
     ) -> TokenType {
-        let scan_len = unsafe { self.current.offset_from(self.start) as usize };
-        let mut scan_str = String::new();
-        let temp_ptr = unsafe { self.start.add(start) };
-        for i in 0..length {
-            unsafe {
-                scan_str.push(*temp_ptr.add(i));
-            }
-        }
-
-        if scan_len == (start + length) && scan_str == rest {
+        let lexeme = &self.source[self.start..self.current];
+        if lexeme.len() == start + length && &lexeme[start..] == rest {
             id
         } else {
             TokenType::Identifier
@@ -234,48 +232,46 @@ impl Scanner {
     }
 
     fn identifier_id(&mut self) -> TokenType {
-        unsafe {
-            match *self.start {
-                'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-                'c' => self.check_keyword(1, 4, "less", TokenType::Class),
-                'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-                'f' => {
-                    if self.current.offset_from(self.start) > 1 {
-                        match *self.start.add(1) {
-                            'a' => self.check_keyword(2, 3, "lse", TokenType::False),
-                            'o' => self.check_keyword(2, 1, "r", TokenType::For),
-                            'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
-                            _ => TokenType::Identifier,
-                        }
-                    } else {
-                        TokenType::Identifier
+        match self.byte_at(self.start) {
+            b'a' => self.check_keyword(1, 2, "nd", TokenType::And),
+            b'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            b'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
+            b'f' => {
+                if self.current - self.start > 1 {
+                    match self.byte_at(self.start + 1) {
+                        b'a' => self.check_keyword(2, 3, "lse", TokenType::False),
+                        b'o' => self.check_keyword(2, 1, "r", TokenType::For),
+                        b'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
+                        _ => TokenType::Identifier,
                     }
+                } else {
+                    TokenType::Identifier
                 }
-                'i' => self.check_keyword(1, 1, "f", TokenType::If),
-                'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
-                'o' => self.check_keyword(1, 1, "r", TokenType::Or),
-                'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-                'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-                's' => self.check_keyword(1, 4, "uper", TokenType::Super),
-                't' => {
-                    if self.current.offset_from(self.start) > 1 {
-                        match *self.start.add(1) {
-                            'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                            'r' => self.check_keyword(2, 2, "ue", TokenType::True),
-                            _ => TokenType::Identifier,
-                        }
-                    } else {
-                        TokenType::Identifier
+            }
+            b'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            b'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
+            b'o' => self.check_keyword(1, 1, "r", TokenType::Or),
+            b'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
+            b'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
+            b's' => self.check_keyword(1, 4, "uper", TokenType::Super),
+            b't' => {
+                if self.current - self.start > 1 {
+                    match self.byte_at(self.start + 1) {
+                        b'h' => self.check_keyword(2, 2, "is", TokenType::This),
+                        b'r' => self.check_keyword(2, 2, "ue", TokenType::True),
+                        _ => TokenType::Identifier,
                     }
+                } else {
+                    TokenType::Identifier
                 }
-                'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-                'w' => self.check_keyword(1, 4, "hile", TokenType::While),
-                _ => TokenType::Identifier,
             }
+            b'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
+            b'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            _ => TokenType::Identifier,
         }
     }
 
-    fn identifier(&mut self) -> Token {
+    fn identifier(&mut self) -> Token<'a> {
         while Scanner::is_alpha(self.peek()) || Scanner::is_digit(self.peek()) {
             self.advance();
         }
@@ -283,13 +279,24 @@ impl Scanner {
         self.make_token(id)
     }
 
-    fn number(&mut self) -> Token {
+    fn number(&mut self) -> Token<'a> {
+        // `0x`, `0o` and `0b` start hexadecimal, octal and binary integers.
+        // The compiler checks their digits.
+        let radix = matches!(self.peek(), b'x' | b'X' | b'o' | b'O' | b'b' | b'B');
+        if radix && &self.source[self.start..self.current] == "0" {
+            self.advance();
+            while Scanner::is_alpha(self.peek()) || Scanner::is_digit(self.peek()) {
+                self.advance();
+            }
+            return self.make_token(TokenType::Number);
+        }
+
         while Scanner::is_digit(self.peek()) {
             self.advance();
         }
 
         // Look for a fractional part.
-        if self.peek() == '.' && Scanner::is_digit(self.peek_next()) {
+        if self.peek() == b'.' && Scanner::is_digit(self.peek_next()) {
             // Consume the ".".
             self.advance();
 
@@ -301,12 +308,13 @@ impl Scanner {
         self.make_token(TokenType::Number)
     }
 
-    fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+    fn string(&mut self) -> Token<'a> {
+        while self.peek() != b'"' && !self.is_at_end() {
+            if self.peek() == b'\n' {
+                self.newline();
+            } else {
+                self.advance();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -318,9 +326,11 @@ impl Scanner {
         self.make_token(TokenType::String)
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    pub fn scan_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = column(self.source, self.line_start, self.current);
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -335,47 +345,70 @@ impl Scanner {
         }
 
         match c {
-            '(' => self.make_token(TokenType::LeftParen),
-            ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
-            ';' => self.make_token(TokenType::Semicolon),
-            ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
-            '!' => {
-                if self.matches('=') {
+            b'(' => self.make_token(TokenType::LeftParen),
+            b')' => self.make_token(TokenType::RightParen),
+            b'{' => self.make_token(TokenType::LeftBrace),
+            b'}' => self.make_token(TokenType::RightBrace),
+            b';' => self.make_token(TokenType::Semicolon),
+            b',' => self.make_token(TokenType::Comma),
+            b'.' => self.make_token(TokenType::Dot),
+            b'-' => self.make_token(TokenType::Minus),
+            b'+' => self.make_token(TokenType::Plus),
+            b'/' => self.make_token(TokenType::Slash),
+            b'*' => self.make_token(TokenType::Star),
+            b'%' => self.make_token(TokenType::Percent),
+            b'&' => self.make_token(TokenType::Ampersand),
+            b'|' => self.make_token(TokenType::Pipe),
+            b'^' => self.make_token(TokenType::Caret),
+            b'~' => {
+                if self.matches(b'/') {
+                    self.make_token(TokenType::TildeSlash)
+                } else {
+                    self.make_token(TokenType::Tilde)
+                }
+            }
+            b'!' => {
+                if self.matches(b'=') {
                     self.make_token(TokenType::BangEqual)
                 } else {
                     self.make_token(TokenType::Bang)
                 }
             }
-            '=' => {
-                if self.matches('=') {
+            b'=' => {
+                if self.matches(b'=') {
                     self.make_token(TokenType::EqualEqual)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
             }
-            '<' => {
-                if self.matches('=') {
+            b'<' => {
+                if self.matches(b'=') {
                     self.make_token(TokenType::LessEqual)
+                } else if self.matches(b'<') {
+                    self.make_token(TokenType::LessLess)
                 } else {
                     self.make_token(TokenType::Less)
                 }
             }
-            '>' => {
-                if self.matches('=') {
+            b'>' => {
+                if self.matches(b'=') {
                     self.make_token(TokenType::GreaterEqual)
+                } else if self.matches(b'>') {
+                    self.make_token(TokenType::GreaterGreater)
                 } else {
                     self.make_token(TokenType::Greater)
                 }
             }
-            '"' => self.string(),
+            b'"' => self.string(),
             _ => self.error_token("Unexpected character."),
         }
     }
 }
+
+/// Returns the column of byte `offset` in `source`, on the line starting
+/// at byte `line_start`. Columns count characters, not bytes.
+pub(super) fn column(source: &str, line_start: usize, offset: usize) -> usize {
+    let bytes = &source.as_bytes()[line_start..offset];
+    // Continuation bytes carry on the character before them.
+    bytes.iter().filter(|&&byte| byte & 0xc0 != 0x80).count() + 1
+}
@@ -0,0 +1,160 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::chunk::{Chunk, OpCode};
+use crate::globals::Globals;
+use crate::value::{heap::Heap, objects::ObjFunction, print_value};
+/// Outputs `code` to stdout.
+/// Returns offset + 1.
+fn simple_instruction(code: OpCode, offset: usize) -> usize {
+    println!("{}", code);
+    offset + 1
+}
+
+/// Reads the constant index following the instruction at `offset`.
+/// Returns the index and the offset just past it.
+fn read_index(code: OpCode, chunk: &Chunk, offset: usize) -> (usize, usize) {
+    if code.is_long() {
+        let index = ((chunk.code[offset + 1] as usize) << 16)
+            | ((chunk.code[offset + 2] as usize) << 8)
+            | chunk.code[offset + 3] as usize;
+        (index, offset + 4)
+    } else {
+        (chunk.code[offset + 1] as usize, offset + 2)
+    }
+}
+
+/// Outputs `code` to stdout along with its assosiated value.
+/// Returns the offset of the next instruction.
+fn constant_instruction(code: OpCode, heap: &Heap, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, offset) = read_index(code, chunk, offset);
+    print!("{:-16} {:4} '", code, constant);
+    print_value(heap, chunk.constants[constant]);
+    println!("'");
+    offset
+}
+
+/// Outputs a global variable instruction along with the name of the variable.
+/// Returns the offset of the next instruction.
+fn global_instruction(code: OpCode, globals: &Globals, chunk: &Chunk, offset: usize) -> usize {
+    let (slot, offset) = read_index(code, chunk, offset);
+    println!("{:-16} {:4} '{}'", code, slot, globals.name(slot));
+    offset
+}
+
+fn byte_instruction(code: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.code[offset + 1];
+    println!("{:-16} {:4}", code, slot);
+    offset + 2
+}
+
+/// Outputs a jump instruction along with the offset it jumps to.
+/// Returns offset + 3.
+fn jump_instruction(code: OpCode, sign: isize, chunk: &Chunk, offset: usize) -> usize {
+    let jump = ((chunk.code[offset + 1] as u16) << 8) | chunk.code[offset + 2] as u16;
+    let target = offset as isize + 3 + sign * jump as isize;
+    println!("{:-16} {:4} -> {}", code, offset, target);
+    offset + 3
+}
+
+/// Outputs a closure instruction along with the variables it captures.
+fn closure_instruction(code: OpCode, heap: &Heap, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, mut offset) = read_index(code, chunk, offset);
+    print!("{:-16} {:4} ", code, constant);
+    print_value(heap, chunk.constants[constant]);
+    println!();
+
+    let function = heap.get::<ObjFunction>(chunk.constants[constant].as_handle());
+    for _ in 0..function.upvalue_count {
+        let is_local = chunk.code[offset];
+        let index = chunk.code[offset + 1];
+        println!(
+            "{:04}    |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+        offset += 2;
+    }
+    offset
+}
+
+/// Prints code at chunk offset.
+pub fn disassemble_instruction(heap: &Heap, globals: &Globals, chunk: &Chunk, offset: usize) -> usize {
+    print!("{:04} ", offset);
+
+    let position = chunk.position(offset);
+    if offset > 0 && position.line == chunk.line(offset - 1) {
+        print!("     | ");
+    } else {
+        print!("{:>6} ", position.to_string());
+    }
+
+    let instruction = OpCode::from(chunk.code[offset]);
+    match instruction {
+        OpCode::Return
+        | OpCode::Negate
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Not
+        | OpCode::Equal
+        | OpCode::NotEqual
+        | OpCode::IntDivide
+        | OpCode::Modulo
+        | OpCode::BitAnd
+        | OpCode::BitOr
+        | OpCode::BitXor
+        | OpCode::BitNot
+        | OpCode::ShiftLeft
+        | OpCode::ShiftRight
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::Print
+        | OpCode::PrintLisp
+        | OpCode::Pop
+        | OpCode::CloseUpvalue
+        | OpCode::PopHandler => simple_instruction(instruction, offset),
+        OpCode::Constant | OpCode::ConstantLong => constant_instruction(instruction, heap, chunk, offset),
+        OpCode::DefineGlobal
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::DefineGlobalLong
+        | OpCode::GetGlobalLong
+        | OpCode::SetGlobalLong => global_instruction(instruction, globals, chunk, offset),
+        OpCode::SetLocal
+        | OpCode::GetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => byte_instruction(instruction, chunk, offset),
+        OpCode::Closure | OpCode::ClosureLong => closure_instruction(instruction, heap, chunk, offset),
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler => jump_instruction(instruction, 1, chunk, offset),
+        OpCode::Loop => jump_instruction(instruction, -1, chunk, offset),
+    }
+}
+
+/// Prints the contents of [`Chunk`] to std out.
+pub fn disassemble_chunk(heap: &Heap, globals: &Globals, chunk: &Chunk, name: &str) {
+    println!("== {} ==", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(heap, globals, chunk, offset);
+    }
+}
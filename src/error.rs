@@ -0,0 +1,260 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! The errors reported while compiling and running scripts.
+
+Nothing here is printed as it happens. Errors are collected into
+[`Diagnostic`]s and handed back to the caller. [`Display`] gives the
+one line summary of each, and [`Diagnostic::render`] adds the lines of
+source they point at, underlined.
+ */
+use crate::chunk::{Position, Source};
+use std::error::Error;
+use std::fmt::{self, Display, Write};
+
+const RED: &str = "1;31";
+const CYAN: &str = "1;36";
+const BLUE: &str = "1;34";
+const BOLD: &str = "1";
+
+/// The part of a file an error points at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub position: Position,
+    /// The number of characters covered, 0 if the span only marks a position.
+    pub length: usize,
+    /// The text of the line the span starts on, if the source is known.
+    pub source_line: Option<String>,
+}
+
+impl Span {
+    pub fn new(source: &Source, position: Position, length: usize) -> Span {
+        Span {
+            file: source.name.clone(),
+            position,
+            length,
+            source_line: source.line(position.line).map(str::to_string),
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.position)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The source couldn't be split into tokens or read as S-expressions.
+    Read,
+    /// The compiler rejected the code.
+    Compile,
+    /// Running the code failed.
+    Runtime,
+}
+
+/// A call that was in progress when a runtime error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The name of the function, [`None`] for the top level script.
+    pub function: Option<String>,
+    /// The call or instruction the function was at.
+    pub span: Span,
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.function {
+            Some(ref name) => write!(f, "[{}] in {}()", self.span, name),
+            None => write!(f, "[{}] in script", self.span),
+        }
+    }
+}
+
+/// More about a [`Diagnostic`], such as where something it mentions is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Note {
+    pub fn new(message: &str, span: Option<Span>) -> Note {
+        Note {
+            message: message.to_string(),
+            span,
+        }
+    }
+}
+
+impl Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref span) = self.span {
+            write!(f, "[{}] ", span)?;
+        }
+        write!(f, "Note: {}", self.message)
+    }
+}
+
+/// A single error along with everything known about where it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Where the error happened, if it happened within a source file.
+    pub span: Option<Span>,
+    /// What the compiler found where it expected something else: a
+    /// quoted lexeme or `end`.
+    pub found: Option<String>,
+    /// The calls in progress for a runtime error, innermost first.
+    pub trace: Vec<TraceFrame>,
+    pub notes: Vec<Note>,
+    /// The error that led to this one, such as a macro failing to expand.
+    pub cause: Option<Box<Diagnostic>>,
+    /// Whether the source ended before it was complete, as when a
+    /// bracket or string is left open, so more of it might fix the error.
+    pub incomplete: bool,
+}
+
+impl Diagnostic {
+    pub fn new(kind: ErrorKind, message: &str, span: Option<Span>) -> Diagnostic {
+        Diagnostic {
+            kind,
+            message: message.to_string(),
+            span,
+            found: None,
+            trace: Vec::new(),
+            notes: Vec::new(),
+            cause: None,
+            incomplete: false,
+        }
+    }
+
+    /// Formats the diagnostic along with the source lines it points at.
+    /// Set `color` to highlight it with terminal escape codes.
+    pub fn render(&self, color: bool) -> String {
+        let paint = |code, text| paint(color, code, text);
+        let mut out = String::new();
+        if let Some(ref cause) = self.cause {
+            out.push_str(&cause.render(color));
+            out.push('\n');
+        }
+
+        if self.kind == ErrorKind::Runtime {
+            out.push_str(&paint(RED, &self.message));
+        } else {
+            if let Some(ref span) = self.span {
+                let _ = write!(out, "[{}] ", span);
+            }
+            out.push_str(&paint(RED, "Error"));
+            if let Some(ref found) = self.found {
+                let _ = write!(out, " at {}", found);
+            }
+            let _ = write!(out, ": {}", paint(BOLD, &self.message));
+        }
+        if let Some(ref span) = self.span {
+            snippet(&mut out, span, '^', RED, color);
+        }
+        for frame in self.trace.iter() {
+            let _ = write!(out, "\n{}", frame);
+        }
+
+        for note in self.notes.iter() {
+            out.push('\n');
+            if let Some(ref span) = note.span {
+                let _ = write!(out, "[{}] ", span);
+            }
+            let _ = write!(out, "{}: {}", paint(CYAN, "Note"), note.message);
+            if let Some(ref span) = note.span {
+                snippet(&mut out, span, '-', CYAN, color);
+            }
+        }
+        out
+    }
+}
+
+/// Wraps `text` in the escape codes for `code` if `color` is set.
+fn paint(color: bool, code: &str, text: &str) -> String {
+    match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text.to_string(),
+    }
+}
+
+/// Writes the source line `span` is on to `out`, with `marker`s in
+/// `marker_color` under the part of it the span covers.
+fn snippet(out: &mut String, span: &Span, marker: char, marker_color: &str, color: bool) {
+    let line = match span.source_line {
+        Some(ref line) => line,
+        None => return,
+    };
+    let number = span.position.line.to_string();
+    let gutter = paint(color, BLUE, "|");
+    let label = paint(color, BLUE, &number);
+    let _ = write!(out, "\n {} {} {}", label, gutter, line);
+
+    // Columns count characters. Tabs are kept so the markers line up
+    // with the source however wide the terminal shows them.
+    let start = span.position.column - 1;
+    let indent: String = line
+        .chars()
+        .take(start)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let remaining = line.chars().count().saturating_sub(start);
+    let width = span.length.min(remaining).max(1);
+    let markers: String = std::iter::repeat_n(marker, width).collect();
+    let markers = paint(color, marker_color, &markers);
+    let blank = " ".repeat(number.len());
+    let _ = write!(out, "\n {} {} {}{}", blank, gutter, indent, markers);
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref cause) = self.cause {
+            writeln!(f, "{}", cause)?;
+        }
+
+        if self.kind == ErrorKind::Runtime {
+            write!(f, "{}", self.message)?;
+            for frame in self.trace.iter() {
+                write!(f, "\n{}", frame)?;
+            }
+        } else {
+            if let Some(ref span) = self.span {
+                write!(f, "[{}] ", span)?;
+            }
+            write!(f, "Error")?;
+            if let Some(ref found) = self.found {
+                write!(f, " at {}", found)?;
+            }
+            write!(f, ": {}", self.message)?;
+        }
+
+        for note in self.notes.iter() {
+            write!(f, "\n{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostic {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_deref().map(|cause| cause as &(dyn Error + 'static))
+    }
+}
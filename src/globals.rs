@@ -0,0 +1,114 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! The global variables of a vm.
+
+Each global name is given a slot the first time it is compiled, and the
+instructions reading and writing it carry the slot rather than the name.
+A slot exists before its variable is defined, as code may mention a
+global that is only defined later on. Names are still looked up for the
+repl and for Rust code reaching into the vm. The slots a compile gives
+out are taken back if it fails.
+ */
+use crate::value::Value;
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct Globals {
+    /// The slot of every name given one.
+    slots: HashMap<String, usize>,
+    /// The name of each slot.
+    names: Vec<String>,
+    /// The value of each slot, [`None`] until it is defined.
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals::default()
+    }
+
+    /// Returns the slot of `name`, giving it a new one if it has none.
+    pub fn slot(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        let slot = self.names.len();
+        self.slots.insert(name.to_string(), slot);
+        self.names.push(name.to_string());
+        self.values.push(None);
+        slot
+    }
+
+    /// The number of slots given out.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Takes back the slots given out since there were `len`, so a
+    /// failed compile doesn't keep the names it saw. Nothing is taken
+    /// back if any of those slots has been defined.
+    pub fn truncate(&mut self, len: usize) {
+        if self.values[len..].iter().any(Option::is_some) {
+            return;
+        }
+        for name in self.names.drain(len..) {
+            self.slots.remove(&name);
+        }
+        self.values.truncate(len);
+    }
+
+    /// Returns the slot of `name`, if it has one.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    /// The name of every slot, in slot order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Returns the value in `slot`, if it is defined.
+    pub fn get(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+
+    /// Defines the variable in `slot`, or changes its value if it is defined.
+    pub fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    /// Changes the value in `slot`. Returns false, changing nothing, if
+    /// the variable isn't defined.
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match self.values[slot] {
+            Some(ref mut current) => {
+                *current = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The values of every defined variable.
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flatten()
+    }
+}
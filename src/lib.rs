@@ -14,186 +14,39 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-pub mod chunk {
-    use crate::value::Value;
-    use std::fmt::{self, Display};
-    /// Code representing and instruction to execute.
-    #[derive(Clone, Copy)]
-    pub enum OpCode {
-        Return,
-        Constant,
-        Negate,
-        Add,
-        Subtract,
-        Multiply,
-        Divide,
-        Nil,
-        True,
-        False,
-        Not,
-        Equal,
-        Greater,
-        Less,
-        Print,
-        Pop,
-        DefineGlobal,
-        GetGlobal,
-        SetGlobal,
-	GetLocal,
-	SetLocal,
-    }
-
-    impl From<u8> for OpCode {
-        fn from(value: u8) -> OpCode {
-            match value {
-                0 => OpCode::Return,
-                1 => OpCode::Constant,
-                2 => OpCode::Negate,
-                3 => OpCode::Add,
-                4 => OpCode::Subtract,
-                5 => OpCode::Multiply,
-                6 => OpCode::Divide,
-                7 => OpCode::Nil,
-                8 => OpCode::True,
-                9 => OpCode::False,
-                10 => OpCode::Not,
-                11 => OpCode::Equal,
-                12 => OpCode::Greater,
-                13 => OpCode::Less,
-                14 => OpCode::Print,
-                15 => OpCode::Pop,
-                16 => OpCode::DefineGlobal,
-                17 => OpCode::GetGlobal,
-                18 => OpCode::SetGlobal,
-		19 => OpCode::GetLocal,
-		20 => OpCode::SetLocal,
-                _ => unreachable!(),
-            }
-        }
-    }
-
-    impl Display for OpCode {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match *self {
-                OpCode::Return => write!(f, "OP_RETURN"),
-                OpCode::Constant => write!(f, "OP_CONSTANT"),
-                OpCode::Negate => write!(f, "OP_NEGATE"),
-                OpCode::Add => write!(f, "OP_ADD"),
-                OpCode::Subtract => write!(f, "OP_SUBTRACT"),
-                OpCode::Multiply => write!(f, "OP_MULTIPLY"),
-                OpCode::Divide => write!(f, "OP_DIVIDE"),
-                OpCode::Nil => write!(f, "OP_NIL"),
-                OpCode::True => write!(f, "OP_TRUE"),
-                OpCode::False => write!(f, "OP_FALSE"),
-                OpCode::Not => write!(f, "OP_NOT"),
-                OpCode::Equal => write!(f, "OP_EQUAL"),
-                OpCode::Greater => write!(f, "OP_GREATER"),
-                OpCode::Less => write!(f, "OP_LESS"),
-                OpCode::Print => write!(f, "OP_PRINT"),
-                OpCode::Pop => write!(f, "OP_POP"),
-                OpCode::DefineGlobal => write!(f, "OP_DEFINE_GLOBAL"),
-                OpCode::GetGlobal => write!(f, "OP_GET_GLOBAL"),
-                OpCode::SetGlobal => write!(f, "OP_SET_GLOBAL"),
-		OpCode::GetLocal => write!(f, "OP_GET_LOCAL"),
-		OpCode::SetLocal => write!(f, "OP_SET_LOCAL"),
-            }
-        }
-    }
-
-    /// A chunk houses the instructions for the vm to execute
-    pub struct Chunk {
-        pub code: Vec<u8>,
-        pub constants: Vec<Value>,
-        pub lines: Vec<usize>,
-    }
-
-    impl Chunk {
-        pub fn new() -> Self {
-            Self {
-                code: Vec::new(),
-                constants: Vec::new(),
-                lines: Vec::new(),
-            }
-        }
-        /// Writes to [`chunk.code`] and [`chunk.lines`]
-        pub fn write(&mut self, code: u8, line: usize) {
-            self.code.push(code);
-            self.lines.push(line);
-        }
-        /// Writes to [`chunk.constans`] and returns its position in the array.
-        pub fn constant(&mut self, value: Value) -> u8 {
-            self.constants.push(value);
-            (self.constants.len() - 1) as u8
-        }
-    }
-}
-
-pub mod debug {
-
-    use super::chunk::{Chunk, OpCode};
-    /// Outputs `code` to stdout.
-    /// Returns offset + 1.
-    fn simple_instruction(code: OpCode, offset: usize) -> usize {
-        println!("{}", code);
-        offset + 1
-    }
-
-    /// Outputs `code` to stdout along with its assosiated value.
-    /// Returns offset + 2.
-    fn constant_instruction(code: OpCode, chunk: &Chunk, offset: usize) -> usize {
-        let constant = chunk.code[offset + 1] as usize;
-        print!("{:-16} {:4} '", code, constant);
-        crate::value::print_value(chunk.constants[constant]);
-        println!("'");
-        offset + 2
-    }
-
-    fn byte_instruction(code: OpCode, chunk: &Chunk, offset: usize) -> usize {
-	let slot = chunk.code[offset + 1];
-	println!("{:-16} {:4}", code, slot);
-	offset + 2
-    }
-    
-    /// Prints code at chunk offset.
-    pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-        print!("{:04} ", offset);
-
-        if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-            print!("   | ");
-        } else {
-            print!("{:4} ", chunk.lines[offset]);
-        }
-
-        let instruction = OpCode::from(chunk.code[offset]);
-        match instruction {
-            OpCode::Return
-            | OpCode::Negate
-            | OpCode::Add
-            | OpCode::Subtract
-            | OpCode::Multiply
-            | OpCode::Divide
-            | OpCode::Nil
-            | OpCode::True
-            | OpCode::False
-            | OpCode::Not
-            | OpCode::Equal
-            | OpCode::Greater
-            | OpCode::Less
-            | OpCode::Print
-            | OpCode::Pop => simple_instruction(instruction, offset),
-            OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal => {
-                constant_instruction(instruction, chunk, offset)
-            }
-	    OpCode::SetLocal | OpCode::GetLocal => byte_instruction(instruction, chunk, offset),
-        }
-    }
-
-    /// Prints the contents of [`Chunk`] to std out.
-    pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-        println!("== {} ==", name);
-        let mut offset = 0;
-        while offset < chunk.code.len() {
-            offset = disassemble_instruction(chunk, offset);
-        }
-    }
-}
+/*! An Emacs Lisp like language for configuring eswm.
+
+Scripts are run by a [`Vm`], which holds their globals from one call
+to [`Vm::interpret`] to the next. Rust code exposes functions to
+scripts with [`Vm::define_native`], and shows the values scripts give
+it with [`Vm::display`]. Nothing is printed when a script fails, the
+[`VmErr`] returned holds a [`Diagnostic`] for every error.
+
+```
+use eswm::{Syntax, Vm};
+
+let mut vm = Vm::new();
+vm.interpret_as("(defvar gap 10)", "<config>", Syntax::Lisp).unwrap();
+assert_eq!(vm.global("gap").map(|gap| gap.as_number()), Some(10.0));
+
+let error = vm.interpret_as("(car 1)", "<config>", Syntax::Lisp).unwrap_err();
+assert_eq!(error.diagnostics()[0].message, "Argument to 'car' must be a list.");
+```
+ */
+mod cache;
+mod chunk;
+mod compiler;
+#[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
+mod debug;
+mod error;
+mod globals;
+mod natives;
+mod value;
+mod vm;
+
+pub use cache::{source_hash, CacheInfo, FORMAT_VERSION};
+pub use chunk::Position;
+pub use compiler::{OptLevel, Syntax};
+pub use error::{Diagnostic, ErrorKind, Note, Span, TraceFrame};
+pub use value::{Value, ValueDisplay, ValueType};
+pub use vm::{InterpretResult, RuntimeError, Vm, VmConfig, VmErr};
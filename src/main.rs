@@ -14,61 +14,228 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use std::env;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
-use std::io::Write;
-mod compiler;
-#[allow(dead_code)]
-mod lib;
-mod value;
-#[allow(dead_code)]
-mod vm;
-
-fn repl() -> io::Result<()> {
-    let mut vm: vm::Vm = vm::Vm::new();
-    loop {
-        let mut input = String::new();
-
-        print!("> ");
-        io::stdout().flush()?;
-
-        io::stdin().read_line(&mut input)?;
-	if input.len() == 0 {
-	    break Ok(());
-	}
-	
-        if let Err(_err) = vm.interpret(&input) {
-            vm.chunk.take();
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use eswm::{CacheInfo, OptLevel, Syntax, ValueType, Vm, VmErr};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+/// Prints `error` to stderr, in color if stderr is a terminal.
+fn report(error: &VmErr) {
+    let color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    eprintln!("{}", error.render(color));
+}
+
+/// Where the repl keeps the lines typed into it between sessions.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".eswm_history"))
+}
+
+/// Reads and evaluates input until it ends, printing the value of each
+/// expression that isn't nil. Input that ends early, such as a line
+/// leaving a brace open, is continued on the next line until it is
+/// complete or a blank line is entered.
+fn repl(syntax: Syntax, level: OptLevel) -> io::Result<()> {
+    let mut vm = Vm::new();
+    vm.set_opt_level(level);
+    let mut editor = DefaultEditor::new().map_err(io::Error::other)?;
+    let history = history_path();
+    if let Some(ref path) = history {
+        // There is no history to load the first time the repl is run.
+        let _ = editor.load_history(path);
+    }
+
+    let mut input = String::new();
+    let mut ended = false;
+    while !ended {
+        let prompt = if input.is_empty() { "> " } else { ". " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                // Ctrl-C throws away what has been typed so far.
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                // Whatever is left is run as it is, like after a blank line.
+                ended = true;
+                String::new()
+            }
+            Err(error) => return Err(io::Error::other(error)),
+        };
+        // A blank line runs the input so far even if it looks unfinished.
+        let blank = line.trim().is_empty();
+        if blank && input.is_empty() {
+            continue;
+        } else if !blank {
+            input.push_str(&line);
+            input.push('\n');
         }
-        vm.chunk.take();
+
+        // `,macroexpand FORM` shows what a lisp form expands into.
+        let (result, syntax) = match input.trim_start().strip_prefix(",macroexpand") {
+            Some(form) => {
+                let form = format!("(macroexpand '{})", form.trim());
+                (vm.evaluate(&form, "<input>", Syntax::Lisp), Syntax::Lisp)
+            }
+            None => (vm.evaluate(&input, "<input>", syntax), syntax),
+        };
+        match result {
+            Err(error) if error.is_incomplete() && !blank => continue,
+            Err(error) => report(&error),
+            Ok(value) if value.is_type(ValueType::Nil) => {}
+            Ok(value) if syntax == Syntax::Lisp => println!("{}", vm.display_lisp(value)),
+            Ok(value) => println!("{}", vm.display(value)),
+        }
+        let _ = editor.add_history_entry(input.trim_end());
+        input.clear();
     }
+
+    if let Some(ref path) = history {
+        if let Err(error) = editor.save_history(path) {
+            eprintln!("Could not save history to {}: {}", path.display(), error);
+        }
+    }
+    Ok(())
 }
 
-fn run_file(file: &str) -> io::Result<()> {
-    let mut vm: vm::Vm = vm::Vm::new();
-    let mut file = std::fs::File::open(file)?;
+/// Reports `error` and exits with the status for its kind.
+fn exit_with(error: VmErr) -> ! {
+    report(&error);
+    match error {
+        VmErr::CompileError(_) => std::process::exit(65),
+        VmErr::RuntimeError(_) => std::process::exit(70),
+    }
+}
+
+fn run_file(path: &str, syntax: Syntax, level: OptLevel) -> io::Result<()> {
+    let mut vm = Vm::new();
+    vm.set_opt_level(level);
+    let mut file = std::fs::File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
-    if let Err(result) = vm.interpret(&contents) {
-        match result {
-            vm::VmErr::CompileError => std::process::exit(65),
-            vm::VmErr::RuntimeError => std::process::exit(70),
-        }
+    if let Err(error) = vm.interpret_as(&contents, path, syntax) {
+        exit_with(error);
     }
 
     Ok(())
 }
 
+/// Compiles the file at `path` into a cache written to `output`.
+fn compile_file(path: &str, output: &str, syntax: Syntax, level: OptLevel) -> io::Result<()> {
+    let mut vm = Vm::new();
+    vm.set_opt_level(level);
+    let contents = fs::read_to_string(path)?;
+    // The cache finds its source by this path, wherever it is run from.
+    let file = fs::canonicalize(path)?.to_string_lossy().into_owned();
+    match vm.compile_cache(&contents, &file, syntax) {
+        Ok(bytes) => fs::write(output, bytes),
+        Err(error) => exit_with(error),
+    }
+}
+
+/// Runs the cache at `path`. If its source has changed since it was
+/// written, the source is compiled again and the cache updated.
+fn run_cache(path: &str, level: OptLevel) -> io::Result<()> {
+    let mut bytes = fs::read(path)?;
+    let info = match CacheInfo::read(&bytes) {
+        Some(info) => info,
+        None => {
+            eprintln!("{} isn't an eswm cache.", path);
+            std::process::exit(65);
+        }
+    };
+    let mut vm = Vm::new();
+    vm.set_opt_level(level);
+
+    // Without its source the cache is run as it is.
+    let source = fs::read_to_string(&info.file).ok();
+    if let Some(ref source) = source {
+        if !info.is_fresh(source) {
+            bytes = vm
+                .compile_cache(source, &info.file, info.syntax)
+                .unwrap_or_else(|error| exit_with(error));
+            if let Err(error) = fs::write(path, &bytes) {
+                eprintln!("Couldn't update {}: {}", path, error);
+            }
+        }
+    }
+    if let Err(error) = vm.interpret_cache(&bytes, source.as_deref()) {
+        exit_with(error);
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: eswm [--lisp] [-O0|-O1|-O2] [path]");
+    eprintln!("       eswm compile [--lisp] [-O0|-O1|-O2] path [-o output]");
+    std::process::exit(64);
+}
+
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        repl()?;
-    } else if args.len() == 2 {
-        run_file(&args[1])?;
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Files are read as S-expressions if they look like lisp, or when asked to.
+    let lisp = args.iter().position(|arg| arg == "--lisp");
+    if let Some(flag) = lisp {
+        args.remove(flag);
+    }
+    let mut level = OptLevel::default();
+    args.retain(|arg| {
+        level = match arg.as_str() {
+            "-O0" => OptLevel::None,
+            "-O1" => OptLevel::Peephole,
+            "-O2" => OptLevel::Full,
+            _ => return true,
+        };
+        false
+    });
+
+    let syntax = |path: &str| match lisp {
+        Some(_) => Syntax::Lisp,
+        None => Syntax::from_path(path),
+    };
+
+    if args.first().is_some_and(|arg| arg == "compile") {
+        args.remove(0);
+        let output = match args.iter().position(|arg| arg == "-o") {
+            Some(flag) if flag + 1 < args.len() => {
+                args.remove(flag);
+                Some(args.remove(flag))
+            }
+            Some(_) => usage(),
+            None => None,
+        };
+        if args.len() != 1 {
+            usage();
+        }
+        let output = output.unwrap_or_else(|| {
+            let path = Path::new(&args[0]).with_extension("esc");
+            path.to_string_lossy().into_owned()
+        });
+        compile_file(&args[0], &output, syntax(&args[0]), level)?;
+    } else if args.is_empty() {
+        let syntax = if lisp.is_some() {
+            Syntax::Lisp
+        } else {
+            Syntax::Script
+        };
+        repl(syntax, level)?;
+    } else if args.len() == 1 {
+        if Path::new(&args[0])
+            .extension()
+            .is_some_and(|extension| extension == "esc")
+        {
+            run_cache(&args[0], level)?;
+        } else {
+            run_file(&args[0], syntax(&args[0]), level)?;
+        }
     } else {
-        eprintln!("Usage: eswm [path]");
-        std::process::exit(64);
+        usage();
     }
     Ok(())
 }
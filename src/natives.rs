@@ -0,0 +1,355 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! The functions every vm starts out with.
+
+Each native is defined along with the number of arguments it takes,
+so the functions here only need to check what kind of values they got.
+
+Errors are signalled as error objects, lists of an error symbol
+followed by data about the error, the way Emacs Lisp does it. A native
+given the wrong kind of value raises `wrong-type-argument`.
+
+Lists are chains of cons cells ending in nil. A native building a list
+conses it up from the last element, so every cell it has made so far is
+reachable from the newest one should the allocation collect garbage.
+ */
+use crate::compiler::macroexpand;
+use crate::value::{
+    objects::{ObjCons, ObjId, ObjSymbol},
+    values_equal, Value, ValueType,
+};
+use crate::vm::{
+    allocate_cons, allocate_string, allocate_symbol, error_message, RuntimeError, Vm,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Defines the natives on `vm`.
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("clock", 0, clock_native);
+    vm.define_native("car", 1, car_native);
+    vm.define_native("cdr", 1, cdr_native);
+    vm.define_native("cons", 2, cons_native);
+    vm.define_native("list", 0.., list_native);
+    vm.define_native("length", 1, length_native);
+    vm.define_native("/", 1.., divide_native);
+    vm.define_native("concat", 0.., concat_native);
+    vm.define_native("nth", 2, nth_native);
+    vm.define_native("append", 0.., append_native);
+    vm.define_native("reverse", 1, reverse_native);
+    vm.define_native("mapcar", 2, mapcar_native);
+    vm.define_native("assoc", 2, assoc_native);
+    vm.define_native("symbol-name", 1, symbol_name_native);
+    vm.define_native("intern", 1, intern_native);
+    vm.define_native("boundp", 1, boundp_native);
+    vm.define_native("gensym", 0..=1, gensym_native);
+    vm.define_native("macroexpand", 1, macroexpand_native);
+    vm.define_native("signal", 2, signal_native);
+    vm.define_native("error", 1.., error_native);
+    vm.define_native("error-message-string", 1, error_message_string_native);
+}
+
+/// An error for a native given the wrong kind of value.
+fn wrong_type(message: impl Into<String>) -> RuntimeError {
+    RuntimeError::Condition("wrong-type-argument", message.into())
+}
+
+/// Returns the elements of `list`, reporting an error in `name` if it isn't a proper list.
+fn list_items(vm: &mut Vm, list: Value, name: &str) -> Result<Vec<Value>, RuntimeError> {
+    let mut items = Vec::new();
+    let mut tail = list;
+    while tail.is_obj_type(ObjId::Cons) {
+        let cons = vm.heap.get::<ObjCons>(tail.as_handle());
+        items.push(cons.car);
+        tail = cons.cdr;
+    }
+    if !tail.is_type(ValueType::Nil) {
+        return Err(wrong_type(format!("Argument to '{}' must be a list.", name)));
+    }
+    Ok(items)
+}
+
+/// Builds a list of `items` ending in `tail`.
+fn list_from(vm: &mut Vm, items: &[Value], tail: Value) -> Value {
+    items
+        .iter()
+        .rev()
+        .fold(tail, |tail, item| allocate_cons(vm, *item, tail))
+}
+
+/// Returns the number of seconds since the unix epoch.
+fn clock_native(_vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(now.as_secs_f64().into())
+}
+
+fn car_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].is_type(ValueType::Nil) {
+        return Ok(Value::None);
+    }
+    if !args[0].is_obj_type(ObjId::Cons) {
+        return Err(wrong_type("Argument to 'car' must be a list."));
+    }
+    Ok(vm.heap.get::<ObjCons>(args[0].as_handle()).car)
+}
+
+fn cdr_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].is_type(ValueType::Nil) {
+        return Ok(Value::None);
+    }
+    if !args[0].is_obj_type(ObjId::Cons) {
+        return Err(wrong_type("Argument to 'cdr' must be a list."));
+    }
+    Ok(vm.heap.get::<ObjCons>(args[0].as_handle()).cdr)
+}
+
+fn cons_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(allocate_cons(vm, args[0], args[1]))
+}
+
+fn list_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(list_from(vm, args, Value::None))
+}
+
+/// Returns the number of elements in a list or characters in a string.
+fn length_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].is_obj_type(ObjId::String) {
+        let length = vm.heap.string(args[0].as_handle()).chars().count();
+        return Ok((length as i64).into());
+    }
+    let items = list_items(vm, args[0], "length")?;
+    Ok((items.len() as i64).into())
+}
+
+/// Joins the strings given into a new one.
+fn concat_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut string = String::new();
+    for arg in args {
+        if !arg.is_obj_type(ObjId::String) {
+            return Err(wrong_type("Arguments to 'concat' must be strings."));
+        }
+        string.push_str(vm.heap.string(arg.as_handle()));
+    }
+    Ok(allocate_string(vm, string))
+}
+
+/// Divides the first argument by the rest, or 1 by a lone argument.
+/// Integers are divided as integers, truncating toward zero, unless a
+/// float is among the arguments, when they are all divided as floats.
+fn divide_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args.iter().all(|arg| arg.is_numeric()) {
+        return Err(wrong_type("Arguments to '/' must be numbers."));
+    }
+    let (first, divisors) = match args {
+        [only] => (Value::from(1), std::slice::from_ref(only)),
+        _ => (args[0], &args[1..]),
+    };
+    if !args.iter().all(|arg| arg.is_type(ValueType::Int)) {
+        let quotient = divisors
+            .iter()
+            .fold(first.as_number(), |quotient, divisor| quotient / divisor.as_number());
+        return Ok(quotient.into());
+    }
+    let mut quotient = first.as_int();
+    for divisor in divisors {
+        if divisor.as_int() == 0 {
+            return Err(RuntimeError::Condition("arith-error", "Division by zero.".into()));
+        }
+        quotient /= divisor.as_int();
+    }
+    Ok(quotient.into())
+}
+
+/// Returns element `n` of a list, or nil if the list is shorter than that.
+fn nth_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let n = args[0];
+    if !n.is_numeric() || n.as_number().fract() != 0.0 {
+        return Err(wrong_type("Index given to 'nth' must be a whole number."));
+    }
+    if n.as_number() < 0.0 {
+        return Err(RuntimeError::Condition(
+            "args-out-of-range",
+            "Index given to 'nth' can't be negative.".into(),
+        ));
+    }
+    let items = list_items(vm, args[1], "nth")?;
+    Ok(items
+        .get(n.as_number() as usize)
+        .copied()
+        .unwrap_or(Value::None))
+}
+
+/// Joins lists together. The last list is shared rather than copied.
+fn append_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (last, rest) = match args.split_last() {
+        Some(split) => split,
+        None => return Ok(Value::None),
+    };
+    let mut items = Vec::new();
+    for list in rest {
+        items.extend(list_items(vm, *list, "append")?);
+    }
+    Ok(list_from(vm, &items, *last))
+}
+
+fn reverse_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut items = list_items(vm, args[0], "reverse")?;
+    items.reverse();
+    Ok(list_from(vm, &items, Value::None))
+}
+
+/// Calls a function on every element of a list and returns a list of the results.
+fn mapcar_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let items = list_items(vm, args[1], "mapcar")?;
+
+    // Keep the results on the stack so they survive collections
+    // triggered by the calls that follow.
+    let base = vm.stack.len();
+    for item in items {
+        let result = vm.call_function(args[0], &[item])?;
+        vm.push(result)?;
+    }
+    let results = vm.stack[base..].to_vec();
+    let list = list_from(vm, &results, Value::None);
+    vm.stack.truncate(base);
+    Ok(list)
+}
+
+/// Returns the first pair in an association list whose car is equal to the key.
+fn assoc_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    for item in list_items(vm, args[1], "assoc")? {
+        if !item.is_obj_type(ObjId::Cons) {
+            continue;
+        }
+        let key = vm.heap.get::<ObjCons>(item.as_handle()).car;
+        if values_equal(&vm.heap, key, args[0]) {
+            return Ok(item);
+        }
+    }
+    Ok(Value::None)
+}
+
+/// Returns the name of a symbol as a string.
+fn symbol_name_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args[0].is_obj_type(ObjId::Symbol) {
+        return Err(wrong_type("Argument to 'symbol-name' must be a symbol."));
+    }
+    let name = vm.heap.symbol(args[0].as_handle()).to_string();
+    Ok(allocate_string(vm, name))
+}
+
+/// Returns the symbol named by a string, creating it if need be.
+fn intern_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args[0].is_obj_type(ObjId::String) {
+        return Err(wrong_type("Argument to 'intern' must be a string."));
+    }
+    let name = vm.heap.string(args[0].as_handle()).to_string();
+    Ok(allocate_symbol(vm, name))
+}
+
+/// Whether the global a symbol names is defined.
+fn boundp_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args[0].is_obj_type(ObjId::Symbol) {
+        return Err(wrong_type("Argument to 'boundp' must be a symbol."));
+    }
+    let name = vm.heap.symbol(args[0].as_handle());
+    Ok(vm.global(name).is_some().into())
+}
+
+/// Returns a new symbol that isn't interned, for macros to name the
+/// variables they introduce. It takes an optional prefix for the name.
+fn gensym_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let prefix = match args {
+        [] => String::from("g"),
+        [prefix] if prefix.is_obj_type(ObjId::String) => vm.heap.string(prefix.as_handle()).to_string(),
+        _ => return Err(wrong_type("Prefix given to 'gensym' must be a string.")),
+    };
+    let name = format!("{}{}", prefix, vm.gensym_counter);
+    vm.gensym_counter += 1;
+    Ok(vm.allocate(ObjSymbol(name)).into())
+}
+
+/// Expands a form until it is no longer a call to a macro.
+fn macroexpand_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(macroexpand(vm, args[0])?)
+}
+
+/// Raises the error object made of an error symbol and its data.
+fn signal_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args[0].is_obj_type(ObjId::Symbol) {
+        return Err(wrong_type("First argument to 'signal' must be a symbol."));
+    }
+    let error = allocate_cons(vm, args[0], args[1]);
+    Err(vm.signal(error).into())
+}
+
+/// Raises an `error` with a message formatted from the rest of the
+/// arguments, as `format` would.
+fn error_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args[0].is_obj_type(ObjId::String) {
+        return Err(wrong_type("First argument to 'error' must be a string."));
+    }
+    let message = format_string(vm, args[0], &args[1..])?;
+    let message = allocate_string(vm, message);
+    vm.push(message)?;
+    let symbol = allocate_symbol(vm, String::from("error"));
+    vm.push(symbol)?;
+    let data = allocate_cons(vm, message, Value::None);
+    let error = allocate_cons(vm, symbol, data);
+    vm.pop()?;
+    vm.pop()?;
+    Err(vm.signal(error).into())
+}
+
+/// Replaces the `%s`, `%S` and `%d` directives in `format` with `args`
+/// in turn. `%%` stands for a lone `%`.
+fn format_string(vm: &Vm, format: Value, args: &[Value]) -> Result<String, RuntimeError> {
+    let mut string = String::new();
+    let mut args = args.iter();
+    let mut chars = vm.heap.string(format.as_handle()).chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            string.push(c);
+            continue;
+        }
+        let directive = chars.next();
+        if directive == Some('%') {
+            string.push('%');
+            continue;
+        }
+        let arg = match args.next() {
+            Some(arg) => *arg,
+            None => return Err("Not enough arguments for format string.".into()),
+        };
+        match directive {
+            Some('s' | 'S') => string.push_str(&arg.display(&vm.heap).to_string()),
+            Some('d') if arg.is_type(ValueType::Int) => string.push_str(&arg.as_int().to_string()),
+            Some('d') if arg.is_numeric() => string.push_str(&(arg.as_number() as i64).to_string()),
+            Some('d') => return Err("Format specifier '%d' doesn't match argument type.".into()),
+            Some(c) => return Err(format!("Invalid format operation '%{}'.", c).into()),
+            None => return Err("Format string ends in the middle of a directive.".into()),
+        }
+    }
+    Ok(string)
+}
+
+/// Returns the message an error object would be reported with.
+fn error_message_string_native(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let message = error_message(&vm.heap, args[0]);
+    Ok(allocate_string(vm, message))
+}
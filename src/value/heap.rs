@@ -0,0 +1,308 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! The garbage collected object heap.
+
+Objects are stored in slots and referred to by [`Handle`]s. A handle
+stays valid for as long as the object it refers to is reachable from
+the roots handed to [`Heap::collect`].
+ */
+use super::objects::{ObjId, ObjString, ObjSymbol, ObjVal, Object};
+use super::Value;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+/// The number of bytes that can be allocated before the first collection.
+pub const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+/// How much the heap may grow after a collection before the next one.
+pub const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// A reference to an object on the [`Heap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
+pub struct Handle(u32);
+
+impl Handle {
+    /// Rebuilds the handle at `index`, as packed into a NaN-boxed value.
+    #[cfg(feature = "nan_boxing")]
+    pub(super) fn new(index: u32) -> Handle {
+        Handle(index)
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Marks the objects reachable from the roots of a collection.
+pub struct Tracer<'a> {
+    marks: &'a mut Vec<bool>,
+    gray: &'a mut Vec<Handle>,
+}
+
+impl Tracer<'_> {
+    pub fn mark_object(&mut self, handle: Handle) {
+        if self.marks[handle.index()] {
+            return;
+        }
+        self.marks[handle.index()] = true;
+        self.gray.push(handle);
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(handle) = value.handle() {
+            self.mark_object(handle);
+        }
+    }
+}
+
+/// Interned strings or symbols, looked up by the hash of their text.
+/// Only the objects hold the text, so it is compared through the heap.
+#[derive(Default)]
+struct Interned {
+    hasher: RandomState,
+    handles: HashMap<u64, Vec<Handle>>,
+}
+
+impl Interned {
+    fn hash(&self, text: &str) -> u64 {
+        self.hasher.hash_one(text)
+    }
+
+    fn find<'a>(&self, text: &str, text_of: impl Fn(Handle) -> &'a str) -> Option<Handle> {
+        let handles = self.handles.get(&self.hash(text))?;
+        handles.iter().copied().find(|&handle| text_of(handle) == text)
+    }
+
+    /// Interns the object at `handle`, whose text hashes to `hash`.
+    fn insert(&mut self, hash: u64, handle: Handle) {
+        self.handles.entry(hash).or_default().push(handle);
+    }
+
+    /// Forgets the objects `marks` doesn't mark as reachable.
+    fn retain_marked(&mut self, marks: &[bool]) {
+        self.handles.retain(|_, handles| {
+            handles.retain(|handle| marks[handle.index()]);
+            !handles.is_empty()
+        });
+    }
+}
+
+pub struct Heap {
+    objects: Vec<Option<Box<dyn ObjVal>>>,
+    marks: Vec<bool>,
+    /// Slots freed by the last collection, ready to be reused.
+    free: Vec<usize>,
+    /// Every live string, used to intern strings.
+    strings: Interned,
+    /// Every live symbol, used to intern symbols.
+    symbols: Interned,
+    bytes_allocated: usize,
+    next_gc: usize,
+    grow_factor: usize,
+    /// Whether to collect on every allocation.
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::with_threshold(GC_INITIAL_THRESHOLD, GC_HEAP_GROW_FACTOR)
+    }
+
+    /// Creates a heap that collects once `threshold` bytes are allocated,
+    /// and then again every time it grows by `grow_factor`.
+    pub fn with_threshold(threshold: usize, grow_factor: usize) -> Heap {
+        Heap {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            strings: Interned::default(),
+            symbols: Interned::default(),
+            bytes_allocated: 0,
+            next_gc: threshold,
+            grow_factor: grow_factor.max(1),
+            stress: cfg!(feature = "debug_stress_gc"),
+        }
+    }
+
+    /// Makes the heap collect on every allocation, as every heap does
+    /// with the `debug_stress_gc` feature, to shake out objects that
+    /// are used without being reachable from a root.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    /// The number of bytes the objects on the heap take up.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Whether enough has been allocated since the last collection to warrant another.
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Moves `object` onto the heap. The caller is responsible for
+    /// collecting garbage beforehand.
+    pub fn insert<T: ObjVal>(&mut self, object: T) -> Object {
+        self.bytes_allocated += object.size();
+        let id = object.id();
+        let object: Box<dyn ObjVal> = Box::new(object);
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                index
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.marks.push(false);
+                self.objects.len() - 1
+            }
+        };
+
+        let handle = Handle(index as u32);
+        #[cfg(feature = "debug_log_gc")]
+        println!(
+            "{:?} allocate {} for {:?}",
+            handle,
+            self.objects[index].as_ref().unwrap().size(),
+            id
+        );
+        Object::new(id, handle)
+    }
+
+    /// Returns the interned string equal to `string`, if there is one.
+    pub fn find_string(&self, string: &str) -> Option<Object> {
+        self.strings
+            .find(string, |handle| self.string(handle))
+            .map(|handle| Object::new(ObjId::String, handle))
+    }
+
+    /// Moves `string` onto the heap and interns it.
+    pub fn insert_string(&mut self, string: String) -> Object {
+        let hash = self.strings.hash(&string);
+        let object = self.insert(ObjString(string));
+        self.strings.insert(hash, object.handle);
+        object
+    }
+
+    /// Returns the interned symbol named `name`, if there is one.
+    pub fn find_symbol(&self, name: &str) -> Option<Object> {
+        self.symbols
+            .find(name, |handle| self.symbol(handle))
+            .map(|handle| Object::new(ObjId::Symbol, handle))
+    }
+
+    /// Moves a symbol named `name` onto the heap and interns it.
+    pub fn insert_symbol(&mut self, name: String) -> Object {
+        let hash = self.symbols.hash(&name);
+        let object = self.insert(ObjSymbol(name));
+        self.symbols.insert(hash, object.handle);
+        object
+    }
+
+    pub fn get_dyn(&self, handle: Handle) -> &dyn ObjVal {
+        self.objects[handle.index()]
+            .as_deref()
+            .expect("handle refers to a freed object")
+    }
+
+    pub fn get<T: ObjVal>(&self, handle: Handle) -> &T {
+        self.objects[handle.index()]
+            .as_ref()
+            .and_then(|object| object.as_any().downcast_ref::<T>())
+            .expect("handle refers to an object of another type")
+    }
+
+    pub fn get_mut<T: ObjVal>(&mut self, handle: Handle) -> &mut T {
+        self.objects[handle.index()]
+            .as_mut()
+            .and_then(|object| object.as_any_mut().downcast_mut::<T>())
+            .expect("handle refers to an object of another type")
+    }
+
+    pub fn string(&self, handle: Handle) -> &str {
+        &self.get::<ObjString>(handle).0
+    }
+
+    pub fn symbol(&self, handle: Handle) -> &str {
+        &self.get::<ObjSymbol>(handle).0
+    }
+
+    /// Frees every object not reachable from `roots`.
+    pub fn collect<I: IntoIterator<Item = Handle>>(&mut self, roots: I) {
+        #[cfg(feature = "debug_log_gc")]
+        let before = self.bytes_allocated;
+        #[cfg(feature = "debug_log_gc")]
+        println!("-- gc begin");
+
+        let mut gray = Vec::new();
+        let mut tracer = Tracer {
+            marks: &mut self.marks,
+            gray: &mut gray,
+        };
+        for root in roots {
+            tracer.mark_object(root);
+        }
+
+        while let Some(handle) = tracer.gray.pop() {
+            if let Some(object) = self.objects[handle.index()].as_ref() {
+                object.trace(&mut tracer);
+            }
+        }
+
+        self.sweep();
+        self.next_gc = self.bytes_allocated.max(1) * self.grow_factor;
+
+        #[cfg(feature = "debug_log_gc")]
+        {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn sweep(&mut self) {
+        let marks = &self.marks;
+        self.strings.retain_marked(marks);
+        self.symbols.retain_marked(marks);
+
+        for index in 0..self.objects.len() {
+            if self.marks[index] {
+                self.marks[index] = false;
+                continue;
+            }
+
+            if let Some(object) = self.objects[index].take() {
+                #[cfg(feature = "debug_log_gc")]
+                println!("{:?} free", Handle(index as u32));
+                self.bytes_allocated -= object.size();
+                self.free.push(index);
+            }
+        }
+    }
+}
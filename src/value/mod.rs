@@ -14,143 +14,174 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-// pub mod objects;
-// use objects::*;
-// use std::cell::Ref;
-use std::cmp::PartialEq;
-use std::ops::{Add, Div, Mul, Sub};
+pub mod heap;
+#[cfg(feature = "nan_boxing")]
+mod nan_boxed;
+#[allow(dead_code)]
+pub mod objects;
+#[cfg(not(feature = "nan_boxing"))]
+mod tagged;
+use heap::{Handle, Heap};
+#[cfg(feature = "nan_boxing")]
+pub use nan_boxed::Value;
+use objects::{ObjId, ObjVal};
+use std::cmp::{Ordering, PartialEq};
+use std::fmt::{self, Display};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Sub};
+#[cfg(not(feature = "nan_boxing"))]
+pub use tagged::Value;
+/// The largest integer a value holds as one. Larger results of integer
+/// arithmetic become floats.
+pub const FIXNUM_MAX: i64 = (1 << 47) - 1;
+/// The smallest integer a value holds as one.
+pub const FIXNUM_MIN: i64 = -(1 << 47);
+
 #[derive(PartialEq)]
 pub enum ValueType {
     Bool,
     Nil,
-    // Obj,
+    Obj,
+    /// A float.
     Number,
-    String,
-}
-
-#[derive(Debug, Clone, Copy, PartialOrd)]
-/// eswm's internal value representation.
-pub enum Value {
-    Bool(bool),
-    Number(f64),
-    // Obj(Object),
-    String(*const String),
-    None,
+    Int,
 }
 
 impl Value {
-    pub fn is_type(&self, val_type: ValueType) -> bool {
-        match *self {
-            Self::Bool(_) => ValueType::Bool == val_type,
-            Self::Number(_) => ValueType::Number == val_type,
-            // Self::Obj(_) => ValueType::Obj == val_type,
-            Self::String(_) => ValueType::String == val_type,
-            Self::None => ValueType::Nil == val_type,
+    /// Whether the value counts as false in a condition: nil and false do.
+    pub fn is_falsy(&self) -> bool {
+        self.is_type(ValueType::Nil) || (self.is_type(ValueType::Bool) && !self.as_bool())
+    }
+
+    /// Whether the value is an integer or a float.
+    pub fn is_numeric(&self) -> bool {
+        self.is_type(ValueType::Number) || self.is_type(ValueType::Int)
+    }
+
+    /// Divides the numbers, rounding the quotient down to an integer.
+    /// Returns [`None`] when dividing an integer by zero.
+    pub fn floor_div(self, other: Value) -> Option<Value> {
+        if self.is_type(ValueType::Int) && other.is_type(ValueType::Int) {
+            let (a, b) = (self.as_int(), other.as_int());
+            if b == 0 {
+                return None;
+            }
+            let quotient = a / b;
+            return Some(match a % b != 0 && (a < 0) != (b < 0) {
+                true => quotient - 1,
+                false => quotient,
+            }
+            .into());
         }
+        let quotient = (self.as_number() / other.as_number()).floor();
+        Some(match quotient.is_finite() {
+            true if (FIXNUM_MIN as f64..=FIXNUM_MAX as f64).contains(&quotient) => {
+                (quotient as i64).into()
+            }
+            _ => quotient.into(),
+        })
     }
 
-    pub fn val_type(&self) -> ValueType {
-        match self {
-            Self::Bool(_) => ValueType::Bool,
-            Self::Number(_) => ValueType::Number,
-            // Self::Obj(_) => ValueType::Obj,
-            Self::String(_) => ValueType::String,
-            Self::None => ValueType::Nil,
+    /// Returns the remainder of [`Value::floor_div`], which has the sign
+    /// of `other`. Returns [`None`] when dividing an integer by zero.
+    pub fn modulo(self, other: Value) -> Option<Value> {
+        if self.is_type(ValueType::Int) && other.is_type(ValueType::Int) {
+            let (a, b) = (self.as_int(), other.as_int());
+            if b == 0 {
+                return None;
+            }
+            let remainder = a % b;
+            return Some(match remainder != 0 && (remainder < 0) != (b < 0) {
+                true => remainder + b,
+                false => remainder,
+            }
+            .into());
         }
+        let (a, b) = (self.as_number(), other.as_number());
+        Some((a - b * (a / b).floor()).into())
     }
 
-    // pub fn is_obj_type(&self, obj_type: ObjId) -> bool {
-    // 	if let Self::Obj(obj) = self {
-    // 	    obj.id == obj_type
-    // 	} else {
-    // 	    false
-    // 	}
-    // }
+    /// Shifts the bits of the integer `count` places to the left, or to
+    /// the right for a negative `count`. Returns [`None`] if bits are
+    /// shifted out of the fixnum range.
+    pub fn shift_left(self, count: i64) -> Option<Value> {
+        let value = self.as_int();
+        if count < 0 {
+            return Some((value >> count.unsigned_abs().min(63)).into());
+        }
+        let shifted = value.checked_shl(count.try_into().ok()?)?;
+        let fits = (FIXNUM_MIN..=FIXNUM_MAX).contains(&shifted) && shifted >> count == value;
+        fits.then(|| shifted.into())
+    }
 
-    pub fn _nil() -> Value {
-        Value::None
+    /// Returns the object the value refers to from `heap`.
+    pub fn obj_val<'a>(&self, heap: &'a Heap) -> &'a dyn ObjVal {
+        heap.get_dyn(self.as_obj().handle)
     }
 
-    pub fn as_bool(&self) -> bool {
-        match *self {
-            Self::Bool(val) => val,
-            _ => unreachable!(),
-        }
+    pub fn obj_type(&self) -> ObjId {
+        self.as_obj().id
     }
 
-    pub fn as_number(&self) -> f64 {
-        match *self {
-            Self::Number(val) => val,
-            _ => unreachable!(),
-        }
+    pub fn as_handle(&self) -> Handle {
+        self.as_obj().handle
     }
 
-    // pub fn as_obj(&self) -> &Object {
-    // 	match self {
-    // 	    Self::Obj(ref object) => object,
-    // 	    _ => unreachable!(),
-    // 	}
-    // }
-
-    // pub fn obj_val(&self) -> Ref<'_, (dyn objects::ObjVal + 'static)> {
-    // 	unsafe {
-    // 	    match self {
-    // 		Self::Obj(ref object) => (*object.object).borrow(),
-    // 		_ => unreachable!(),
-
-    // 	}
-    // }
-
-    // pub fn obj_type(&self) -> ObjId {
-    // 	match self {
-    // 	    Self::Obj(ref object) => object.id,
-    // 	    _ => unreachable!(),
-    // 	}
-    // }
-
-    pub fn as_rstring(&self) -> String {
-        match self {
-            Self::String(string) => unsafe { String::from(string.as_ref().unwrap()) },
-            _ => unreachable!(),
-        }
+    /// Formats the value, looking up any object it refers to on `heap`.
+    pub fn display<'a>(&self, heap: &'a Heap) -> ValueDisplay<'a> {
+        ValueDisplay { heap, value: *self, lisp: false }
     }
-}
 
-impl From<f64> for Value {
-    fn from(value: f64) -> Value {
-        Value::Number(value)
+    /// Formats the value as lisp data, where booleans read back as `t`
+    /// and `nil`.
+    pub fn display_lisp<'a>(&self, heap: &'a Heap) -> ValueDisplay<'a> {
+        ValueDisplay { heap, value: *self, lisp: true }
     }
 }
 
-impl From<bool> for Value {
-    fn from(value: bool) -> Value {
-        Value::Bool(value)
+/// Compares two values, dispatching to [`ObjVal::equals`] for objects of the same kind.
+pub fn values_equal(heap: &Heap, a: Value, b: Value) -> bool {
+    if a == b {
+        return true;
     }
+    if !a.is_type(ValueType::Obj) || !b.is_type(ValueType::Obj) {
+        return false;
+    }
+    let (a, b) = (a.as_obj(), b.as_obj());
+    a.id == b.id && heap.get_dyn(a.handle).equals(heap.get_dyn(b.handle), heap)
+}
+/// Applies `op` to two integers. Gives [`None`] if either is a float or
+/// the result overflows, leaving the operation to floats.
+fn int_op(a: Value, b: Value, op: fn(i64, i64) -> Option<i64>) -> Option<Value> {
+    if !a.is_type(ValueType::Int) || !b.is_type(ValueType::Int) {
+        return None;
+    }
+    op(a.as_int(), b.as_int()).map(Value::from)
 }
 
-// impl From<Object> for Value {
-//     fn from(value: Object) -> Value {
-// 	Value::Obj(value)
-//     }
-// }
-
-impl From<*const String> for Value {
-    fn from(value: *const String) -> Value {
-        Value::String(value)
+/// Compares numbers by value, an integer with a float included. Values
+/// of other types are ordered by type, and by identity within a type.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        if self.is_type(ValueType::Int) && other.is_type(ValueType::Int) {
+            return self.as_int().partial_cmp(&other.as_int());
+        }
+        if self.is_numeric() && other.is_numeric() {
+            return self.as_number().partial_cmp(&other.as_number());
+        }
+        match (self.val_type(), other.val_type()) {
+            (ValueType::Bool, ValueType::Bool) => self.as_bool().partial_cmp(&other.as_bool()),
+            (ValueType::Obj, ValueType::Obj) => self.as_obj().partial_cmp(&other.as_obj()),
+            (a, b) => type_rank(a).partial_cmp(&type_rank(b)),
+        }
     }
 }
 
-impl PartialEq for Value {
-    fn eq(&self, other: &Value) -> bool {
-        self.val_type() == other.val_type()
-            && match self.val_type() {
-                // ValueType::Obj => false,
-                ValueType::Nil if ValueType::Nil == other.val_type() => true,
-                ValueType::Bool if self.as_bool() == other.as_bool() => true,
-                ValueType::Number if self.as_number() == other.as_number() => true,
-                ValueType::String if self.as_rstring() == other.as_rstring() => true,
-                _ => false,
-            }
+fn type_rank(val_type: ValueType) -> u8 {
+    match val_type {
+        ValueType::Bool => 0,
+        ValueType::Number | ValueType::Int => 1,
+        ValueType::Obj => 2,
+        ValueType::Nil => 3,
     }
 }
 
@@ -158,62 +189,111 @@ impl Add for Value {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        match self {
-            Self::Number(val) => match other {
-                Self::Number(val2) => (val + val2).into(),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+        if let Some(result) = int_op(self, other, i64::checked_add) {
+            return result;
         }
+        (self.as_number() + other.as_number()).into()
     }
 }
 
 impl Sub for Value {
     type Output = Self;
     fn sub(self, other: Self) -> Self {
-        match self {
-            Self::Number(val) => match other {
-                Self::Number(val2) => (val - val2).into(),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+        if let Some(result) = int_op(self, other, i64::checked_sub) {
+            return result;
         }
+        (self.as_number() - other.as_number()).into()
     }
 }
 
 impl Div for Value {
     type Output = Self;
     fn div(self, other: Self) -> Self {
-        match self {
-            Self::Number(val) => match other {
-                Self::Number(val2) => (val / val2).into(),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
-        }
+        (self.as_number() / other.as_number()).into()
     }
 }
 
 impl Mul for Value {
     type Output = Self;
     fn mul(self, other: Self) -> Self {
-        match self {
-            Self::Number(val) => match other {
-                Self::Number(val2) => (val * val2).into(),
-                _ => unreachable!(),
+        if let Some(result) = int_op(self, other, i64::checked_mul) {
+            return result;
+        }
+        (self.as_number() * other.as_number()).into()
+    }
+}
+
+impl Neg for Value {
+    type Output = Self;
+    fn neg(self) -> Self {
+        match self.is_type(ValueType::Int) {
+            true => Value::from(-self.as_int()),
+            false => (-self.as_number()).into(),
+        }
+    }
+}
+
+// The bitwise operators take integers. Fixnums are sign extended, so the
+// results of these stay in range.
+
+impl BitAnd for Value {
+    type Output = Self;
+    fn bitand(self, other: Self) -> Self {
+        (self.as_int() & other.as_int()).into()
+    }
+}
+
+impl BitOr for Value {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        (self.as_int() | other.as_int()).into()
+    }
+}
+
+impl BitXor for Value {
+    type Output = Self;
+    fn bitxor(self, other: Self) -> Self {
+        (self.as_int() ^ other.as_int()).into()
+    }
+}
+
+impl Not for Value {
+    type Output = Self;
+    fn not(self) -> Self {
+        (!self.as_int()).into()
+    }
+}
+
+/// Displays a [`Value`] along with the object it refers to.
+pub struct ValueDisplay<'a> {
+    heap: &'a Heap,
+    value: Value,
+    lisp: bool,
+}
+
+impl Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value.val_type() {
+            ValueType::Nil => write!(f, "nil"),
+            ValueType::Bool if self.lisp => write!(f, "{}", if self.value.as_bool() { "t" } else { "nil" }),
+            ValueType::Bool => write!(f, "{}", self.value.as_bool()),
+            // Floats keep their fraction so they can't pass for integers.
+            ValueType::Number => match self.value.as_number() {
+                number if number.is_finite() && number.fract() == 0.0 => write!(f, "{:.1}", number),
+                number => write!(f, "{}", number),
             },
-            _ => unreachable!(),
+            ValueType::Int => write!(f, "{}", self.value.as_int()),
+            ValueType::Obj => self.value.obj_val(self.heap).fmt(self.heap, f),
         }
     }
 }
 
 /// Prints [`Value`] to stdout.
-pub fn print_value(value: Value) {
-    match value {
-        Value::None => print!("nil"),
-        Value::Bool(_) => print!("{}", value.as_bool()),
-        Value::Number(_) => print!("{}", value.as_number()),
-        Value::String(_) => print!("{}", value.as_rstring()),
-        // Value::Obj(_) => print_object(value.as_obj()),
-    }
+pub fn print_value(heap: &Heap, value: Value) {
+    print!("{}", value.display(heap));
+}
+
+/// Prints [`Value`] to stdout as lisp data.
+pub fn print_lisp_value(heap: &Heap, value: Value) {
+    print!("{}", value.display_lisp(heap));
 }
@@ -0,0 +1,313 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! A [`Value`] packed into the 8 bytes of an `f64`, used with the
+`nan_boxing` feature.
+
+Numbers are stored as themselves. Every other value is hidden in the
+payload of a quiet NaN, a bit pattern no arithmetic produces:
+
+| bits             | value                                    |
+|------------------+------------------------------------------|
+| `0x7ffc...0001`  | nil                                      |
+| `0x7ffc...0002`  | false                                    |
+| `0x7ffc...0003`  | true                                     |
+| `0x7ffd...`      | an integer, in the low 48 bits           |
+| `0xfffc...`      | an object, its [`ObjId`] in bits 32 to 47 |
+|                  | and its [`Handle`] in the low 32 bits    |
+
+A NaN made by arithmetic is stored as the one NaN Rust gives, so it
+can't be mistaken for anything else. Integers are kept to 48 bits so
+they fit, which is why [`FIXNUM_MAX`] is what it is for both
+representations.
+ */
+use super::heap::Handle;
+use super::objects::{ObjId, Object};
+use super::{ValueType, FIXNUM_MAX, FIXNUM_MIN};
+use std::fmt;
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+/// The bit set in every integer, next to the quiet NaN bits.
+const INT: u64 = 1 << 48;
+const INT_MASK: u64 = INT - 1;
+/// The bits set in every object.
+const OBJ: u64 = SIGN_BIT | QNAN;
+/// The object kind takes the 16 bits between the handle and the tag.
+const ID_SHIFT: u64 = 32;
+const ID_MASK: u64 = 0xffff << ID_SHIFT;
+
+#[derive(Clone, Copy)]
+/// eswm's internal value representation.
+pub struct Value(u64);
+
+impl Value {
+    /// nil, named after the variant of the enum [`Value`] has without
+    /// the `nan_boxing` feature.
+    #[allow(non_upper_case_globals)]
+    pub const None: Value = Value(QNAN | TAG_NIL);
+
+    const FALSE: Value = Value(QNAN | TAG_FALSE);
+    const TRUE: Value = Value(QNAN | TAG_TRUE);
+
+    fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    fn is_obj(&self) -> bool {
+        self.0 & OBJ == OBJ
+    }
+
+    fn is_int(&self) -> bool {
+        self.0 & (OBJ | INT) == QNAN | INT
+    }
+
+    fn is_bool(&self) -> bool {
+        // false and true differ only in the lowest bit.
+        self.0 | 1 == Value::TRUE.0
+    }
+
+    pub fn is_type(&self, val_type: ValueType) -> bool {
+        match val_type {
+            ValueType::Bool => self.is_bool(),
+            ValueType::Number => self.is_number(),
+            ValueType::Obj => self.is_obj(),
+            ValueType::Nil => self.0 == Value::None.0,
+            ValueType::Int => self.is_int(),
+        }
+    }
+
+    pub fn val_type(&self) -> ValueType {
+        if self.is_number() {
+            ValueType::Number
+        } else if self.is_int() {
+            ValueType::Int
+        } else if self.is_obj() {
+            ValueType::Obj
+        } else if self.is_bool() {
+            ValueType::Bool
+        } else {
+            ValueType::Nil
+        }
+    }
+
+    pub fn is_obj_type(&self, obj_type: ObjId) -> bool {
+        self.0 & (OBJ | ID_MASK) == OBJ | (obj_type as u64) << ID_SHIFT
+    }
+
+    pub fn _nil() -> Value {
+        Value::None
+    }
+
+    pub fn as_bool(&self) -> bool {
+        debug_assert!(self.is_bool());
+        self.0 == Value::TRUE.0
+    }
+
+    /// Returns the number as a float, converting an integer.
+    pub fn as_number(&self) -> f64 {
+        match self.is_int() {
+            true => self.as_int() as f64,
+            false => {
+                debug_assert!(self.is_number());
+                f64::from_bits(self.0)
+            }
+        }
+    }
+
+    pub fn as_int(&self) -> i64 {
+        debug_assert!(self.is_int());
+        // Shifting the 48 bits to the top and back extends their sign.
+        ((self.0 << 16) as i64) >> 16
+    }
+
+    pub fn as_obj(&self) -> Object {
+        debug_assert!(self.is_obj());
+        let id = match (self.0 & ID_MASK) >> ID_SHIFT {
+            0 => ObjId::String,
+            1 => ObjId::Function,
+            2 => ObjId::Closure,
+            3 => ObjId::Upvalue,
+            4 => ObjId::Native,
+            5 => ObjId::Cons,
+            6 => ObjId::Symbol,
+            _ => unreachable!(),
+        };
+        Object::new(id, Handle::new(self.0 as u32))
+    }
+
+    /// Returns the heap handle of the value, if it lives on the heap.
+    pub fn handle(&self) -> Option<Handle> {
+        match self.is_obj() {
+            true => Some(Handle::new(self.0 as u32)),
+            false => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        match value.is_nan() {
+            true => Value(f64::NAN.to_bits()),
+            false => Value(value.to_bits()),
+        }
+    }
+}
+
+/// Integers outside the fixnum range become floats.
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        match (FIXNUM_MIN..=FIXNUM_MAX).contains(&value) {
+            true => Value(QNAN | INT | (value as u64 & INT_MASK)),
+            false => Value((value as f64).to_bits()),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        match value {
+            true => Value::TRUE,
+            false => Value::FALSE,
+        }
+    }
+}
+
+impl From<Object> for Value {
+    fn from(value: Object) -> Value {
+        Value(OBJ | (value.id as u64) << ID_SHIFT | value.handle.index() as u64)
+    }
+}
+
+/// Compares values by identity, two objects are only equal if they are
+/// the same object. Use `values_equal` to compare the contents of
+/// objects. An integer equals the float of the same value.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        let numeric = |value: &Value| value.is_number() || value.is_int();
+        if !numeric(self) || !numeric(other) {
+            return self.0 == other.0;
+        }
+        match self.is_int() && other.is_int() {
+            true => self.0 == other.0,
+            false => self.as_number() == other.as_number(),
+        }
+    }
+}
+
+/// Formats the value like the enum [`Value`] does.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.val_type() {
+            ValueType::Bool => f.debug_tuple("Bool").field(&self.as_bool()).finish(),
+            ValueType::Number => f.debug_tuple("Number").field(&self.as_number()).finish(),
+            ValueType::Obj => f.debug_tuple("Obj").field(&self.as_obj()).finish(),
+            ValueType::Int => f.debug_tuple("Int").field(&self.as_int()).finish(),
+            ValueType::Nil => write!(f, "None"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_fit_in_a_word() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        for int in [0, 1, -1, 42, FIXNUM_MAX, FIXNUM_MIN] {
+            let value = Value::from(int);
+            assert!(matches!(value.val_type(), ValueType::Int));
+            assert_eq!(value.as_int(), int);
+        }
+    }
+
+    #[test]
+    fn integers_outside_a_fixnum_become_floats() {
+        let value = Value::from(FIXNUM_MAX + 1);
+        assert!(matches!(value.val_type(), ValueType::Number));
+        assert_eq!(value.as_number(), (FIXNUM_MAX + 1) as f64);
+    }
+
+    #[test]
+    fn floats_round_trip() {
+        let floats = [0.0, -0.0, 1.5, -2.25, f64::MAX, f64::MIN_POSITIVE, f64::INFINITY, f64::NEG_INFINITY];
+        for float in floats {
+            let value = Value::from(float);
+            assert!(matches!(value.val_type(), ValueType::Number));
+            assert_eq!(value.as_number().to_bits(), float.to_bits());
+        }
+    }
+
+    #[test]
+    fn nan_stays_a_number() {
+        // A NaN with a payload mustn't be read back as a boxed value.
+        let payload = f64::from_bits(QNAN | TAG_TRUE);
+        for nan in [f64::NAN, -f64::NAN, payload] {
+            let value = Value::from(nan);
+            assert!(matches!(value.val_type(), ValueType::Number));
+            assert!(value.as_number().is_nan());
+        }
+    }
+
+    #[test]
+    fn bools_and_nil_round_trip() {
+        assert!(matches!(Value::from(true).val_type(), ValueType::Bool));
+        assert!(Value::from(true).as_bool());
+        assert!(matches!(Value::from(false).val_type(), ValueType::Bool));
+        assert!(!Value::from(false).as_bool());
+        assert!(matches!(Value::None.val_type(), ValueType::Nil));
+        assert!(Value::None.is_type(ValueType::Nil));
+        assert!(!Value::from(false).is_type(ValueType::Nil));
+    }
+
+    #[test]
+    fn objects_round_trip() {
+        let ids = [
+            ObjId::String,
+            ObjId::Function,
+            ObjId::Closure,
+            ObjId::Upvalue,
+            ObjId::Native,
+            ObjId::Cons,
+            ObjId::Symbol,
+        ];
+        for id in ids {
+            for index in [0, 1, 0xdead_beef, u32::MAX] {
+                let value = Value::from(Object::new(id, Handle::new(index)));
+                assert!(matches!(value.val_type(), ValueType::Obj));
+                assert!(value.is_obj_type(id));
+                assert_eq!(value.as_obj(), Object::new(id, Handle::new(index)));
+                assert_eq!(value.handle(), Some(Handle::new(index)));
+            }
+        }
+    }
+
+    #[test]
+    fn object_kind_lives_in_bits_32_to_47() {
+        let value = Value::from(Object::new(ObjId::Symbol, Handle::new(u32::MAX)));
+        assert_eq!((value.0 >> ID_SHIFT) & 0xffff, ObjId::Symbol as u64);
+        assert_eq!(value.0 as u32, u32::MAX);
+        assert!(!value.is_obj_type(ObjId::String));
+    }
+}
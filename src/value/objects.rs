@@ -18,51 +18,413 @@
 
 this Module holds eswm's heap allocated objects.
  */
-use std::rc::Rc;
-use std::cell::RefCell;
-/// The heap allocated object
-pub type ObjPtr = Rc<RefCell<dyn ObjVal>>;
+use super::heap::{Handle, Heap, Tracer};
+use super::{Value, ValueType};
+use crate::chunk::Chunk;
+use crate::vm::{RuntimeError, Vm};
+use std::any::Any;
+use std::fmt;
+use std::mem::size_of;
+use std::ops::{RangeFrom, RangeInclusive};
 
+/// The kind of an eswm object.
 #[derive(Debug ,Clone, Copy, PartialOrd, PartialEq)]
 pub enum ObjId {
-
+    String,
+    Function,
+    Closure,
+    Upvalue,
+    Native,
+    Cons,
+    Symbol,
 }
 
+/// A reference to an object on the [`Heap`] tagged with its kind.
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
 pub struct Object {
     pub id: ObjId,
-    /// A pointer to the [`Rc`] of the object
-    pub object: *const ObjPtr,
+    pub handle: Handle,
 }
 
 impl Object {
-    pub fn new(id: ObjId, object: *const ObjPtr ) -> Object {
+    pub fn new(id: ObjId, handle: Handle) -> Object {
 	Object {
 	    id,
-	    object,
+	    handle,
 	}
     }
 }
 
-/// A link list that holds the master [`Rc`] for each eswm object.
-pub struct ObjList {
-    pub value: ObjPtr,
-    pub next: Option<Box<ObjList>>,
+/// Lets an [`ObjVal`] be downcast to its concrete type.
+pub trait AsAny: Any {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+	self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+	self
+    }
+}
 
 /// Defines Object behavior.
-pub trait ObjVal {
-    fn placeholder_fn();
+///
+/// A new kind of object needs an [`ObjId`] and an implementation of
+/// this trait, the heap and the vm handle the rest.
+pub trait ObjVal: AsAny {
+    fn id(&self) -> ObjId;
+    /// Marks every object this one refers to.
+    fn trace(&self, tracer: &mut Tracer);
+    /// The number of bytes the object accounts for on the heap.
+    fn size(&self) -> usize;
+    /// Formats the object the way `print` shows it.
+    fn fmt(&self, heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+    /// Compares the object with `other`, which is always of the same kind.
+    /// Objects are only equal to themselves unless this is overridden.
+    fn equals(&self, _other: &dyn ObjVal, _heap: &Heap) -> bool {
+	false
+    }
+}
+
+/// An interned eswm string.
+pub struct ObjString(pub String);
+
+impl ObjVal for ObjString {
+    fn id(&self) -> ObjId {
+	ObjId::String
+    }
+
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn size(&self) -> usize {
+	size_of::<Self>() + self.0.capacity()
+    }
+
+    fn fmt(&self, _heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+/// An interned eswm symbol.
+///
+/// Symbols are interned apart from strings, so two symbols with the
+/// same name are the same object and compare by handle alone.
+pub struct ObjSymbol(pub String);
+
+impl ObjSymbol {
+    /// Whether the symbol is a `:keyword`, which evaluates to itself.
+    pub fn is_keyword(&self) -> bool {
+	is_keyword(&self.0)
+    }
+}
+
+/// Whether `name` names a `:keyword`.
+pub fn is_keyword(name: &str) -> bool {
+    name.len() > 1 && name.starts_with(':')
+}
+
+impl ObjVal for ObjSymbol {
+    fn id(&self) -> ObjId {
+	ObjId::Symbol
+    }
+
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn size(&self) -> usize {
+	size_of::<Self>() + self.0.capacity()
+    }
+
+    fn fmt(&self, _heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+/// A compiled eswm function.
+pub struct ObjFunction {
+    /// The number of parameters the function expects.
+    pub arity: usize,
+    /// The number of variables the function captures from its enclosing scopes.
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// The name of the function, [`None`] for the top level script.
+    pub name: Option<String>,
+}
+
+impl Default for ObjFunction {
+    fn default() -> ObjFunction {
+	ObjFunction::new()
+    }
+}
+
+impl ObjFunction {
+    pub fn new() -> ObjFunction {
+	ObjFunction {
+	    arity: 0,
+	    upvalue_count: 0,
+	    chunk: Chunk::new(),
+	    name: None,
+	}
+    }
 }
 
-pub fn print_object(object: &Object) {
-    // unsafe {
-    // 	match object.id {
+impl ObjVal for ObjFunction {
+    fn id(&self) -> ObjId {
+	ObjId::Function
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+	for constant in self.chunk.constants.iter() {
+	    tracer.mark_value(*constant);
+	}
+    }
 
-    // 	}
-    // }	
+    fn size(&self) -> usize {
+	size_of::<Self>()
+	    + self.chunk.code.capacity()
+	    + self.chunk.lines_size()
+	    + self.chunk.constants.capacity() * size_of::<Value>()
+    }
+
+    fn fmt(&self, _heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self.name {
+	    Some(ref name) => write!(f, "<fn {}>", name),
+	    None => write!(f, "<script>"),
+	}
+    }
 }
 
+/// A variable captured by a closure.
+pub enum ObjUpvalue {
+    /// The variable is still on the stack, in this slot.
+    Open(usize),
+    /// The variable has left the stack and now lives in the upvalue.
+    Closed(Value),
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> ObjUpvalue {
+	ObjUpvalue::Open(slot)
+    }
 
+    /// The stack slot of the captured variable, if it is still open.
+    pub fn slot(&self) -> Option<usize> {
+	match self {
+	    ObjUpvalue::Open(slot) => Some(*slot),
+	    ObjUpvalue::Closed(_) => None,
+	}
+    }
+
+    /// Moves the captured variable, currently holding `value`, off of
+    /// the stack and into the upvalue.
+    pub fn close(&mut self, value: Value) {
+	*self = ObjUpvalue::Closed(value);
+    }
+}
+
+impl ObjVal for ObjUpvalue {
+    fn id(&self) -> ObjId {
+	ObjId::Upvalue
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+	if let ObjUpvalue::Closed(value) = self {
+	    tracer.mark_value(*value);
+	}
+    }
+
+    fn size(&self) -> usize {
+	size_of::<Self>()
+    }
+
+    fn fmt(&self, _heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "upvalue")
+    }
+}
+
+/// A [`ObjFunction`] along with the variables it captures.
+pub struct ObjClosure {
+    pub function: Handle,
+    pub upvalues: Vec<Handle>,
+}
 
+impl ObjClosure {
+    pub fn new(function: Handle, upvalues: Vec<Handle>) -> ObjClosure {
+	ObjClosure {
+	    function,
+	    upvalues,
+	}
+    }
+}
+
+impl ObjVal for ObjClosure {
+    fn id(&self) -> ObjId {
+	ObjId::Closure
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+	tracer.mark_object(self.function);
+	for upvalue in self.upvalues.iter() {
+	    tracer.mark_object(*upvalue);
+	}
+    }
+
+    fn size(&self) -> usize {
+	size_of::<Self>() + self.upvalues.capacity() * size_of::<Handle>()
+    }
+
+    fn fmt(&self, heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	heap.get::<ObjFunction>(self.function).fmt(heap, f)
+    }
+}
+
+/// A function implemented in rust.
+///
+/// The arguments have already been checked against the [`Arity`] the
+/// function was defined with.
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError>;
+
+/// The number of arguments a native function accepts.
+///
+/// A plain number is an exact count, `1..=2` a range and `1..` a lower
+/// bound for functions taking any number of arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arity {
+    pub min: usize,
+    /// [`None`] if there is no upper bound.
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+	count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(count: usize) -> Arity {
+	Arity {
+	    min: count,
+	    max: Some(count),
+	}
+    }
+}
+
+impl From<RangeInclusive<usize>> for Arity {
+    fn from(range: RangeInclusive<usize>) -> Arity {
+	Arity {
+	    min: *range.start(),
+	    max: Some(*range.end()),
+	}
+    }
+}
+
+impl From<RangeFrom<usize>> for Arity {
+    fn from(range: RangeFrom<usize>) -> Arity {
+	Arity {
+	    min: range.start,
+	    max: None,
+	}
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self.max {
+	    Some(max) if max == self.min => write!(f, "{}", max),
+	    Some(max) => write!(f, "{} to {}", self.min, max),
+	    None => write!(f, "at least {}", self.min),
+	}
+    }
+}
+
+/// A rust function callable from eswm.
+pub struct ObjNative {
+    pub name: String,
+    pub arity: Arity,
+    pub function: NativeFn,
+}
+
+impl ObjVal for ObjNative {
+    fn id(&self) -> ObjId {
+	ObjId::Native
+    }
+
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn size(&self) -> usize {
+	size_of::<Self>() + self.name.capacity()
+    }
+
+    fn fmt(&self, _heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "<native fn {}>", self.name)
+    }
+}
+
+/// A cons cell, the building block of eswm lists.
+///
+/// A list is either `nil` or a cons whose `cdr` is a list.
+pub struct ObjCons {
+    pub car: Value,
+    pub cdr: Value,
+}
+
+impl ObjVal for ObjCons {
+    fn id(&self) -> ObjId {
+	ObjId::Cons
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+	tracer.mark_value(self.car);
+	tracer.mark_value(self.cdr);
+    }
+
+    fn size(&self) -> usize {
+	size_of::<Self>()
+    }
+
+    fn fmt(&self, heap: &Heap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "({}", self.car.display_lisp(heap))?;
+	let mut tail = self.cdr;
+	while tail.is_obj_type(ObjId::Cons) {
+	    let cons = heap.get::<ObjCons>(tail.as_handle());
+	    write!(f, " {}", cons.car.display_lisp(heap))?;
+	    tail = cons.cdr;
+	}
+	if !tail.is_type(ValueType::Nil) {
+	    write!(f, " . {}", tail.display_lisp(heap))?;
+	}
+	write!(f, ")")
+    }
+
+    fn equals(&self, other: &dyn ObjVal, heap: &Heap) -> bool {
+	let other = other.as_any().downcast_ref::<ObjCons>().unwrap();
+	if !super::values_equal(heap, self.car, other.car) {
+	    return false;
+	}
+
+	// Walk the rest of the lists in a loop rather than recursing on every cdr.
+	let (mut a, mut b) = (self.cdr, other.cdr);
+	while a.is_obj_type(ObjId::Cons) && b.is_obj_type(ObjId::Cons) {
+	    if a == b {
+		return true;
+	    }
+	    let a_cons = heap.get::<ObjCons>(a.as_handle());
+	    let b_cons = heap.get::<ObjCons>(b.as_handle());
+	    if !super::values_equal(heap, a_cons.car, b_cons.car) {
+		return false;
+	    }
+	    a = a_cons.cdr;
+	    b = b_cons.cdr;
+	}
+	super::values_equal(heap, a, b)
+    }
+}
+
+/// Prints the object to stdout.
+pub fn print_object(heap: &Heap, object: &Object) {
+    print!("{}", Value::from(*object).display(heap));
+}
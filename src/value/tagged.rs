@@ -0,0 +1,155 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/*! The default representation of a [`Value`]: an enum with a variant
+for each type, 16 bytes in size.
+ */
+use super::heap::Handle;
+use super::objects::{ObjId, Object};
+use super::{ValueType, FIXNUM_MAX, FIXNUM_MIN};
+
+#[derive(Debug, Clone, Copy)]
+/// eswm's internal value representation.
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    /// An integer between `FIXNUM_MIN` and `FIXNUM_MAX`.
+    Int(i64),
+    Obj(Object),
+    None,
+}
+
+impl Value {
+    pub fn is_type(&self, val_type: ValueType) -> bool {
+        match *self {
+            Self::Bool(_) => ValueType::Bool == val_type,
+            Self::Number(_) => ValueType::Number == val_type,
+            Self::Int(_) => ValueType::Int == val_type,
+            Self::Obj(_) => ValueType::Obj == val_type,
+            Self::None => ValueType::Nil == val_type,
+        }
+    }
+
+    pub fn val_type(&self) -> ValueType {
+        match self {
+            Self::Bool(_) => ValueType::Bool,
+            Self::Number(_) => ValueType::Number,
+            Self::Int(_) => ValueType::Int,
+            Self::Obj(_) => ValueType::Obj,
+            Self::None => ValueType::Nil,
+        }
+    }
+
+    pub fn is_obj_type(&self, obj_type: ObjId) -> bool {
+        if let Self::Obj(obj) = self {
+            obj.id == obj_type
+        } else {
+            false
+        }
+    }
+
+    pub fn _nil() -> Value {
+        Value::None
+    }
+
+    pub fn as_bool(&self) -> bool {
+        match *self {
+            Self::Bool(val) => val,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the number as a float, converting an integer.
+    pub fn as_number(&self) -> f64 {
+        match *self {
+            Self::Number(val) => val,
+            Self::Int(val) => val as f64,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_int(&self) -> i64 {
+        match *self {
+            Self::Int(val) => val,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_obj(&self) -> Object {
+        match *self {
+            Self::Obj(object) => object,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the heap handle of the value, if it lives on the heap.
+    pub fn handle(&self) -> Option<Handle> {
+        match *self {
+            Self::Obj(object) => Some(object.handle),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Number(value)
+    }
+}
+
+/// Integers outside the fixnum range become floats.
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        match (FIXNUM_MIN..=FIXNUM_MAX).contains(&value) {
+            true => Value::Int(value),
+            false => Value::Number(value as f64),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<Object> for Value {
+    fn from(value: Object) -> Value {
+        Value::Obj(value)
+    }
+}
+
+/// Compares values by identity, two objects are only equal if they are
+/// the same object. Use `values_equal` to compare the contents of
+/// objects. An integer equals the float of the same value.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        if let (Self::Int(a), Self::Int(b)) = (self, other) {
+            return a == b;
+        }
+        if self.is_numeric() && other.is_numeric() {
+            return self.as_number() == other.as_number();
+        }
+        self.val_type() == other.val_type()
+            && match self.val_type() {
+                ValueType::Nil if ValueType::Nil == other.val_type() => true,
+                ValueType::Bool if self.as_bool() == other.as_bool() => true,
+                ValueType::Number if self.as_number() == other.as_number() => true,
+                ValueType::Obj => self.as_obj() == other.as_obj(),
+                _ => false,
+            }
+    }
+}
@@ -14,297 +14,1195 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::compiler::compile;
-use crate::lib::chunk::{Chunk, OpCode};
-use crate::lib::debug::disassemble_instruction;
-use crate::value::{print_value, Value, ValueType}; //objects::{ObjList, ObjString, Object, ObjPtr, ObjId}};
+use crate::cache;
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler::{compile, compile_repl, Macro, OptLevel, Syntax};
+#[cfg(feature = "debug_trace_execution")]
+use crate::debug::disassemble_instruction;
+use crate::error::{Diagnostic, ErrorKind, Span, TraceFrame};
+use crate::globals::Globals;
+use crate::natives;
+use crate::value::{
+    heap::{Handle, Heap, GC_HEAP_GROW_FACTOR, GC_INITIAL_THRESHOLD},
+    objects::{
+        Arity, NativeFn, ObjClosure, ObjCons, ObjFunction, ObjId, ObjNative, ObjUpvalue, ObjVal,
+        Object,
+    },
+    print_lisp_value, print_value, values_equal, Value, ValueDisplay, ValueType,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
 use std::result::Result;
-// use std::rc::Rc;
-// use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * u8::MAX as usize;
 
 enum BinaryOp {
     Add,
     Sub,
     Div,
     Mul,
+    IntDiv,
+    Mod,
     Less,
     Greater,
 }
 
-#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum VmErr {
-    CompileError,
-    RuntimeError,
+    /// Every error found while compiling, in the order they were found.
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(Box<Diagnostic>),
 }
 
+impl VmErr {
+    /// The errors that caused this one.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            VmErr::CompileError(errors) => errors,
+            VmErr::RuntimeError(error) => std::slice::from_ref(&**error),
+        }
+    }
+
+    /// Whether the source failed to compile only because it ended too
+    /// early, so the repl should ask for more of it.
+    pub fn is_incomplete(&self) -> bool {
+        match self {
+            VmErr::CompileError(errors) => errors.iter().all(|error| error.incomplete),
+            VmErr::RuntimeError(_) => false,
+        }
+    }
+}
+
+impl VmErr {
+    /// Formats every diagnostic along with the source lines they point
+    /// at, see [`Diagnostic::render`].
+    pub fn render(&self, color: bool) -> String {
+        let rendered: Vec<String> = self
+            .diagnostics()
+            .iter()
+            .map(|error| error.render(color))
+            .collect();
+        rendered.join("\n")
+    }
+}
+
+impl Display for VmErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, error) in self.diagnostics().iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for VmErr {}
+
 pub type InterpretResult<T> = Result<T, VmErr>;
 
+/// An error raised by a native function.
+#[derive(Debug)]
+pub enum RuntimeError {
+    /// The vm reports the message along with a stack trace.
+    Message(String),
+    /// Like `Message`, but raised with the condition symbol given
+    /// rather than `error`, for `condition-case` to match.
+    Condition(&'static str, String),
+    /// The vm already raised the error, as when a script called back
+    /// into by the native failed.
+    Raised(VmErr),
+}
+
+impl From<String> for RuntimeError {
+    fn from(message: String) -> RuntimeError {
+        RuntimeError::Message(message)
+    }
+}
+
+impl From<&str> for RuntimeError {
+    fn from(message: &str) -> RuntimeError {
+        RuntimeError::Message(message.to_string())
+    }
+}
+
+impl From<VmErr> for RuntimeError {
+    fn from(error: VmErr) -> RuntimeError {
+        RuntimeError::Raised(error)
+    }
+}
+
+/// A single ongoing function call.
+#[derive(Clone, Copy)]
+pub(crate) struct CallFrame {
+    pub closure: Handle,
+    /// The function of `closure`, cached to save a lookup on every instruction.
+    pub function: Handle,
+    /// Offset of the next instruction in the function's chunk.
+    pub ip: usize,
+    /// The first slot in the vm's stack the function can use.
+    pub slots: usize,
+}
+
+/// Where to go should an error be raised, installed by `condition-case`.
+#[derive(Clone, Copy)]
+pub(crate) struct Handler {
+    /// The closure to call with the error object.
+    pub closure: Handle,
+    /// The list of error symbols the handler catches, or true if it
+    /// catches every error.
+    pub conditions: Value,
+    /// The number of calls in progress when the handler was installed.
+    pub frames: usize,
+    /// The height of the stack when the handler was installed.
+    pub stack: usize,
+    /// Offset of the code calling `closure` in the installing function's chunk.
+    pub ip: usize,
+}
+
+/// How big a [`Vm`] may grow and how often it collects garbage.
+///
+/// ```
+/// use eswm::{Vm, VmConfig};
+///
+/// let vm = Vm::with_config(VmConfig {
+///     frames_max: 16,
+///     ..VmConfig::default()
+/// });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmConfig {
+    /// The most values the stack may hold at once. Going past it is a
+    /// runtime error.
+    pub stack_max: usize,
+    /// The most calls that may be in progress at once, counting the
+    /// top level. Going past it is a runtime error.
+    pub frames_max: usize,
+    /// The number of bytes allocated before garbage is first collected.
+    pub gc_threshold: usize,
+    /// How many times over the heap may grow before it is collected again.
+    pub gc_grow_factor: usize,
+    /// Whether to collect garbage on every allocation, which is slow
+    /// but shakes out objects that are used without being reachable.
+    pub stress_gc: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            stack_max: STACK_MAX,
+            frames_max: FRAMES_MAX,
+            gc_threshold: GC_INITIAL_THRESHOLD,
+            gc_grow_factor: GC_HEAP_GROW_FACTOR,
+            stress_gc: cfg!(feature = "debug_stress_gc"),
+        }
+    }
+}
+
+/// The globals and macros a compile started with, to go back to if
+/// it fails.
+pub(crate) struct Checkpoint {
+    slots: usize,
+    macros: HashMap<String, Macro>,
+}
+
 pub struct Vm {
-    pub chunk: Option<Chunk>,
-    pub ip: *const u8,
-    pub stack: Vec<Value>,
-    pub stack_top: *mut Value,
-    pub globals: HashMap<String, Value>,
-    pub strings: HashSet<String>,
-    // pub objects: Option<Box<ObjList>>,
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) stack: Vec<Value>,
+    pub(crate) globals: Globals,
+    pub(crate) heap: Heap,
+    /// Upvalues still pointing into the stack, sorted by stack slot.
+    pub(crate) open_upvalues: Vec<Handle>,
+    /// Constants of the functions currently being compiled.
+    pub(crate) compiler_roots: Vec<Value>,
+    /// The most values the stack may hold at once.
+    pub(crate) stack_max: usize,
+    /// The most calls that may be in progress at once.
+    pub(crate) frames_max: usize,
+    /// The macros defined so far, by name.
+    pub(crate) macros: HashMap<String, Macro>,
+    /// The number of symbols made by `gensym`.
+    pub(crate) gensym_counter: usize,
+    /// The error handlers installed, innermost last.
+    pub(crate) handlers: Vec<Handler>,
+    /// The error object of the error being raised, if the script
+    /// signalled one rather than the vm.
+    pub(crate) raised: Option<Value>,
+    /// The condition symbol of the error being raised, if the vm
+    /// raised it itself.
+    pub(crate) condition: &'static str,
+    /// How much the compiler optimizes the code it compiles for the vm.
+    pub(crate) opt_level: OptLevel,
 }
 
-// pub fn allocate_obj(vm: &mut Vm, object: ObjPtr, id: ObjId) -> Object {
-//     let list = vm.objects.take();
-//     let new_list = Box::new(ObjList {
-// 	value: object,
-// 	next: list,
-//     });
-//     let object: *const ObjPtr = &new_list.value;
-//     let _ = vm.objects.insert(new_list);
-//     Object::new(id, object)
-
-// }
-
-pub fn allocate_string(vm: &mut Vm, to_allocate: String) -> *const String {
-    let key = to_allocate.clone();
-    vm.strings.insert(to_allocate);
-    vm.strings.get(&key).unwrap()
+/// Returns the interned string equal to `to_allocate`, allocating it if needed.
+pub(crate) fn allocate_string(vm: &mut Vm, to_allocate: String) -> Value {
+    if let Some(string) = vm.heap.find_string(&to_allocate) {
+        return string.into();
+    }
+    vm.collect_if_needed();
+    vm.heap.insert_string(to_allocate).into()
 }
 
-fn generate_stack() -> Vec<Value> {
-    let mut vector = Vec::new();
-    vector.resize(STACK_MAX, Value::None);
-    vector
+/// Returns the symbol named `name`, interning it if it is new.
+pub(crate) fn allocate_symbol(vm: &mut Vm, name: String) -> Value {
+    if let Some(symbol) = vm.heap.find_symbol(&name) {
+        return symbol.into();
+    }
+    vm.collect_if_needed();
+    vm.heap.insert_symbol(name).into()
 }
 
-fn is_falsy(value: Value) -> bool {
-    value.is_type(ValueType::Nil) || (value.is_type(ValueType::Bool) && !value.as_bool())
+pub(crate) fn allocate_function(vm: &mut Vm, function: ObjFunction) -> Value {
+    vm.allocate(function).into()
+}
+
+pub(crate) fn allocate_closure(vm: &mut Vm, closure: ObjClosure) -> Value {
+    vm.allocate(closure).into()
+}
+
+pub(crate) fn allocate_upvalue(vm: &mut Vm, upvalue: ObjUpvalue) -> Handle {
+    vm.allocate(upvalue).handle
+}
+
+pub(crate) fn allocate_native(vm: &mut Vm, native: ObjNative) -> Value {
+    vm.allocate(native).into()
+}
+
+pub(crate) fn allocate_cons(vm: &mut Vm, car: Value, cdr: Value) -> Value {
+    vm.allocate(ObjCons { car, cdr }).into()
+}
+
+/// The condition symbols of the errors the vm raises itself. Their
+/// error objects hold the message the vm reports them with.
+const CONDITIONS: [&str; 7] = [
+    "error",
+    "arith-error",
+    "wrong-type-argument",
+    "args-out-of-range",
+    "void-variable",
+    "wrong-number-of-arguments",
+    "invalid-function",
+];
+
+/// Returns the message shown for the error object `error`: the
+/// message given to `error` or raised by the vm, or the error symbol
+/// followed by its data.
+pub(crate) fn error_message(heap: &Heap, error: Value) -> String {
+    if !error.is_obj_type(ObjId::Cons) {
+        return error.display_lisp(heap).to_string();
+    }
+    let cons = heap.get::<ObjCons>(error.as_handle());
+    let (symbol, mut data) = (cons.car, cons.cdr);
+    if data.is_obj_type(ObjId::Cons) && symbol.is_obj_type(ObjId::Symbol) {
+        let first = heap.get::<ObjCons>(data.as_handle()).car;
+        if CONDITIONS.contains(&heap.symbol(symbol.as_handle())) && first.is_obj_type(ObjId::String)
+        {
+            return heap.string(first.as_handle()).to_string();
+        }
+    }
+
+    let mut message = symbol.display(heap).to_string();
+    let mut separator = ": ";
+    while data.is_obj_type(ObjId::Cons) {
+        let cons = heap.get::<ObjCons>(data.as_handle());
+        message.push_str(separator);
+        message.push_str(&cons.car.display_lisp(heap).to_string());
+        separator = ", ";
+        data = cons.cdr;
+    }
+    if !data.is_type(ValueType::Nil) {
+        message.push_str(separator);
+        message.push_str(&data.display_lisp(heap).to_string());
+    }
+    message
 }
 
 /// concatenates the two values on the stack into a new value
-fn concatenate(vm: &mut Vm) {
-    let b = String::from(vm.pop().as_rstring());
-    let a = String::from(vm.pop().as_rstring());
-    let c = format!("{}{}", a, b);
+fn concatenate(vm: &mut Vm) -> InterpretResult<()> {
+    let b = vm.peek(0)?.as_handle();
+    let a = vm.peek(1)?.as_handle();
+    let c = format!("{}{}", vm.heap.string(a), vm.heap.string(b));
     let c = allocate_string(vm, c);
-    vm.push(c);
+    vm.pop()?;
+    vm.pop()?;
+    vm.push(c)
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
-        let stack = generate_stack();
+        Vm::with_config(VmConfig::default())
+    }
+
+    /// Creates a vm with the limits and garbage collection `config` sets.
+    pub fn with_config(config: VmConfig) -> Self {
+        let mut heap = Heap::with_threshold(config.gc_threshold, config.gc_grow_factor);
+        heap.set_stress(config.stress_gc);
         let mut vm = Vm {
-            chunk: None,
-            ip: &mut 0,
-            stack,
-            stack_top: &mut Value::None,
-            globals: HashMap::new(),
-            strings: HashSet::new(),
-            // objects: Some(Box::new(ObjList {
-            // 	value: Rc::new(RefCell::new(ObjString(String::new()))),
-            // 	next: None,
-            // }
-            // ))
+            frames: Vec::new(),
+            stack: Vec::new(),
+            globals: Globals::new(),
+            heap,
+            open_upvalues: Vec::new(),
+            compiler_roots: Vec::new(),
+            stack_max: config.stack_max,
+            frames_max: config.frames_max,
+            macros: HashMap::new(),
+            gensym_counter: 0,
+            handlers: Vec::new(),
+            raised: None,
+            condition: "error",
+            opt_level: OptLevel::default(),
         };
         vm.reset_stack();
+        natives::define_natives(&mut vm);
         vm
     }
 
+    /// Sets how much code compiled from now on is optimized.
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.opt_level = level;
+    }
+
+    /// Makes `function` available to scripts as the global `name`.
+    ///
+    /// Calls with a number of arguments `arity` doesn't accept are
+    /// reported as runtime errors before `function` is run, as are the
+    /// errors `function` returns.
+    pub fn define_native<A: Into<Arity>>(&mut self, name: &str, arity: A, function: NativeFn) {
+        let name = name.to_string();
+        let native = allocate_native(
+            self,
+            ObjNative {
+                name: name.clone(),
+                arity: arity.into(),
+                function,
+            },
+        );
+        let slot = self.globals.slot(&name);
+        self.globals.define(slot, native);
+    }
+
+    /// Returns the value of the global variable `name`, if it is defined.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals
+            .find(name)
+            .and_then(|slot| self.globals.get(slot))
+    }
+
+    /// Defines the global variable `name`, or changes its value if it exists.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let slot = self.globals.slot(name);
+        self.globals.define(slot, value);
+    }
+
+    /// Notes the global slots and macros given out so far.
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            slots: self.globals.len(),
+            macros: self.macros.clone(),
+        }
+    }
+
+    /// Forgets the global slots and macros given out since `checkpoint`,
+    /// as the code that needed them failed to compile. The closures of
+    /// replaced macros must still be rooted.
+    pub(crate) fn restore(&mut self, checkpoint: Checkpoint) {
+        self.globals.truncate(checkpoint.slots);
+        self.macros = checkpoint.macros;
+    }
+
+    /// Formats `value`, looking up any object it refers to.
+    pub fn display(&self, value: Value) -> ValueDisplay<'_> {
+        value.display(&self.heap)
+    }
+
+    /// Formats `value` as lisp data, where booleans read back as `t`
+    /// and `nil`.
+    pub fn display_lisp(&self, value: Value) -> ValueDisplay<'_> {
+        value.display_lisp(&self.heap)
+    }
+
+    /// The number of bytes the objects on the heap take up.
+    pub fn bytes_allocated(&self) -> usize {
+        self.heap.bytes_allocated()
+    }
+
+    /// Moves `object` onto the heap, collecting garbage first if it is due.
+    pub(crate) fn allocate<T: ObjVal>(&mut self, object: T) -> Object {
+        let object = self.heap.insert(object);
+        if self.heap.should_collect() {
+            // The new object is not reachable from any root yet.
+            self.collect_garbage(Some(object.handle));
+        }
+        object
+    }
+
+    fn collect_if_needed(&mut self) {
+        if self.heap.should_collect() {
+            self.collect_garbage(None);
+        }
+    }
+
+    /// Frees every object that is no longer reachable from the vm.
+    /// `pending` is kept alive along with the roots.
+    pub(crate) fn collect_garbage(&mut self, pending: Option<Handle>) {
+        let roots = self
+            .stack
+            .iter()
+            .chain(self.globals.values())
+            .chain(self.compiler_roots.iter())
+            .chain(self.macros.values().map(|definition| &definition.closure))
+            .chain(self.raised.iter())
+            .chain(self.handlers.iter().map(|handler| &handler.conditions))
+            .filter_map(|value| value.handle())
+            .chain(self.frames.iter().map(|frame| frame.closure))
+            .chain(self.handlers.iter().map(|handler| handler.closure))
+            .chain(self.open_upvalues.iter().copied())
+            .chain(pending);
+        self.heap.collect(roots);
+    }
+
     fn reset_stack(&mut self) {
-        self.stack_top = &mut self.stack[0];
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.raised = None;
+    }
+
+    /// Returns an error for `message` with a trace of the calls in
+    /// progress. The stack is left as it is for a handler to unwind.
+    pub(crate) fn runtime_error(&mut self, message: &str) -> VmErr {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.get::<ObjFunction>(frame.function);
+                TraceFrame {
+                    function: function.name.clone(),
+                    span: Span::new(
+                        &function.chunk.source,
+                        function.chunk.position(frame.ip - 1),
+                        0,
+                    ),
+                }
+            })
+            .collect();
+        self.raised = None;
+        self.condition = "error";
+
+        let span = trace.first().map(|frame| frame.span.clone());
+        let mut error = Diagnostic::new(ErrorKind::Runtime, message, span);
+        error.trace = trace;
+        VmErr::RuntimeError(Box::new(error))
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
+    /// Returns an error for `message` like [`Vm::runtime_error`], which
+    /// `condition-case` catches as the condition symbol `condition`.
+    pub(crate) fn raise(&mut self, condition: &'static str, message: &str) -> VmErr {
+        let error = self.runtime_error(message);
+        self.condition = condition;
+        error
+    }
+
+    /// Returns an error raising the error object `error`, a list of
+    /// the error symbol followed by its data, for `condition-case` to
+    /// catch.
+    pub(crate) fn signal(&mut self, error: Value) -> VmErr {
+        let message = error_message(&self.heap, error);
+        let raised = self.runtime_error(&message);
+        self.raised = Some(error);
+        raised
+    }
+
+    /// Calls the innermost handler catching `error` installed by the
+    /// calls `run` is executing, the ones above `depth`, with its error
+    /// object. Returns false, leaving the stack as it is, if there is
+    /// no such handler.
+    fn handle(&mut self, error: &VmErr, depth: usize) -> bool {
+        // The handlers are searched from the innermost out, stopping at
+        // the first one installed below `depth`.
+        let index = self
+            .handlers
+            .iter()
+            .rposition(|handler| handler.frames <= depth || self.catches(handler));
+        let handler = match index {
+            Some(index) if self.handlers[index].frames > depth => self.handlers[index],
+            _ => return false,
+        };
+        self.handlers.truncate(index.unwrap());
+        self.close_upvalues(handler.stack);
+        self.stack.truncate(handler.stack);
+        self.frames.truncate(handler.frames);
+        self.frame_mut().ip = handler.ip;
+
+        // The closure is pushed first, keeping it alive while the error
+        // object is allocated.
+        self.stack
+            .push(Value::from(Object::new(ObjId::Closure, handler.closure)));
+        let object = match self.raised.take() {
+            Some(object) => object,
+            None => {
+                let message = error.diagnostics()[0].message.clone();
+                let symbol = allocate_symbol(self, String::from(self.condition));
+                self.stack.push(symbol);
+                let message = allocate_string(self, message);
+                let data = allocate_cons(self, message, Value::None);
+                self.stack.pop();
+                allocate_cons(self, symbol, data)
+            }
+        };
+        self.stack.push(object);
+        true
+    }
 
-        let instruction =
-            unsafe { (self.ip.offset_from(&self.chunk.as_ref().unwrap().code[0]) - 1) as usize };
-        let line = self.chunk.as_ref().unwrap().lines[instruction];
-        eprintln!("[line {}] in script", line);
-        self.reset_stack();
+    /// Whether `handler` catches the error being raised, by the
+    /// symbol of its error object or, for an error the vm raised
+    /// itself, by the name of its condition.
+    fn catches(&self, handler: &Handler) -> bool {
+        if handler.conditions.is_type(ValueType::Bool) {
+            return handler.conditions.as_bool();
+        }
+        let matches = |condition: Value| match self.raised {
+            Some(raised) => {
+                raised.is_obj_type(ObjId::Cons)
+                    && self.heap.get::<ObjCons>(raised.as_handle()).car == condition
+            }
+            None => {
+                condition.is_obj_type(ObjId::Symbol)
+                    && self.heap.symbol(condition.as_handle()) == self.condition
+            }
+        };
+        let mut conditions = handler.conditions;
+        while conditions.is_obj_type(ObjId::Cons) {
+            let cons = self.heap.get::<ObjCons>(conditions.as_handle());
+            if matches(cons.car) {
+                return true;
+            }
+            conditions = cons.cdr;
+        }
+        false
     }
 
     pub fn interpret(&mut self, source: &str) -> InterpretResult<()> {
-        let chunk = compile(self, source)?;
+        self.interpret_file(source, "<input>")
+    }
+
+    /// Runs `source`, reporting errors as coming from `file`. The
+    /// syntax of `source` is picked from the extension of `file`.
+    pub fn interpret_file(&mut self, source: &str, file: &str) -> InterpretResult<()> {
+        self.interpret_as(source, file, Syntax::from_path(file))
+    }
+
+    /// Runs `source`, written in `syntax`, reporting errors as coming from `file`.
+    pub fn interpret_as(
+        &mut self,
+        source: &str,
+        file: &str,
+        syntax: Syntax,
+    ) -> InterpretResult<()> {
+        let function = compile(self, source, file, syntax);
+        self.run_script(function).map(|_| ())
+    }
+
+    /// Compiles `source` like [`Vm::interpret_as`], returning a cache of
+    /// the compiled code to run later with [`Vm::interpret_cache`].
+    pub fn compile_cache(
+        &mut self,
+        source: &str,
+        file: &str,
+        syntax: Syntax,
+    ) -> InterpretResult<Vec<u8>> {
+        let function = compile(self, source, file, syntax)?;
+        cache::write(self, function, source, file, syntax).map_err(|message| {
+            VmErr::CompileError(vec![Diagnostic::new(ErrorKind::Compile, message, None)])
+        })
+    }
+
+    /// Runs the code cached in `bytes` by [`Vm::compile_cache`]. Pass
+    /// the `source` it was compiled from, if it is still around, to show
+    /// runtime errors against it. The cache is rejected if it was
+    /// compiled from other source or by another version of eswm.
+    pub fn interpret_cache(&mut self, bytes: &[u8], source: Option<&str>) -> InterpretResult<()> {
+        let function = cache::read(self, bytes, source).map_err(|message| {
+            VmErr::CompileError(vec![Diagnostic::new(ErrorKind::Read, message, None)])
+        });
+        self.run_script(function).map(|_| ())
+    }
 
-        self.ip = &chunk.code[0];
-        self.chunk = Some(chunk);
-        self.run()
+    /// Runs `source` like [`Vm::interpret_as`], as typed at the repl.
+    /// Returns the value of the expression ending it, or nil if it ends
+    /// in some other statement.
+    ///
+    /// The value isn't kept alive, so it should be used before the vm
+    /// next allocates.
+    pub fn evaluate(&mut self, source: &str, file: &str, syntax: Syntax) -> InterpretResult<Value> {
+        let function = compile_repl(self, source, file, syntax);
+        self.run_script(function)
     }
 
-    fn push<T: Into<Value>>(&mut self, value: T) {
-        unsafe {
-            *self.stack_top = value.into();
-            self.stack_top = self.stack_top.add(1);
+    /// Runs the top-level code of a script, returning what it returned.
+    fn run_script(&mut self, function: InterpretResult<Value>) -> InterpretResult<Value> {
+        let result = function.and_then(|function| {
+            self.push(function)?;
+            let closure = allocate_closure(self, ObjClosure::new(function.as_handle(), Vec::new()));
+            self.pop()?;
+            self.push(closure)?;
+            self.call(closure.as_handle(), 0)?;
+            self.run(0)?;
+            self.pop()
+        });
+        if result.is_err() {
+            self.reset_stack();
         }
+        result
     }
 
-    fn pop(&mut self) -> Value {
-        unsafe {
-            self.stack_top = self.stack_top.sub(1);
-            *self.stack_top
+    /// Calls `callee` with `args` and returns its result. This lets
+    /// natives call back into scripts.
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> InterpretResult<Value> {
+        let depth = self.frames.len();
+        let result = self.call_function_at(depth, callee, args);
+        // Only the outermost call cleans up after an error, the calls
+        // in progress below a nested one may still handle it.
+        if result.is_err() && depth == 0 {
+            self.reset_stack();
         }
+        result
     }
 
-    fn peek(&self, distance: usize) -> Value {
-        unsafe { *self.stack_top.sub(1).sub(distance) }
+    fn call_function_at(
+        &mut self,
+        depth: usize,
+        callee: Value,
+        args: &[Value],
+    ) -> InterpretResult<Value> {
+        if args.len() > u8::MAX as usize {
+            return Err(self.runtime_error("Can't have more than 255 arguments."));
+        }
+
+        self.push(callee)?;
+        for arg in args {
+            self.push(*arg)?;
+        }
+        self.call_value(callee, args.len() as u8)?;
+        if self.frames.len() > depth {
+            self.run(depth)?;
+        }
+        self.pop()
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let instruction: u8 = unsafe { *self.ip };
+    fn call(&mut self, closure: Handle, arg_count: u8) -> InterpretResult<()> {
+        let function_handle = self.heap.get::<ObjClosure>(closure).function;
+        let function = self.heap.get::<ObjFunction>(function_handle);
+        let arity = function.arity;
+        if arg_count as usize != arity {
+            return Err(self.raise(
+                "wrong-number-of-arguments",
+                &format!("Expected {} arguments but got {}.", arity, arg_count),
+            ));
+        }
+
+        if self.frames.len() == self.frames_max {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        let frame = CallFrame {
+            closure,
+            function: function_handle,
+            ip: 0,
+            slots: self.stack.len() - arg_count as usize - 1,
+        };
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> InterpretResult<()> {
+        if callee.is_type(ValueType::Obj) {
+            match callee.obj_type() {
+                ObjId::Closure => return self.call(callee.as_handle(), arg_count),
+                ObjId::Native => {
+                    let native = self.heap.get::<ObjNative>(callee.as_handle());
+                    let (arity, function) = (native.arity, native.function);
+                    if !arity.accepts(arg_count as usize) {
+                        return Err(self.raise(
+                            "wrong-number-of-arguments",
+                            &format!("Expected {} arguments but got {}.", arity, arg_count),
+                        ));
+                    }
+
+                    let args_start = self.stack.len() - arg_count as usize;
+                    let args = self.stack[args_start..].to_vec();
+                    let result = match function(self, &args) {
+                        Ok(result) => result,
+                        Err(RuntimeError::Message(message)) => {
+                            return Err(self.runtime_error(&message));
+                        }
+                        Err(RuntimeError::Condition(condition, message)) => {
+                            return Err(self.raise(condition, &message));
+                        }
+                        Err(RuntimeError::Raised(error)) => return Err(error),
+                    };
+                    self.stack.truncate(args_start - 1);
+                    self.push(result)?;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Err(self.raise("invalid-function", "Can only call functions and classes."))
+    }
+
+    /// Returns the upvalue for the stack slot `local`, reusing an open one if it exists.
+    fn capture_upvalue(&mut self, local: usize) -> Handle {
+        let mut index = 0;
+        while index < self.open_upvalues.len() {
+            let slot = self.open_slot(self.open_upvalues[index]);
+            if slot == local {
+                return self.open_upvalues[index];
+            } else if slot > local {
+                break;
+            }
+            index += 1;
+        }
+
+        let upvalue = allocate_upvalue(self, ObjUpvalue::new(local));
+        self.open_upvalues.insert(index, upvalue);
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at or above the stack slot `last`.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = self.open_slot(upvalue);
+            if slot < last {
+                break;
+            }
+            let value = self.stack[slot];
+            self.heap.get_mut::<ObjUpvalue>(upvalue).close(value);
+            self.open_upvalues.pop();
+        }
+    }
 
-        self.ip = unsafe { self.ip.add(1) };
+    fn open_slot(&self, upvalue: Handle) -> usize {
+        self.heap
+            .get::<ObjUpvalue>(upvalue)
+            .slot()
+            .expect("open upvalue has been closed")
+    }
 
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.heap.get::<ObjFunction>(self.frame().function).chunk
+    }
+
+    fn upvalue(&self, slot: usize) -> Handle {
+        self.heap.get::<ObjClosure>(self.frame().closure).upvalues[slot]
+    }
+
+    pub(crate) fn push<T: Into<Value>>(&mut self, value: T) -> InterpretResult<()> {
+        if self.stack.len() == self.stack_max {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.stack.push(value.into());
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> InterpretResult<Value> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Stack underflow.")),
+        }
+    }
+
+    fn peek(&mut self, distance: usize) -> InterpretResult<Value> {
+        if distance >= self.stack.len() {
+            return Err(self.runtime_error("Stack underflow."));
+        }
+        Ok(self.stack[self.stack.len() - 1 - distance])
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let instruction = self.heap.get::<ObjFunction>(frame.function).chunk.code[frame.ip];
+        frame.ip += 1;
         instruction
     }
 
-    fn read_constant(&mut self) -> Value {
-        let const_location = self.read_byte() as usize;
-        self.chunk.as_ref().unwrap().constants[const_location]
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+        (high << 8) | low
     }
 
-    fn read_string(&mut self) -> String {
-        self.read_constant().as_rstring()
+    /// Reads a 24-bit operand.
+    fn read_long(&mut self) -> usize {
+        let high = self.read_byte() as usize;
+        let low = self.read_short() as usize;
+        (high << 16) | low
     }
 
-    fn binary_op(&mut self, op: BinaryOp) {
-        if !self.peek(0).is_type(ValueType::Number) || !self.peek(1).is_type(ValueType::Number) {
-            self.runtime_error("Operands must be numbers.");
+    /// Reads the operand of `instruction`, a constant index or global slot.
+    fn read_index(&mut self, instruction: OpCode) -> usize {
+        if instruction.is_long() {
+            self.read_long()
+        } else {
+            self.read_byte() as usize
         }
-        let b = self.pop();
-        let a = self.pop();
-        self.push(match op {
+    }
+
+    /// Reads the constant the operand of `instruction` refers to.
+    fn read_constant(&mut self, instruction: OpCode) -> Value {
+        let index = self.read_index(instruction);
+        self.chunk().constants[index]
+    }
+
+    fn binary_op(&mut self, op: BinaryOp) -> InterpretResult<()> {
+        if !self.peek(0)?.is_numeric() || !self.peek(1)?.is_numeric() {
+            return Err(self.raise("wrong-type-argument", "Operands must be numbers."));
+        }
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let result = match op {
             BinaryOp::Add => a + b,
             BinaryOp::Sub => a - b,
             BinaryOp::Div => a / b,
             BinaryOp::Mul => a * b,
+            BinaryOp::IntDiv => a
+                .floor_div(b)
+                .ok_or_else(|| self.raise("arith-error", "Division by zero."))?,
+            BinaryOp::Mod => a
+                .modulo(b)
+                .ok_or_else(|| self.raise("arith-error", "Division by zero."))?,
             BinaryOp::Greater => (a > b).into(),
             BinaryOp::Less => (a < b).into(),
-        });
+        };
+        self.push(result)
+    }
+
+    /// Applies `op` to the two integers on top of the stack. `op` gives
+    /// [`None`] if the result doesn't fit in one.
+    fn integer_op(&mut self, op: fn(Value, Value) -> Option<Value>) -> InterpretResult<()> {
+        if !self.peek(0)?.is_type(ValueType::Int) || !self.peek(1)?.is_type(ValueType::Int) {
+            return Err(self.raise("wrong-type-argument", "Operands must be integers."));
+        }
+        let b = self.pop()?;
+        let a = self.pop()?;
+        match op(a, b) {
+            Some(result) => self.push(result),
+            None => Err(self.raise("arith-error", "Integer overflow.")),
+        }
+    }
+
+    /// Executes instructions until the call stack shrinks to `depth`
+    /// frames, passing the errors raised to the handlers installed on
+    /// the way.
+    fn run(&mut self, depth: usize) -> InterpretResult<()> {
+        loop {
+            match self.execute(depth) {
+                Ok(()) => return Ok(()),
+                Err(error) => {
+                    if !self.handle(&error, depth) {
+                        return Err(error);
+                    }
+                }
+            }
+        }
     }
 
-    fn run(&mut self) -> InterpretResult<()> {
+    fn execute(&mut self, depth: usize) -> InterpretResult<()> {
         loop {
             #[cfg(feature = "debug_trace_execution")]
             {
                 print!("          ");
 
-                let mut slot: *mut Value = &mut self.stack[0];
-                let stack_dif = unsafe { self.stack_top.offset_from(slot) };
-                loop {
-                    let cur_dif = unsafe { slot.offset_from(&mut self.stack[0]) };
-                    if cur_dif == stack_dif {
-                        break;
-                    }
-
+                for slot in self.stack.iter() {
                     print!("[ ");
-                    print_value(unsafe { *slot });
+                    print_value(&self.heap, *slot);
                     print!(" ]");
-                    unsafe {
-                        slot = slot.add(1);
-                    }
                 }
                 println!();
-                unsafe {
-                    disassemble_instruction(
-                        &self.chunk.as_ref().unwrap(),
-                        self.ip.offset_from(&self.chunk.as_ref().unwrap().code[0]) as usize,
-                    );
-                }
+                disassemble_instruction(&self.heap, &self.globals, self.chunk(), self.frame().ip);
             }
 
             let instruction = OpCode::from(self.read_byte());
             match instruction {
                 OpCode::Return => {
-                    return Ok(());
+                    let result = self.pop()?;
+                    let slots = self.frame().slots;
+                    self.close_upvalues(slots);
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(frame.slots);
+                    self.push(result)?;
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
                 }
-                OpCode::Constant => {
-                    let constant = self.read_constant();
-                    self.push(constant);
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let constant = self.read_constant(instruction);
+                    self.push(constant)?;
                 }
                 OpCode::Negate => {
-                    if !self.peek(0).is_type(ValueType::Number) {
-                        self.runtime_error("Operand must be a number.");
-                        return Err(VmErr::RuntimeError);
+                    if !self.peek(0)?.is_numeric() {
+                        return Err(self.raise("wrong-type-argument", "Operand must be a number."));
                     }
-                    let val = -(self.pop().as_number());
-                    self.push(val);
+                    let val = -self.pop()?;
+                    self.push(val)?;
                 }
-                OpCode::Nil => self.push(Value::None),
-                OpCode::True => self.push(true),
-                OpCode::False => self.push(false),
+                OpCode::Nil => self.push(Value::None)?,
+                OpCode::True => self.push(true)?,
+                OpCode::False => self.push(false)?,
                 OpCode::Equal => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a == b);
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let equal = values_equal(&self.heap, a, b);
+                    self.push(equal)?;
+                }
+                OpCode::NotEqual => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let equal = values_equal(&self.heap, a, b);
+                    self.push(!equal)?;
                 }
                 OpCode::Add => {
-                    if self.peek(0).is_type(ValueType::String)
-                        && self.peek(1).is_type(ValueType::String)
+                    if self.peek(0)?.is_obj_type(ObjId::String)
+                        && self.peek(1)?.is_obj_type(ObjId::String)
                     {
-                        concatenate(self);
-                    } else if self.peek(0).is_type(ValueType::Number)
-                        && self.peek(1).is_type(ValueType::Number)
-                    {
-                        self.binary_op(BinaryOp::Add)
+                        concatenate(self)?;
+                    } else if self.peek(0)?.is_numeric() && self.peek(1)?.is_numeric() {
+                        self.binary_op(BinaryOp::Add)?
                     } else {
-                        self.runtime_error("Operands must be two numbers or two strings.");
-                        return Err(VmErr::RuntimeError);
+                        return Err(self.raise(
+                            "wrong-type-argument",
+                            "Operands must be two numbers or two strings.",
+                        ));
+                    }
+                }
+                OpCode::Greater => self.binary_op(BinaryOp::Greater)?,
+                OpCode::Less => self.binary_op(BinaryOp::Less)?,
+                OpCode::Subtract => self.binary_op(BinaryOp::Sub)?,
+                OpCode::Divide => self.binary_op(BinaryOp::Div)?,
+                OpCode::Multiply => self.binary_op(BinaryOp::Mul)?,
+                OpCode::IntDivide => self.binary_op(BinaryOp::IntDiv)?,
+                OpCode::Modulo => self.binary_op(BinaryOp::Mod)?,
+                OpCode::BitAnd => self.integer_op(|a, b| Some(a & b))?,
+                OpCode::BitOr => self.integer_op(|a, b| Some(a | b))?,
+                OpCode::BitXor => self.integer_op(|a, b| Some(a ^ b))?,
+                OpCode::ShiftLeft => self.integer_op(|a, b| a.shift_left(b.as_int()))?,
+                OpCode::ShiftRight => self.integer_op(|a, b| a.shift_left(-b.as_int()))?,
+                OpCode::BitNot => {
+                    if !self.peek(0)?.is_type(ValueType::Int) {
+                        return Err(
+                            self.raise("wrong-type-argument", "Operand must be an integer.")
+                        );
                     }
+                    let val = !self.pop()?;
+                    self.push(val)?;
                 }
-                OpCode::Greater => self.binary_op(BinaryOp::Greater),
-                OpCode::Less => self.binary_op(BinaryOp::Less),
-                OpCode::Subtract => self.binary_op(BinaryOp::Sub),
-                OpCode::Divide => self.binary_op(BinaryOp::Div),
-                OpCode::Multiply => self.binary_op(BinaryOp::Mul),
                 OpCode::Not => {
-                    let val = is_falsy(self.pop());
-                    self.push(val);
+                    let val = self.pop()?.is_falsy();
+                    self.push(val)?;
                 }
                 OpCode::Print => {
-                    print_value(self.pop());
-		    println!();
+                    let value = self.pop()?;
+                    print_value(&self.heap, value);
+                    println!();
+                }
+                OpCode::PrintLisp => {
+                    let value = self.pop()?;
+                    print_lisp_value(&self.heap, value);
+                    println!();
                 }
                 OpCode::Pop => {
-                    self.pop();
+                    self.pop()?;
                 }
-                OpCode::DefineGlobal => {
-                    let name = self.read_string();
-                    let value = self.peek(0);
-                    self.globals.insert(name, value);
-                    self.pop();
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let slot = self.read_index(instruction);
+                    let value = self.peek(0)?;
+                    self.globals.define(slot, value);
+                    self.pop()?;
                 }
-                OpCode::GetGlobal => {
-                    let name = self.read_string();
-                    let value = match self.globals.get(&name) {
-                        Some(n) => n.clone(),
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let slot = self.read_index(instruction);
+                    let value = match self.globals.get(slot) {
+                        Some(value) => value,
                         None => {
-                            self.runtime_error(&format!("Undefined varialbe: '{}'.", name));
-                            return Err(VmErr::RuntimeError);
+                            let name = self.globals.name(slot).to_string();
+                            return Err(self.raise(
+                                "void-variable",
+                                &format!("Undefined variable '{}'.", name),
+                            ));
                         }
                     };
-                    self.push(value);
+                    self.push(value)?;
                 }
-                OpCode::SetGlobal => {
-                    let name = self.read_string();
-                    let val = self.peek(0);
-                    let result = self.globals.insert(name.clone(), val);
-                    if let None = result {
-                        self.globals.remove_entry(&name);
-                        self.runtime_error(&format!("Undefined variable '{}'.", name));
-                        return Err(VmErr::RuntimeError);
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let slot = self.read_index(instruction);
+                    let val = self.peek(0)?;
+                    if !self.globals.set(slot, val) {
+                        let name = self.globals.name(slot).to_string();
+                        return Err(
+                            self.raise("void-variable", &format!("Undefined variable '{}'.", name))
+                        );
                     }
                 }
-		OpCode::GetLocal => {
-		    let slot = self.read_byte();
-		    let val = self.stack[slot as usize];
-		    self.push(val);
-		}
-		OpCode::SetLocal => {
-		    let slot = self.read_byte();
-		    let val = self.peek(0);
-		    self.stack[slot as usize] = val;
-		}
+                OpCode::GetLocal => {
+                    let slot = self.read_byte();
+                    let val = self.stack[self.frame().slots + slot as usize];
+                    self.push(val)?;
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte();
+                    let val = self.peek(0)?;
+                    let slots = self.frame().slots;
+                    self.stack[slots + slot as usize] = val;
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short() as usize;
+                    if self.peek(0)?.is_falsy() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte();
+                    let callee = self.peek(arg_count as usize)?;
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let function = self.read_constant(instruction).as_handle();
+                    let upvalue_count = self.heap.get::<ObjFunction>(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let local = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(local));
+                        } else {
+                            let closure = self.heap.get::<ObjClosure>(self.frame().closure);
+                            upvalues.push(closure.upvalues[index]);
+                        }
+                    }
+                    let closure = allocate_closure(self, ObjClosure::new(function, upvalues));
+                    self.push(closure)?;
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let val = match self.heap.get::<ObjUpvalue>(self.upvalue(slot)) {
+                        ObjUpvalue::Open(local) => self.stack[*local],
+                        ObjUpvalue::Closed(value) => *value,
+                    };
+                    self.push(val)?;
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let val = self.peek(0)?;
+                    let upvalue = self.upvalue(slot);
+                    match self.heap.get_mut::<ObjUpvalue>(upvalue) {
+                        ObjUpvalue::Open(local) => self.stack[*local] = val,
+                        ObjUpvalue::Closed(value) => *value = val,
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    let last = self.stack.len() - 1;
+                    self.close_upvalues(last);
+                    self.pop()?;
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_short() as usize;
+                    let closure = self.pop()?;
+                    if !closure.is_obj_type(ObjId::Closure) {
+                        return Err(self.runtime_error("Handler must be a function."));
+                    }
+                    let closure = closure.as_handle();
+                    let conditions = self.pop()?;
+                    let handler = Handler {
+                        closure,
+                        conditions,
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        ip: self.frame().ip + offset,
+                    };
+                    self.handlers.push(handler);
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the message of the runtime error `result` failed with.
+    fn message<T: std::fmt::Debug>(result: InterpretResult<T>) -> String {
+        result.unwrap_err().diagnostics()[0].message.clone()
+    }
+
+    #[test]
+    fn popping_an_empty_stack_underflows() {
+        let mut vm = Vm::new();
+        assert_eq!(message(vm.pop()), "Stack underflow.");
+        vm.push(1).unwrap();
+        assert_eq!(message(vm.peek(1)), "Stack underflow.");
+        assert_eq!(vm.pop().unwrap().as_int(), 1);
+    }
+
+    #[test]
+    fn pushing_past_the_limit_overflows() {
+        let mut vm = Vm::with_config(VmConfig {
+            stack_max: 2,
+            ..VmConfig::default()
+        });
+        vm.push(1).unwrap();
+        vm.push(2).unwrap();
+        assert_eq!(message(vm.push(3)), "Stack overflow.");
+    }
+
+    #[test]
+    fn failed_compiles_give_back_their_global_slots() {
+        let mut vm = Vm::new();
+        let slots = vm.globals.len();
+        assert!(vm
+            .evaluate("var a = b + c; 1 +;", "<test>", Syntax::Script)
+            .is_err());
+        assert!(vm
+            .evaluate("(setq d (e)) (if)", "<test>", Syntax::Lisp)
+            .is_err());
+        assert_eq!(vm.globals.len(), slots);
+
+        vm.evaluate("var a = b;", "<test>", Syntax::Script)
+            .unwrap_err();
+        assert_eq!(vm.globals.len(), slots + 2);
+        assert_eq!(vm.globals.find("b"), Some(slots + 1));
+    }
+}
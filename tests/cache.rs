@@ -0,0 +1,92 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks caches run like their source, and that broken ones are
+//! rejected rather than crashing the vm.
+use eswm::{Syntax, Vm};
+
+const SCRIPT: &str = "
+fun adder(n) {
+    fun add(x) { return x + n; }
+    return add;
+}
+var add = adder(3);
+var big = 1 < 2 and !(3 == 4);
+var result = add(4) * 2;
+if (big) result = result + 1; else result = result - 1;
+";
+
+const LISP: &str = "
+(defmacro twice (x) `(* 2 ,x))
+(defun checked (n)
+  (condition-case e
+      (if (< n 2) (signal 'small (list n)) (twice n))
+    (small (list 'caught (car (cdr e))))))
+(defvar result (list (checked 1) (checked 3)))
+";
+
+/// Compiles `source` into a cache with a vm of its own.
+fn cache(source: &str, syntax: Syntax) -> Vec<u8> {
+    let mut vm = Vm::new();
+    match vm.compile_cache(source, "<test>", syntax) {
+        Ok(bytes) => bytes,
+        Err(error) => panic!("{}", error.render(false)),
+    }
+}
+
+/// Runs `bytes` in a new vm and returns the value of `result` it leaves.
+fn run(bytes: &[u8], source: Option<&str>) -> Result<String, String> {
+    let mut vm = Vm::new();
+    vm.interpret_cache(bytes, source)
+        .map_err(|error| error.render(false))?;
+    let result = vm.global("result").ok_or("result is undefined")?;
+    Ok(vm.display(result).to_string())
+}
+
+#[test]
+fn script_round_trip() {
+    let bytes = cache(SCRIPT, Syntax::Script);
+    assert_eq!(run(&bytes, Some(SCRIPT)).unwrap(), "15");
+    assert_eq!(run(&bytes, None).unwrap(), "15");
+}
+
+#[test]
+fn lisp_round_trip() {
+    let bytes = cache(LISP, Syntax::Lisp);
+    assert_eq!(run(&bytes, Some(LISP)).unwrap(), "((caught 1) 6)");
+}
+
+#[test]
+fn stale_cache_is_rejected() {
+    let bytes = cache(SCRIPT, Syntax::Script);
+    assert!(run(&bytes, Some("var result = 1;")).is_err());
+}
+
+#[test]
+fn corrupt_caches_dont_crash() {
+    for (source, syntax) in [(SCRIPT, Syntax::Script), (LISP, Syntax::Lisp)] {
+        let bytes = cache(source, syntax);
+        for offset in 0..bytes.len() {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut corrupt = bytes.clone();
+                corrupt[offset] ^= flip;
+                // Errors are fine, only panics fail the test.
+                let _ = run(&corrupt, None);
+            }
+            assert!(run(&bytes[..offset], None).is_err());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Runs lisp and checks the value it gives.
+//! Checks how errors are rendered for a terminal and for plain output.
+use eswm::{Syntax, Vm};
+
+/// Runs `source`, which must fail, and renders its errors without color.
+fn render(source: &str, syntax: Syntax) -> String {
+    let mut vm = Vm::new();
+    match vm.evaluate(source, "<test>", syntax) {
+        Ok(_) => panic!("'{}' ran without an error", source),
+        Err(error) => error.render(false),
+    }
+}
+
+#[test]
+fn snippet_shows_the_line_and_marks_the_token() {
+    let expected = "\
+[<test>:1:20] Error at 'var': Expect expression.
+ 1 | var a = 1; var b = var;
+   |                    ^^^";
+    assert_eq!(render("var a = 1; var b = var;", Syntax::Script), expected);
+}
+
+#[test]
+fn columns_count_characters_after_multibyte_text() {
+    let expected = "\
+[<test>:1:20] Error at 'var': Expect expression.
+ 1 | var s = \"ünï\"; s + var;
+   |                    ^^^";
+    assert_eq!(render("var s = \"ünï\"; s + var;", Syntax::Script), expected);
+
+    let expected = "\
+Undefined variable 'missing'.
+ 1 | (let ((ü 1)) (+ ü missing))
+   |                   ^
+[<test>:1:19] in script";
+    assert_eq!(render("(let ((ü 1)) (+ ü missing))", Syntax::Lisp), expected);
+}
+
+#[test]
+fn shadowed_local_notes_the_earlier_declaration() {
+    let expected = "\
+[<test>:2:7] Error at 'abc': Already a variable with this name in this scope.
+ 2 |   var abc = 2; }
+   |       ^^^
+[<test>:1:7] Note: The earlier declaration is here.
+ 1 | { var abc = 1;
+   |       ---";
+    assert_eq!(render("{ var abc = 1;\n  var abc = 2; }", Syntax::Script), expected);
+}
+
+#[test]
+fn runtime_errors_show_the_stack_trace() {
+    let expected = "\
+Operands must be two numbers or two strings.
+ 1 | fun f() { return 1 + nil; }
+   |                      ^
+[<test>:1:22] in f()
+[<test>:2:3] in script";
+    assert_eq!(render("fun f() { return 1 + nil; }\nf();", Syntax::Script), expected);
+}
+
+#[test]
+fn plain_output_has_no_escape_codes() {
+    let mut vm = Vm::new();
+    let error = vm.evaluate("1 +;", "<test>", Syntax::Script).unwrap_err();
+    assert!(!error.render(false).contains('\x1b'));
+    assert!(error.render(true).contains("\x1b[1;31mError\x1b[0m"));
+    assert_eq!(error.render(false), error.to_string() + "\n 1 | 1 +;\n   |    ^");
+}
@@ -0,0 +1,121 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks that garbage is collected and that collecting keeps
+//! everything still in use.
+use eswm::{Syntax, Vm, VmConfig};
+
+/// Creates a vm that first collects garbage once `threshold` bytes are allocated.
+fn vm_with_threshold(threshold: usize) -> Vm {
+    Vm::with_config(VmConfig {
+        gc_threshold: threshold,
+        ..VmConfig::default()
+    })
+}
+
+/// Creates a vm that collects garbage on every allocation.
+fn stressed_vm() -> Vm {
+    Vm::with_config(VmConfig {
+        stress_gc: true,
+        ..VmConfig::default()
+    })
+}
+
+/// Evaluates `source` on `vm` and returns its value as it would be printed.
+fn eval(vm: &mut Vm, source: &str, syntax: Syntax) -> String {
+    match vm.evaluate(source, "<test>", syntax) {
+        Ok(value) => vm.display(value).to_string(),
+        Err(error) => panic!("{}", error.render(false)),
+    }
+}
+
+#[test]
+fn garbage_is_collected() {
+    let threshold = 64 * 1024;
+    let mut vm = vm_with_threshold(threshold);
+    // Every pass makes a new string and three conses that are garbage by
+    // the next pass, megabytes in all.
+    let source = "
+(let ((i 0) (kept nil))
+  (while (< i 50000)
+    (setq kept (list i (concat \"item\" (symbol-name (gensym))) i))
+    (setq i (+ i 1)))
+  (car kept))";
+    assert_eq!(eval(&mut vm, source, Syntax::Lisp), "49999");
+    assert!(
+        vm.bytes_allocated() < 4 * threshold,
+        "{} bytes still allocated",
+        vm.bytes_allocated()
+    );
+}
+
+#[test]
+fn interned_strings_are_collected() {
+    let mut vm = vm_with_threshold(64 * 1024);
+    let source = "
+(let ((i 0))
+  (while (< i 20000)
+    (intern (concat \"name\" (symbol-name (gensym))))
+    (setq i (+ i 1))))
+(eq (intern \"kept\") (intern (concat \"ke\" \"pt\")))";
+    assert_eq!(eval(&mut vm, source, Syntax::Lisp), "true");
+    assert!(vm.bytes_allocated() < 256 * 1024);
+}
+
+#[test]
+fn closures_survive_stress_collection() {
+    let mut vm = stressed_vm();
+    let source = "
+fun counter(start) {
+    var count = start;
+    fun increment(by) {
+        count = count + by;
+        return count;
+    }
+    return increment;
+}
+var a = counter(1);
+var b = counter(100);
+var name = \"co\" + \"unt\";
+for (var i = 0; i < 10; i = i + 1) {
+    a(i);
+    b(1);
+}
+a(0) + b(0);";
+    assert_eq!(eval(&mut vm, source, Syntax::Script), "156");
+}
+
+#[test]
+fn lisp_closures_survive_stress_collection() {
+    let mut vm = stressed_vm();
+    let source = "
+(defun make-adders (n)
+  (let ((adders nil) (i 0))
+    (while (< i n)
+      (let ((k i))
+        (setq adders (cons (lambda (x) (+ x k)) adders)))
+      (setq i (+ i 1)))
+    adders))
+(defmacro twice (form) `(list ,form ,form))
+(let ((adders (make-adders 5)))
+  (list (mapcar (lambda (f) (f 10)) adders)
+        (twice (concat \"a\" \"b\"))
+        (condition-case e (error \"bad %s\" 'x) (error (cdr e)))))";
+    assert_eq!(
+        eval(&mut vm, source, Syntax::Lisp),
+        "((14 13 12 11 10) (ab ab) (bad x))"
+    );
+}
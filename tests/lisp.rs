@@ -0,0 +1,139 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Runs lisp and checks the value it gives.
+use eswm::{Syntax, Vm};
+
+/// Evaluates `source` and returns its value as it would be printed.
+fn eval(source: &str) -> String {
+    let mut vm = Vm::new();
+    match vm.evaluate(source, "<test>", Syntax::Lisp) {
+        Ok(value) => vm.display_lisp(value).to_string(),
+        Err(error) => panic!("{}", error.render(false)),
+    }
+}
+
+const SWAP: &str = "
+(defmacro swap (a b)
+  (let ((tmp (gensym)))
+    `(let ((,tmp ,a)) (setq ,a ,b) (setq ,b ,tmp))))
+";
+
+#[test]
+fn gensym_is_not_a_variable_of_the_same_name() {
+    let source = format!("{} (let ((g0 1) (y 2)) (swap g0 y) (list g0 y))", SWAP);
+    assert_eq!(eval(&source), "(2 1)");
+}
+
+#[test]
+fn quoted_gensym_keeps_its_identity() {
+    let source = "
+(defmacro same () (let ((s (gensym))) `(list (eq ',s ',s) (eq ',s 'g0))))
+(same)";
+    assert_eq!(eval(source), "(t nil)");
+}
+
+#[test]
+fn unhandled_condition_keeps_its_location() {
+    let mut vm = Vm::new();
+    let source = "(defun f () (condition-case e (error \"x\") (foo 1)))\n(f)";
+    let error = vm.evaluate(source, "<test>", Syntax::Lisp).unwrap_err();
+    let error = &error.diagnostics()[0];
+    assert_eq!(error.message, "x");
+    assert_eq!(error.trace[0].function.as_deref(), Some("f"));
+}
+
+#[test]
+fn unhandled_condition_reaches_outer_handler() {
+    let source = "
+(condition-case e
+    (condition-case e (signal 'foo '(1)) (bar 'inner))
+  (foo (list 'outer e)))";
+    assert_eq!(eval(source), "(outer (foo 1))");
+}
+
+#[test]
+fn error_formats_its_arguments() {
+    let source = "(condition-case e (error \"bad %s: %d%%\" 'x 3) (error (error-message-string e)))";
+    assert_eq!(eval(source), "bad x: 3%");
+}
+
+#[test]
+fn vm_errors_carry_their_condition() {
+    let source = "
+(list (condition-case nil (/ 1 0) (arith-error 'div))
+      (condition-case nil (+ 1 \"a\") (wrong-type-argument 'type))
+      (condition-case nil (nth -1 '(1)) (args-out-of-range 'range))
+      (condition-case nil undefined-global (void-variable 'void))
+      (condition-case nil (car 1) (wrong-type-argument 'native)))";
+    assert_eq!(eval(source), "(div type range void native)");
+}
+
+#[test]
+fn vm_error_objects_hold_their_message() {
+    let source = "(condition-case e (/ 1 0) (arith-error (list (car e) (error-message-string e))))";
+    assert_eq!(eval(source), "(arith-error Division by zero.)");
+}
+
+#[test]
+fn other_conditions_dont_catch_vm_errors() {
+    let source = "
+(condition-case nil
+    (condition-case nil (/ 1 0) (void-variable 'wrong))
+  (error 'outer))";
+    assert_eq!(eval(source), "outer");
+}
+
+#[test]
+fn dividing_integers_truncates() {
+    assert_eq!(eval("(list (/ 7 2) (/ -7 2) (/ 4))"), "(3 -3 0)");
+}
+
+#[test]
+fn a_float_makes_division_float() {
+    assert_eq!(eval("(list (/ 7 2.0) (/ 7 2 2.0) (/ 4.0))"), "(3.5 1.75 0.25)");
+}
+
+#[test]
+fn floats_print_with_a_fraction() {
+    assert_eq!(eval("(list 3.0 (* 1.5 2) -0.0 2.5 3)"), "(3.0 3.0 -0.0 2.5 3)");
+}
+
+#[test]
+fn quoted_data_prints_as_lisp() {
+    assert_eq!(eval("'(t nil (1 . t) 2.0 sym)"), "(t nil (1 . t) 2.0 sym)");
+    assert_eq!(eval("(eq 'a 'a)"), "t");
+}
+
+#[test]
+fn macro_expansion_prints_as_lisp() {
+    let source = "
+(defmacro my-when (test &rest body) `(if ,test (progn ,@body) nil))
+(macroexpand '(my-when t 1 2))";
+    assert_eq!(eval(source), "(if t (progn 1 2) nil)");
+}
+
+#[test]
+fn failed_compile_leaves_macros_as_they_were() {
+    let mut vm = Vm::new();
+    vm.evaluate("(defmacro m () 1)", "<test>", Syntax::Lisp).unwrap();
+    let source = "(defmacro m () 2) (defmacro fresh () 3) (if)";
+    assert!(vm.evaluate(source, "<test>", Syntax::Lisp).is_err());
+    let value = vm.evaluate("(m)", "<test>", Syntax::Lisp).unwrap();
+    assert_eq!(vm.display_lisp(value).to_string(), "1");
+    let error = vm.evaluate("(fresh)", "<test>", Syntax::Lisp).unwrap_err();
+    assert_eq!(error.diagnostics()[0].message, "Undefined variable 'fresh'.");
+}
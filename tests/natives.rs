@@ -0,0 +1,92 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Runs lisp and checks the value it gives.
+//! Checks natives defined with [`Vm::define_native`] from a script.
+use eswm::{RuntimeError, Syntax, Value, Vm};
+
+fn count(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::from(args.len() as i64))
+}
+
+fn fail(_vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Err(RuntimeError::from(String::from("Window not found.")))
+}
+
+/// Creates a vm with natives of every kind of arity.
+fn vm() -> Vm {
+    let mut vm = Vm::new();
+    vm.define_native("exactly_two", 2, count);
+    vm.define_native("one_or_two", 1..=2, count);
+    vm.define_native("any", 0.., count);
+    vm.define_native("fail", 0, fail);
+    vm
+}
+
+/// Runs `source`, which must fail, and returns its error message.
+fn error_message(source: &str) -> String {
+    match vm().evaluate(source, "<test>", Syntax::Script) {
+        Ok(_) => panic!("'{}' ran without an error", source),
+        Err(error) => error.diagnostics()[0].message.clone(),
+    }
+}
+
+#[test]
+fn natives_are_called_with_their_arguments() {
+    let mut vm = vm();
+    let source = "exactly_two(1, 2) + one_or_two(1) + one_or_two(1, 2) + any() + any(1, 2, 3, 4);";
+    let value = vm.evaluate(source, "<test>", Syntax::Script).unwrap();
+    assert_eq!(value.as_int(), 9);
+}
+
+#[test]
+fn arity_is_checked_before_the_call() {
+    assert_eq!(
+        error_message("exactly_two(1);"),
+        "Expected 2 arguments but got 1."
+    );
+    assert_eq!(
+        error_message("one_or_two();"),
+        "Expected 1 to 2 arguments but got 0."
+    );
+    assert_eq!(
+        error_message("one_or_two(1, 2, 3);"),
+        "Expected 1 to 2 arguments but got 3."
+    );
+    assert_eq!(error_message("fail(1);"), "Expected 0 arguments but got 1.");
+}
+
+#[test]
+fn errors_from_natives_are_runtime_errors() {
+    let mut vm = vm();
+    let source = "fun f() { return fail(); }\nf();";
+    let error = vm.evaluate(source, "<test>", Syntax::Script).unwrap_err();
+    let error = &error.diagnostics()[0];
+    assert_eq!(error.message, "Window not found.");
+    assert_eq!(error.trace[0].function.as_deref(), Some("f"));
+    assert_eq!(error.trace[1].function, None);
+}
+
+#[test]
+fn lisp_can_handle_native_errors() {
+    let mut vm = vm();
+    let source = "(condition-case err (fail) (error (error-message-string err)))";
+    let value = vm.evaluate(source, "<test>", Syntax::Lisp).unwrap();
+    assert_eq!(vm.display(value).to_string(), "Window not found.");
+    let source = "(condition-case nil (exactly_two 1) (wrong-number-of-arguments 'caught))";
+    let value = vm.evaluate(source, "<test>", Syntax::Lisp).unwrap();
+    assert_eq!(vm.display(value).to_string(), "caught");
+}
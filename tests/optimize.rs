@@ -0,0 +1,101 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks that optimized code gives the same values as the code the
+//! compiler emits.
+use eswm::{OptLevel, Syntax, Vm};
+
+/// Evaluates `source` at `level` and returns its value as it would be printed.
+fn eval(source: &str, syntax: Syntax, level: OptLevel) -> String {
+    let mut vm = Vm::new();
+    vm.set_opt_level(level);
+    match vm.evaluate(source, "<test>", syntax) {
+        Ok(value) => vm.display(value).to_string(),
+        Err(error) => panic!("{}", error.render(false)),
+    }
+}
+
+/// Asserts `source` gives `expected` both unoptimized and fully optimized.
+fn assert_same(source: &str, syntax: Syntax, expected: &str) {
+    assert_eq!(eval(source, syntax, OptLevel::None), expected);
+    assert_eq!(eval(source, syntax, OptLevel::Full), expected);
+}
+
+#[test]
+fn jumps() {
+    let source = "
+var a = 0;
+if (1 < 2 and !(3 == 4)) a = a + 1; else a = a - 1;
+if (nil or false) a = a + 10;
+if (!true) { a = 100; } else { a = a * 2 + (4 - 1); }
+a;";
+    assert_same(source, Syntax::Script, "5");
+}
+
+#[test]
+fn loops() {
+    let source = "
+var total = 0;
+for (var i = 0; i < 10; i = i + 1) {
+    if (i == 2 + 3) total = total - 1;
+    else total = total + i * (1 + 1);
+}
+var n = 0;
+while (!(n >= 4)) n = n + 1;
+total + n;";
+    assert_same(source, Syntax::Script, "83");
+}
+
+#[test]
+fn closures() {
+    let source = "
+var fns = nil;
+var sum = 0;
+for (var i = 0; i < 3; i = i + 1) {
+    fun add() { sum = sum + i * (2 * 5); }
+    add();
+}
+fun counter() {
+    var count = 0;
+    fun next() { count = count + 1; return count; }
+    return next;
+}
+var next = counter();
+next();
+sum + next();";
+    assert_same(source, Syntax::Script, "32");
+}
+
+#[test]
+fn lisp_closures() {
+    let source = "
+(defun adder (n) (lambda (x) (+ x n (* 2 3))))
+(let ((add (adder 4)))
+  (list (add 1) (add (- 10 5))))";
+    assert_same(source, Syntax::Lisp, "(11 15)");
+}
+
+#[test]
+fn handlers() {
+    let source = "
+(defun checked (n)
+  (condition-case e
+      (if (< n (+ 1 1)) (signal 'small (list n)) (* n 10))
+    (small (list 'caught (car (cdr e))))))
+(list (checked 1) (checked 3)
+      (condition-case e (car (- 5 3)) (error 'bad)))";
+    assert_same(source, Syntax::Lisp, "((caught 1) 30 bad)");
+}
@@ -0,0 +1,78 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Runs lisp and checks the value it gives.
+//! Checks what the repl relies on: telling unfinished input from wrong
+//! input, and keeping state from one entry to the next.
+use eswm::{Syntax, Vm};
+
+/// Whether the repl would ask for more of `source` rather than report it.
+fn incomplete(source: &str, syntax: Syntax) -> bool {
+    match Vm::new().evaluate(source, "<input>", syntax) {
+        Ok(_) => false,
+        Err(error) => error.is_incomplete(),
+    }
+}
+
+#[test]
+fn unfinished_script_input_is_incomplete() {
+    for source in [
+        "{",
+        "fun f() {\n",
+        "var x = 1 +",
+        "print \"open",
+        "if (true",
+    ] {
+        assert!(incomplete(source, Syntax::Script), "{}", source);
+    }
+}
+
+#[test]
+fn wrong_script_input_is_not_incomplete() {
+    for source in ["}", "var x = 1 + ;", "1 +;\n{", "print 1 2;", "var = 2;"] {
+        assert!(!incomplete(source, Syntax::Script), "{}", source);
+    }
+}
+
+#[test]
+fn unfinished_lisp_input_is_incomplete() {
+    for source in ["(defun f ()", "(list 1 '(2", "\"open", "'", "`(a ,"] {
+        assert!(incomplete(source, Syntax::Lisp), "{}", source);
+    }
+    for source in [")", "(1 2))", "(a . )"] {
+        assert!(!incomplete(source, Syntax::Lisp), "{}", source);
+    }
+}
+
+#[test]
+fn entries_share_globals_and_show_their_value() {
+    let mut vm = Vm::new();
+    let mut eval = |source: &str, syntax| match vm.evaluate(source, "<input>", syntax) {
+        Ok(value) => vm.display(value).to_string(),
+        Err(error) => panic!("{}", error.render(false)),
+    };
+    assert_eq!(eval("var count = 1;", Syntax::Script), "nil");
+    assert_eq!(
+        eval(
+            "fun bump() { count = count + 1; return count; }",
+            Syntax::Script
+        ),
+        "nil"
+    );
+    assert_eq!(eval("bump();", Syntax::Script), "2");
+    assert_eq!(eval("count * 10;", Syntax::Script), "20");
+    assert_eq!(eval("(bump)", Syntax::Lisp), "3");
+}
@@ -0,0 +1,248 @@
+// eswm -- Emacs Standalown WindowManager
+// Copyright (C) 2022 Jacob Stannix
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Runs lisp and checks the value it gives.
+//! Runs scripts and checks the value they give.
+use eswm::{Syntax, Vm, VmConfig};
+
+/// Evaluates `source` and returns its value as it would be printed.
+fn eval(source: &str) -> String {
+    let mut vm = Vm::new();
+    match vm.evaluate(source, "<test>", Syntax::Script) {
+        Ok(value) => vm.display(value).to_string(),
+        Err(error) => panic!("{}", error.render(false)),
+    }
+}
+
+#[test]
+fn if_takes_one_branch() {
+    let source = "
+var taken = \"\";
+if (1 < 2) taken = taken + \"then\"; else taken = taken + \"else\";
+if (nil) taken = taken + \" then\"; else taken = taken + \" else\";
+if (false) taken = taken + \" skipped\";
+taken;";
+    assert_eq!(eval(source), "then else");
+}
+
+#[test]
+fn else_binds_to_the_nearest_if() {
+    let source = "
+var result = 0;
+if (true) if (false) result = 1; else result = 2;
+result;";
+    assert_eq!(eval(source), "2");
+}
+
+#[test]
+fn and_or_give_an_operand() {
+    assert_eq!(eval("1 and 2;"), "2");
+    assert_eq!(eval("nil and 2;"), "nil");
+    assert_eq!(eval("false or \"x\";"), "x");
+    assert_eq!(eval("3 or 4;"), "3");
+}
+
+#[test]
+fn and_or_short_circuit() {
+    let source = "
+var calls = 0;
+fun touch() { calls = calls + 1; return true; }
+false and touch();
+true or touch();
+true and touch();
+nil or touch();
+calls;";
+    assert_eq!(eval(source), "2");
+}
+
+#[test]
+fn while_loops_until_false() {
+    let source = "
+var n = 0;
+var sum = 0;
+while (n < 5) { n = n + 1; sum = sum + n; }
+while (false) sum = 0;
+sum;";
+    assert_eq!(eval(source), "15");
+}
+
+#[test]
+fn for_loop_runs_its_clauses() {
+    let source = "
+var out = \"\";
+for (var i = 0; i < 3; i = i + 1) out = out + \"x\";
+out;";
+    assert_eq!(eval(source), "xxx");
+    assert_eq!(eval("var j = 10; for (; j > 7;) j = j - 1; j;"), "7");
+    let source = "fun f() { for (var k = 0;; k = k + 1) if (k == 4) return k; } f();";
+    assert_eq!(eval(source), "4");
+}
+
+#[test]
+fn for_initializer_is_scoped_to_the_loop() {
+    let source = "
+var i = \"outer\";
+for (var i = 0; i < 2; i = i + 1) {}
+i;";
+    assert_eq!(eval(source), "outer");
+}
+
+#[test]
+fn closures_capture_each_iteration() {
+    let source = "
+var first;
+var second;
+for (var i = 1; i < 3; i = i + 1) {
+    fun get() { return i; }
+    if (i == 1) first = get; else second = get;
+}
+first() * 10 + second();";
+    assert_eq!(eval(source), "12");
+}
+
+#[test]
+fn functions_get_their_own_locals() {
+    let source = "
+fun add(a, b) { var sum = a + b; return sum; }
+fun twice(a) { var b = add(a, a); return add(b, b); }
+fun main() { var a = 100; return twice(3) + a; }
+main();";
+    assert_eq!(eval(source), "112");
+}
+
+#[test]
+fn recursion_uses_a_frame_per_call() {
+    let source = "
+fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+fib(15);";
+    assert_eq!(eval(source), "610");
+    assert_eq!(eval("fun f() {} f();"), "nil");
+}
+
+#[test]
+fn wrong_argument_count_is_an_error() {
+    let mut vm = Vm::new();
+    let error = vm
+        .evaluate("fun f(a, b) {}\nf(1);", "<test>", Syntax::Script)
+        .unwrap_err();
+    assert_eq!(
+        error.diagnostics()[0].message,
+        "Expected 2 arguments but got 1."
+    );
+}
+
+#[test]
+fn stack_trace_names_each_function() {
+    let source = "
+fun inner() { return nil + 1; }
+fun outer() { return inner(); }
+outer();";
+    let mut vm = Vm::new();
+    let error = vm.evaluate(source, "<test>", Syntax::Script).unwrap_err();
+    let trace: Vec<String> = error.diagnostics()[0]
+        .trace
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect();
+    assert_eq!(
+        trace,
+        [
+            "[<test>:2:28] in inner()",
+            "[<test>:3:28] in outer()",
+            "[<test>:4:7] in script"
+        ]
+    );
+    // The vm is left ready to run more code.
+    assert_eq!(
+        vm.evaluate("outer;", "<test>", Syntax::Script).map(|_| ()),
+        Ok(())
+    );
+}
+
+/// Runs `source`, which must fail, on `vm` and returns its error message.
+fn error_message(vm: &mut Vm, source: &str) -> String {
+    match vm.evaluate(source, "<test>", Syntax::Script) {
+        Ok(_) => panic!("'{}' ran without an error", source),
+        Err(error) => error.diagnostics()[0].message.clone(),
+    }
+}
+
+#[test]
+fn runaway_recursion_overflows_the_stack() {
+    let mut vm = Vm::new();
+    let message = error_message(&mut vm, "fun f(n) { return f(n + 1); } f(0);");
+    assert_eq!(message, "Stack overflow.");
+    // The stack is reset, so the vm keeps working.
+    let value = vm.evaluate("f;", "<test>", Syntax::Script).unwrap();
+    assert_eq!(vm.display(value).to_string(), "<fn f>");
+}
+
+#[test]
+fn limits_bound_the_stack_and_the_calls() {
+    let source = "
+fun depth(n) { if (n == 0) return 0; return depth(n - 1) + 1; }
+depth(5);";
+    let mut vm = Vm::with_config(VmConfig {
+        frames_max: 7,
+        ..VmConfig::default()
+    });
+    assert_eq!(
+        vm.evaluate(source, "<test>", Syntax::Script).map(|_| ()),
+        Ok(())
+    );
+    let mut vm = Vm::with_config(VmConfig {
+        frames_max: 6,
+        ..VmConfig::default()
+    });
+    assert_eq!(error_message(&mut vm, source), "Stack overflow.");
+
+    let mut vm = Vm::with_config(VmConfig {
+        stack_max: 8,
+        ..VmConfig::default()
+    });
+    let many = "fun f(a, b, c, d, e, g, h, i) {} f(1, 2, 3, 4, 5, 6, 7, 8);";
+    assert_eq!(error_message(&mut vm, many), "Stack overflow.");
+    let value = vm.evaluate("1 + 2;", "<test>", Syntax::Script).unwrap();
+    assert_eq!(vm.display(value).to_string(), "3");
+}
+
+#[test]
+fn chunks_hold_more_than_256_constants_and_globals() {
+    let mut source = String::new();
+    for i in 0..300 {
+        source.push_str(&format!("var g{} = \"s{}\";\n", i, i));
+    }
+    source.push_str("var total = 0;\n");
+    for i in 0..300 {
+        source.push_str(&format!("total = total + {}.5;\n", i));
+    }
+    assert_eq!(eval(&format!("{}total;", source)), "45000.0");
+    assert_eq!(
+        eval(&format!("{}g0 + g255 + g256 + g299;", source)),
+        "s0s255s256s299"
+    );
+    assert_eq!(eval(&format!("{}g299 = \"last\"; g299;", source)), "last");
+}
+
+#[test]
+fn functions_hold_more_than_256_constants() {
+    let mut body = String::from("var x = \"c0\"; var first = x;\n");
+    for i in 1..300 {
+        body.push_str(&format!("x = \"c{}\";\n", i));
+    }
+    let source = format!("fun f() {{\n{}return first + x; }}\nf();", body);
+    assert_eq!(eval(&source), "c0c299");
+}